@@ -0,0 +1,262 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use strum_macros::Display;
+
+use crate::NginxLog;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum AlertRule {
+    /// Too many requests per minute from one client
+    RequestRate,
+    /// Too large a share of 4xx responses
+    ClientErrorRatio,
+    /// Bursts of 401/403 responses that look like credential stuffing
+    AuthFailures,
+}
+
+/// An alert with the statistics of the window that triggered it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Alert {
+    pub rule: AlertRule,
+    pub addr: IpAddr,
+    pub time: DateTime<Utc>,
+    pub window_secs: i64,
+    pub requests: usize,
+    pub rate_per_min: f64,
+    pub client_errors: usize,
+    pub client_error_ratio: f64,
+    pub auth_failures: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct DetectorConfig {
+    pub window: Duration,
+    pub max_rate_per_min: f64,
+    pub max_client_error_ratio: f64,
+    /// Requests needed in a window before the 4xx ratio is judged
+    pub min_requests: usize,
+    pub max_auth_failures: usize,
+}
+
+impl Default for DetectorConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::seconds(60),
+            max_rate_per_min: 300.0,
+            max_client_error_ratio: 0.5,
+            min_requests: 20,
+            max_auth_failures: 10,
+        }
+    }
+}
+
+#[derive(Default)]
+struct ClientWindow {
+    // (time, status) of every request still inside the window
+    events: VecDeque<(DateTime<Utc>, u16)>,
+    client_errors: usize,
+    auth_failures: usize,
+    last_fired: HashMap<AlertRule, DateTime<Utc>>,
+}
+
+/// Evaluates the rules over a per-client sliding window. Time is taken from the
+/// records, so batch and follow mode behave the same.
+pub struct Detector {
+    config: DetectorConfig,
+    clients: HashMap<IpAddr, ClientWindow>,
+    last_sweep: Option<DateTime<Utc>>,
+}
+
+impl Detector {
+    pub fn new(config: DetectorConfig) -> Self {
+        Self {
+            config,
+            clients: HashMap::new(),
+            last_sweep: None,
+        }
+    }
+
+    /// Add a record and return the alerts it triggers. Each rule fires at most
+    /// once per window for a client.
+    pub fn push(&mut self, log: &NginxLog) -> Vec<Alert> {
+        let now = log.datetime;
+        self.sweep(now);
+
+        let window = self.config.window;
+        let client = self.clients.entry(log.client()).or_default();
+        client.events.push_back((now, log.status));
+        if is_client_error(log.status) {
+            client.client_errors += 1;
+        }
+        if is_auth_failure(log.status) {
+            client.auth_failures += 1;
+        }
+        while let Some((t, status)) = client.events.front().copied() {
+            if now - t < window {
+                break;
+            }
+            client.events.pop_front();
+            if is_client_error(status) {
+                client.client_errors -= 1;
+            }
+            if is_auth_failure(status) {
+                client.auth_failures -= 1;
+            }
+        }
+
+        let requests = client.events.len();
+        let window_mins = window.num_milliseconds() as f64 / 60_000.0;
+        let rate_per_min = requests as f64 / window_mins;
+        let client_error_ratio = client.client_errors as f64 / requests as f64;
+
+        let mut fired = Vec::new();
+        if rate_per_min > self.config.max_rate_per_min {
+            fired.push(AlertRule::RequestRate);
+        }
+        if requests >= self.config.min_requests
+            && client_error_ratio > self.config.max_client_error_ratio
+        {
+            fired.push(AlertRule::ClientErrorRatio);
+        }
+        if client.auth_failures > self.config.max_auth_failures {
+            fired.push(AlertRule::AuthFailures);
+        }
+
+        fired
+            .into_iter()
+            .filter(|rule| match client.last_fired.get(rule) {
+                Some(t) if now - *t < window => false,
+                _ => {
+                    client.last_fired.insert(*rule, now);
+                    true
+                }
+            })
+            .map(|rule| Alert {
+                rule,
+                addr: log.client(),
+                time: now,
+                window_secs: window.num_seconds(),
+                requests,
+                rate_per_min,
+                client_errors: client.client_errors,
+                client_error_ratio,
+                auth_failures: client.auth_failures,
+            })
+            .collect()
+    }
+
+    // drop clients that have been quiet for a whole window
+    fn sweep(&mut self, now: DateTime<Utc>) {
+        let window = self.config.window;
+        match self.last_sweep {
+            Some(t) if now - t < window => {}
+            _ => {
+                self.clients.retain(|_, c| match c.events.back() {
+                    Some((t, _)) => now - *t < window,
+                    None => false,
+                });
+                self.last_sweep = Some(now);
+            }
+        }
+    }
+}
+
+fn is_client_error(status: u16) -> bool {
+    (400..500).contains(&status)
+}
+
+fn is_auth_failure(status: u16) -> bool {
+    status == 401 || status == 403
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> DetectorConfig {
+        DetectorConfig {
+            window: Duration::seconds(10),
+            max_rate_per_min: 30.0,
+            max_client_error_ratio: 0.5,
+            min_requests: 4,
+            max_auth_failures: 3,
+        }
+    }
+
+    #[test]
+    fn detector_should_flag_request_rate_once_per_window() {
+        let mut detector = Detector::new(config());
+        // 30/min over a 10s window means more than 5 requests
+        let alerts = (0..8)
+            .flat_map(|i| detector.push(&NginxLog::builder().secs(i).build()))
+            .collect::<Vec<_>>();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].rule, AlertRule::RequestRate);
+        assert_eq!(alerts[0].requests, 6);
+
+        // spread out requests never fire
+        let alerts = (0..8)
+            .flat_map(|i| detector.push(&NginxLog::builder().addr("2.2.2.2").secs(i * 5).build()))
+            .collect::<Vec<_>>();
+        assert!(alerts.is_empty());
+    }
+
+    #[test]
+    fn detector_should_flag_auth_failures_and_error_ratio() {
+        let mut detector = Detector::new(config());
+        let alerts = [401, 403, 401, 401]
+            .iter()
+            .enumerate()
+            .flat_map(|(i, status)| {
+                let log = NginxLog::builder()
+                    .addr("3.3.3.3")
+                    .secs(i as u32 * 2)
+                    .status(*status)
+                    .build();
+                detector.push(&log)
+            })
+            .collect::<Vec<_>>();
+        let rules = alerts.iter().map(|a| a.rule).collect::<Vec<_>>();
+        assert_eq!(
+            rules,
+            vec![AlertRule::ClientErrorRatio, AlertRule::AuthFailures]
+        );
+        assert_eq!(alerts[1].auth_failures, 4);
+        assert_eq!(alerts[1].client_error_ratio, 1.0);
+    }
+
+    #[test]
+    fn detector_should_forget_old_events() {
+        let mut detector = Detector::new(config());
+        for i in 0..3 {
+            detector.push(
+                &NginxLog::builder()
+                    .addr("4.4.4.4")
+                    .secs(i)
+                    .status(401)
+                    .build(),
+            );
+        }
+        // the burst has left the window by now
+        assert!(detector
+            .push(
+                &NginxLog::builder()
+                    .addr("4.4.4.4")
+                    .secs(30)
+                    .status(401)
+                    .build()
+            )
+            .is_empty());
+        assert_eq!(
+            detector.clients[&"4.4.4.4".parse().unwrap()].events.len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,251 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{percent::percent_decode_once, session::Session, NginxLog};
+
+const REDACTED: &str = "REDACTED";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum IpMode {
+    /// Keep addresses as they are
+    #[default]
+    Keep,
+    /// Truncate IPv4 to /24 and IPv6 to /48
+    Truncate,
+    /// Replace addresses with a keyed HMAC pseudonym
+    Hmac,
+}
+
+/// Strips personal data from parsed records before they are exported.
+pub struct Anonymizer {
+    ip: IpAnonymization,
+    redact_params: Vec<String>,
+    drop_user_agent: bool,
+}
+
+enum IpAnonymization {
+    Keep,
+    Truncate,
+    Hmac(Hmac<Sha256>),
+}
+
+impl Anonymizer {
+    pub fn new(
+        ip_mode: IpMode,
+        hmac_key: Option<&str>,
+        redact_params: &[String],
+        drop_user_agent: bool,
+    ) -> anyhow::Result<Self> {
+        let ip = match ip_mode {
+            IpMode::Keep => IpAnonymization::Keep,
+            IpMode::Truncate => IpAnonymization::Truncate,
+            IpMode::Hmac => {
+                let key = hmac_key.ok_or_else(|| anyhow!("HMAC ip mode requires a key"))?;
+                IpAnonymization::Hmac(Hmac::new_from_slice(key.as_bytes())?)
+            }
+        };
+        Ok(Self {
+            ip,
+            redact_params: redact_params.to_vec(),
+            drop_user_agent,
+        })
+    }
+
+    pub fn apply(&self, logs: &mut [NginxLog]) {
+        for log in logs {
+            self.anonymize(log);
+        }
+    }
+
+    pub fn anonymize(&self, log: &mut NginxLog) {
+        log.addr = self.anonymize_ip(log.addr);
+        log.client_ip = log.client_ip.map(|ip| self.anonymize_ip(ip));
+        for ip in log.forwarded_for.iter_mut().flatten() {
+            *ip = self.anonymize_ip(*ip);
+        }
+        log.url = self.redact_query(&log.url);
+        log.referer = self.redact_query(&log.referer);
+        if self.drop_user_agent {
+            log.user_agent = "-".to_string();
+            log.ua = None;
+        }
+    }
+
+    pub fn apply_sessions(&self, sessions: &mut [Session]) {
+        for session in sessions {
+            session.addr = self.anonymize_ip(session.addr);
+            session.entry_url = self.redact_query(&session.entry_url);
+            session.exit_url = self.redact_query(&session.exit_url);
+            if self.drop_user_agent {
+                session.user_agent = session.user_agent.as_ref().map(|_| "-".to_string());
+            }
+        }
+    }
+
+    fn anonymize_ip(&self, addr: IpAddr) -> IpAddr {
+        match &self.ip {
+            IpAnonymization::Keep => addr,
+            IpAnonymization::Truncate => truncate_ip(addr),
+            IpAnonymization::Hmac(mac) => pseudonymize_ip(mac.clone(), addr),
+        }
+    }
+
+    fn redact_query(&self, url: &str) -> String {
+        let Some((path, rest)) = url.split_once('?') else {
+            return url.to_string();
+        };
+        if self.redact_params.is_empty() {
+            return url.to_string();
+        }
+        let (query, fragment) = match rest.split_once('#') {
+            Some((q, f)) => (q, Some(f)),
+            None => (rest, None),
+        };
+
+        let query = query
+            .split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((k, _)) if self.is_redacted(k) => format!("{}={}", k, REDACTED),
+                _ => pair.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+
+        match fragment {
+            Some(f) => format!("{}?{}#{}", path, query, f),
+            None => format!("{}?{}", path, query),
+        }
+    }
+
+    // keys are compared decoded, so `pass%77ord` cannot slip through
+    fn is_redacted(&self, key: &str) -> bool {
+        let key = percent_decode_once(key);
+        self.redact_params
+            .iter()
+            .any(|p| p.eq_ignore_ascii_case(&key))
+    }
+}
+
+fn truncate_ip(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], 0, 0, 0, 0, 0))
+        }
+    }
+}
+
+// Pseudonyms are mapped into the fd00::/8 unique local range so they stay
+// valid `IpAddr`s and still group requests of the same client together.
+fn pseudonymize_ip(mut mac: Hmac<Sha256>, addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => mac.update(&v4.octets()),
+        IpAddr::V6(v6) => mac.update(&v6.octets()),
+    }
+    let digest = mac.finalize().into_bytes();
+    let mut octets = [0u8; 16];
+    octets[0] = 0xfd;
+    octets[1..].copy_from_slice(&digest[..15]);
+    IpAddr::V6(Ipv6Addr::from(octets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> Vec<String> {
+        vec!["token".to_string(), "password".to_string()]
+    }
+
+    #[test]
+    fn truncate_ip_should_work() {
+        let a = Anonymizer::new(IpMode::Truncate, None, &[], false).unwrap();
+        assert_eq!(
+            a.anonymize_ip("93.180.71.3".parse().unwrap()),
+            "93.180.71.0".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            a.anonymize_ip("2001:db8:abcd:12::1".parse().unwrap()),
+            "2001:db8:abcd::".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn hmac_ip_should_be_keyed_and_stable() {
+        assert!(Anonymizer::new(IpMode::Hmac, None, &[], false).is_err());
+
+        let a = Anonymizer::new(IpMode::Hmac, Some("secret"), &[], false).unwrap();
+        let b = Anonymizer::new(IpMode::Hmac, Some("other"), &[], false).unwrap();
+        let ip = "93.180.71.3".parse().unwrap();
+        let p1 = a.anonymize_ip(ip);
+        assert_eq!(p1, a.anonymize_ip(ip));
+        assert_ne!(p1, b.anonymize_ip(ip));
+        match p1 {
+            IpAddr::V6(v6) => assert_eq!(v6.octets()[0], 0xfd),
+            IpAddr::V4(_) => panic!("expected an IPv6 pseudonym"),
+        }
+    }
+
+    #[test]
+    fn redact_query_should_work() {
+        let a = Anonymizer::new(IpMode::Keep, None, &params(), false).unwrap();
+        assert_eq!(
+            a.redact_query("/login?user=bob&Password=hunter2&token=abc#top"),
+            "/login?user=bob&Password=REDACTED&token=REDACTED#top"
+        );
+        assert_eq!(a.redact_query("/plain"), "/plain");
+        assert_eq!(a.redact_query("-"), "-");
+        assert_eq!(
+            a.redact_query("https://example.com/?q=1&token"),
+            "https://example.com/?q=1&token"
+        );
+        assert_eq!(
+            a.redact_query("/login?pass%77ord=hunter2&to%6Ben=abc"),
+            "/login?pass%77ord=REDACTED&to%6Ben=REDACTED"
+        );
+    }
+
+    #[test]
+    fn drop_user_agent_should_clear_parsed_agent() {
+        let mut log = crate::parse_nginx_log(
+            "93.180.71.3 - - [17/May/2015:08:05:32 +0000] \"GET / HTTP/1.1\" 200 1 \"-\" \"curl/7.0\"",
+        )
+        .unwrap();
+        crate::user_agent::apply(std::slice::from_mut(&mut log));
+        assert!(log.ua.is_some());
+        let a = Anonymizer::new(IpMode::Keep, None, &[], true).unwrap();
+        a.anonymize(&mut log);
+        assert_eq!(log.user_agent, "-");
+        assert!(log.ua.is_none());
+    }
+
+    #[test]
+    fn apply_sessions_should_keep_truncated_visitors_apart() {
+        let logs = [
+            "93.180.71.3 - - [17/May/2015:08:05:32 +0000] \"GET /a?token=1 HTTP/1.1\" 200 1 \"-\" \"curl\"",
+            "93.180.71.4 - - [17/May/2015:08:05:33 +0000] \"GET /b HTTP/1.1\" 200 1 \"-\" \"curl\"",
+        ]
+        .map(|v| crate::parse_nginx_log(v).unwrap());
+        let mut sessions = crate::session::sessionize(
+            &logs,
+            crate::session::SessionKey::AddrUserAgent,
+            chrono::Duration::minutes(30),
+        );
+        let a = Anonymizer::new(IpMode::Truncate, None, &params(), true).unwrap();
+        a.apply_sessions(&mut sessions);
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions.iter().all(|v| v.addr.to_string() == "93.180.71.0"));
+        assert!(sessions
+            .iter()
+            .all(|v| v.user_agent.as_deref() == Some("-")));
+        assert!(sessions.iter().any(|v| v.entry_url == "/a?token=REDACTED"));
+    }
+}
@@ -0,0 +1,521 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::anyhow;
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use winnow::{
+    ascii::digit1,
+    combinator::{alt, delimited, eof, opt, preceded, repeat, rest, terminated},
+    error::{ContextError, ErrMode},
+    token::{any, one_of, take_till, take_until, take_while},
+    PResult, Parser,
+};
+
+use crate::{
+    error_log::{ErrorLog, Level},
+    parse_http_method, parse_http_proto, parse_http_url, realip, HttpMethod, HttpProto, NginxLog,
+};
+
+/// The `common` nickname of the stock httpd.conf.
+pub const COMMON: &str = r#"%h %l %u %t "%r" %>s %b"#;
+
+/// The `combined` nickname of the stock httpd.conf.
+pub const COMBINED: &str = r#"%h %l %u %t "%r" %>s %b "%{Referer}i" "%{User-Agent}i""#;
+
+/// The `vhost_combined` nickname of the Debian httpd.conf.
+pub const VHOST_COMBINED: &str = r#"%v:%p %h %l %u %t "%r" %>s %O "%{Referer}i" "%{User-Agent}i""#;
+
+/// A piece of a `LogFormat` string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Literal(String),
+    /// A `%` directive such as `%>s` or `%{Referer}i`
+    Directive {
+        /// The directive as written, used to name untyped values
+        spec: String,
+        arg: Option<String>,
+        letter: char,
+    },
+}
+
+/// Split a `LogFormat` string into literals and directives. Status
+/// conditions and the `<`/`>` modifiers are accepted and ignored, and the
+/// backslash escapes of httpd.conf are undone so a format can be pasted as
+/// is. `common`, `combined` and `vhost_combined` name the usual nicknames.
+pub fn parse_layout(layout: &str) -> anyhow::Result<Vec<Segment>> {
+    let layout = match layout {
+        "common" => COMMON,
+        "combined" => COMBINED,
+        "vhost_combined" => VHOST_COMBINED,
+        _ => layout,
+    };
+    let segments: Vec<Segment> = repeat(0.., parse_segment)
+        .parse(layout)
+        .map_err(|e| anyhow!("Invalid LogFormat\n{}", e))?;
+    // merge the literal pieces split by escapes and `%%`
+    let mut merged = Vec::<Segment>::new();
+    for segment in segments {
+        match (merged.last_mut(), segment) {
+            (Some(Segment::Literal(a)), Segment::Literal(b)) => a.push_str(&b),
+            (_, segment) => merged.push(segment),
+        }
+    }
+    Ok(merged)
+}
+
+/// An Apache `LogFormat` compiled into a parser of `NginxLog` records.
+/// Directives without a typed field are matched and skipped, or returned by
+/// `parse_with_extra`.
+#[derive(Debug, Clone)]
+pub struct ApacheFormat {
+    segments: Vec<Segment>,
+}
+
+// fields collected while walking the format
+#[derive(Default)]
+struct Fields<'f, 's> {
+    addr: Option<IpAddr>,
+    datetime: Option<DateTime<Utc>>,
+    // `%{msec_frac}t` and `%{usec_frac}t` complete a strftime time
+    frac: Option<TimeDelta>,
+    request: Option<(HttpMethod, String, HttpProto)>,
+    status: Option<u16>,
+    body_bytes: Option<u64>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_time: Option<f64>,
+    request_length: Option<u64>,
+    host: Option<String>,
+    request_id: Option<String>,
+    forwarded_for: Vec<Option<IpAddr>>,
+    extra: Vec<(&'f str, &'s str)>,
+}
+
+impl ApacheFormat {
+    pub fn new(layout: &str) -> anyhow::Result<Self> {
+        let segments = parse_layout(layout)?;
+        for pair in segments.windows(2) {
+            if let [Segment::Directive { spec: a, .. }, Segment::Directive { spec: b, .. }] = pair {
+                anyhow::bail!("{} and {} need a literal between them", a, b);
+            }
+        }
+        let has = |letters: &str| {
+            segments.iter().any(
+                |v| matches!(v, Segment::Directive { letter, .. } if letters.contains(*letter)),
+            )
+        };
+        // `%{c}a` is the peer of a connection whose `%a` mod_remoteip rewrote
+        for segment in &segments {
+            if let Segment::Directive {
+                spec,
+                arg: Some(arg),
+                letter: 'a',
+            } = segment
+            {
+                anyhow::ensure!(arg.eq_ignore_ascii_case("c"), "Unknown directive {}", spec);
+            }
+        }
+        for (letters, names) in [
+            ("ha", "%h or %a"),
+            ("t", "%t"),
+            ("r", "%r"),
+            ("s", "%s or %>s"),
+        ] {
+            anyhow::ensure!(has(letters), "LogFormat must contain {}", names);
+        }
+        Ok(Self { segments })
+    }
+
+    pub fn parse(&self, s: &str) -> PResult<NginxLog> {
+        self.parse_with_extra(s).map(|(log, _)| log)
+    }
+
+    /// Parse a line, also returning the text of each directive that has no
+    /// field in `NginxLog`.
+    pub fn parse_with_extra<'f, 's>(
+        &'f self,
+        s: &'s str,
+    ) -> PResult<(NginxLog, Vec<(&'f str, &'s str)>)> {
+        let input = &mut &*s;
+        let mut fields = Fields::default();
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Literal(literal) => {
+                    literal.as_str().parse_next(input)?;
+                }
+                Segment::Directive { spec, arg, letter } => {
+                    let next = match self.segments.get(i + 1) {
+                        Some(Segment::Literal(literal)) => Some(literal.as_str()),
+                        _ => None,
+                    };
+                    let value = match next {
+                        Some(literal) => take_until(0.., literal).parse_next(input)?,
+                        None => rest.parse_next(input)?,
+                    };
+                    parse_directive(spec, arg.as_deref(), *letter, value, &mut fields)
+                        .ok_or_else(|| ErrMode::Backtrack(ContextError::new()))?;
+                }
+            }
+        }
+
+        let missing = || ErrMode::Backtrack(ContextError::new());
+        let (method, url, protocol) = fields.request.ok_or_else(missing)?;
+        let datetime = fields.datetime.ok_or_else(missing)? + fields.frac.unwrap_or_default();
+        let log = NginxLog {
+            addr: fields.addr.ok_or_else(missing)?,
+            datetime,
+            method,
+            url,
+            protocol,
+            status: fields.status.ok_or_else(missing)?,
+            body_bytes: fields.body_bytes.unwrap_or(0),
+            referer: fields.referer.unwrap_or_else(|| "-".to_string()),
+            user_agent: fields.user_agent.unwrap_or_else(|| "-".to_string()),
+            route: None,
+            ua: None,
+            geo: None,
+            request_time: fields.request_time,
+            source: None,
+            request_length: fields.request_length,
+            host: fields.host,
+            request_id: fields.request_id,
+            upstream: None,
+            forwarded_for: fields.forwarded_for,
+            client_ip: None,
+        };
+        Ok((log, fields.extra))
+    }
+}
+
+// Store the text of one directive, returning None when it does not parse.
+fn parse_directive<'f, 's>(
+    spec: &'f str,
+    arg: Option<&str>,
+    letter: char,
+    value: &'s str,
+    fields: &mut Fields<'f, 's>,
+) -> Option<()> {
+    // `-` is how httpd writes an empty value
+    let text = Some(value.to_string()).filter(|v| v != "-");
+    // header and variable names are case-insensitive, time formats are not
+    match (letter, arg.map(str::to_ascii_lowercase).as_deref()) {
+        ('h', _) | ('a', None | Some("c")) => fields.addr = Some(value.parse().ok()?),
+        ('t', None) => {
+            let value = value.strip_prefix('[')?.strip_suffix(']')?;
+            let dt = DateTime::parse_from_str(value, "%d/%b/%Y:%H:%M:%S %z").ok()?;
+            fields.datetime = Some(dt.with_timezone(&Utc));
+        }
+        ('t', Some(_)) => parse_time(arg?, value, fields)?,
+        ('r', _) => {
+            let (method, url, protocol, _) =
+                (parse_http_method, parse_http_url, parse_http_proto, eof)
+                    .parse(value)
+                    .ok()?;
+            fields.request = Some((method, url, protocol));
+        }
+        ('s', _) => fields.status = Some(value.parse().ok()?),
+        ('b', _) if value == "-" => fields.body_bytes = Some(0),
+        // `%O` counts the headers too, the closest httpd has to nginx's `$bytes_sent`
+        ('b' | 'B' | 'O', _) => fields.body_bytes = Some(value.parse().ok()?),
+        ('I', _) => fields.request_length = Some(value.parse().ok()?),
+        ('D', _) => fields.request_time = Some(value.parse::<f64>().ok()? / 1e6),
+        ('T', None | Some("s")) => fields.request_time = Some(value.parse().ok()?),
+        ('T', Some("ms")) => fields.request_time = Some(value.parse::<f64>().ok()? / 1e3),
+        ('T', Some("us")) => fields.request_time = Some(value.parse::<f64>().ok()? / 1e6),
+        ('i', Some("referer")) => fields.referer = Some(value.to_string()),
+        ('i', Some("user-agent")) => fields.user_agent = Some(value.to_string()),
+        ('i', Some("host")) | ('v' | 'V', _) => fields.host = text.or(fields.host.take()),
+        ('i', Some("x-forwarded-for")) => fields.forwarded_for = realip::parse_forwarded_for(value),
+        ('i', Some("x-request-id")) | ('e', Some("unique_id")) | ('L', _) => {
+            fields.request_id = text
+        }
+        _ => fields.extra.push((spec, value)),
+    }
+    Some(())
+}
+
+// `%{format}t`: a strftime format or one of the epoch forms, optionally
+// prefixed with `begin:` or `end:`.
+fn parse_time(format: &str, value: &str, fields: &mut Fields) -> Option<()> {
+    let format = format
+        .strip_prefix("begin:")
+        .or_else(|| format.strip_prefix("end:"))
+        .unwrap_or(format);
+    let int = || value.parse::<i64>().ok();
+    match format {
+        "sec" => fields.datetime = Some(DateTime::from_timestamp(int()?, 0)?),
+        "msec" => fields.datetime = Some(DateTime::from_timestamp_millis(int()?)?),
+        "usec" => fields.datetime = Some(DateTime::from_timestamp_micros(int()?)?),
+        "msec_frac" => fields.frac = Some(TimeDelta::milliseconds(int()?)),
+        "usec_frac" => fields.frac = Some(TimeDelta::microseconds(int()?)),
+        // httpd formats strftime in local time; without an offset it is read as UTC
+        _ => {
+            let format = format.to_string();
+            let dt = match DateTime::parse_from_str(value, &format) {
+                Ok(dt) => dt.with_timezone(&Utc),
+                Err(_) => NaiveDateTime::parse_from_str(value, &format)
+                    .ok()?
+                    .and_utc(),
+            };
+            fields.datetime = Some(dt);
+        }
+    }
+    Some(())
+}
+
+fn parse_segment(s: &mut &str) -> PResult<Segment> {
+    alt((
+        "%%".map(|_| Segment::Literal("%".to_string())),
+        parse_percent_directive,
+        preceded('\\', any).map(|c| {
+            let c = match c {
+                'n' => '\n',
+                't' => '\t',
+                c => c,
+            };
+            Segment::Literal(c.to_string())
+        }),
+        take_till(1.., ['%', '\\']).map(|v: &str| Segment::Literal(v.to_string())),
+    ))
+    .parse_next(s)
+}
+
+// `%`, an optional `!400,501`-style status condition, an optional `<` or
+// `>`, an optional `{arg}` and the directive letter
+fn parse_percent_directive(s: &mut &str) -> PResult<Segment> {
+    let (spec, (_, _, _, arg, letter)) = (
+        '%',
+        opt((
+            opt('!'),
+            take_while(1.., |c: char| c.is_ascii_digit() || c == ','),
+        )),
+        opt(one_of(['<', '>'])),
+        opt(delimited('{', take_till(0.., '}'), '}')),
+        any.verify(char::is_ascii_alphabetic),
+    )
+        .with_taken()
+        .map(|(v, spec): (_, &str)| (spec, v))
+        .parse_next(s)?;
+    Ok(Segment::Directive {
+        spec: spec.to_string(),
+        arg: arg.map(|v: &str| v.to_string()),
+        letter,
+    })
+}
+
+/// Parse an Apache 2.4 error log in the default `ErrorLogFormat`. Lines that
+/// do not start with a timestamp are continuations of the entry above them.
+pub fn parse_apache_error_logs(input: &str) -> Vec<ErrorLog> {
+    let mut entries: Vec<String> = Vec::new();
+    for line in input.lines() {
+        match entries.last_mut() {
+            Some(entry) if parse_error_time.parse_next(&mut &*line).is_err() => {
+                entry.push('\n');
+                entry.push_str(line);
+            }
+            _ => entries.push(line.to_string()),
+        }
+    }
+    entries
+        .iter()
+        .filter_map(|v| parse_apache_error_log(v).ok())
+        .collect()
+}
+
+/// Parse one entry such as
+/// `[Fri Sep 09 10:42:29.902022 2011] [core:error] [pid 35708:tid 4328636416] [client 72.15.99.187:51234] AH00128: File does not exist: /srv/favicon.ico`.
+/// The module, client, `AH` error code and referer go into the context.
+pub fn parse_apache_error_log(s: &str) -> PResult<ErrorLog> {
+    let input = &mut &*s;
+    let datetime = parse_error_time(input)?;
+    let (module, level) = delimited(
+        " [",
+        (terminated(take_till(0.., ':'), ':'), parse_level),
+        ']',
+    )
+    .parse_next(input)?;
+    let (pid, tid) = delimited(
+        " [pid ",
+        (digit1.parse_to(), opt(preceded(":tid ", digit1.parse_to()))),
+        ']',
+    )
+    .parse_next(input)?;
+    let mut message = input.trim_start().to_string();
+
+    let mut context = Vec::new();
+    if !module.is_empty() {
+        context.push(("module".to_string(), module.to_string()));
+    }
+    if let Some(start) = message.find("[client ") {
+        if let Some(len) = message[start..].find(']') {
+            let client = &message[start + "[client ".len()..start + len];
+            let client = match client.parse::<SocketAddr>() {
+                Ok(addr) => addr.ip().to_string(),
+                Err(_) => client.to_string(),
+            };
+            context.push(("client".to_string(), client));
+            let mut end = start + len + 1;
+            if message[end..].starts_with(' ') {
+                end += 1;
+            }
+            message.replace_range(start..end, "");
+        }
+    }
+    // `AH00128: `, the message number of httpd 2.4
+    let code = message.match_indices("AH").map(|(i, _)| i).find(|&i| {
+        let code = &message.as_bytes()[i..];
+        code.len() >= 9 && code[2..7].iter().all(u8::is_ascii_digit) && &code[7..9] == b": "
+    });
+    if let Some(i) = code {
+        context.push(("code".to_string(), message[i..i + 7].to_string()));
+        message.replace_range(i..i + 9, "");
+    }
+    if let Some((text, referer)) = message.rsplit_once(", referer: ") {
+        context.push(("referer".to_string(), referer.to_string()));
+        message.truncate(text.len());
+    }
+
+    Ok(ErrorLog {
+        datetime,
+        level,
+        pid,
+        tid: tid.unwrap_or(0),
+        connection: None,
+        message,
+        context,
+    })
+}
+
+// `[Fri Sep 09 10:42:29.902022 2011]`, local time read as UTC
+fn parse_error_time(s: &mut &str) -> PResult<DateTime<Utc>> {
+    delimited('[', take_till(1.., ']'), ']')
+        .try_map(|v| NaiveDateTime::parse_from_str(v, "%a %b %d %H:%M:%S%.f %Y"))
+        .map(|v| v.and_utc())
+        .parse_next(s)
+}
+
+// httpd's `trace1` to `trace8` are all below `debug`, kept as debug
+fn parse_level(s: &mut &str) -> PResult<Level> {
+    alt((
+        ("trace", one_of('1'..='8')).value(Level::Debug),
+        "debug".value(Level::Debug),
+        "info".value(Level::Info),
+        "notice".value(Level::Notice),
+        "warn".value(Level::Warn),
+        "error".value(Level::Error),
+        "crit".value(Level::Crit),
+        "alert".value(Level::Alert),
+        "emerg".value(Level::Emerg),
+    ))
+    .parse_next(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_layout_should_work() -> anyhow::Result<()> {
+        let segments = parse_layout(r#"%h %!400,501{X-Id}i \"%>s\" 100%%"#)?;
+        assert_eq!(
+            segments,
+            vec![
+                Segment::Directive {
+                    spec: "%h".to_string(),
+                    arg: None,
+                    letter: 'h',
+                },
+                Segment::Literal(" ".to_string()),
+                Segment::Directive {
+                    spec: "%!400,501{X-Id}i".to_string(),
+                    arg: Some("X-Id".to_string()),
+                    letter: 'i',
+                },
+                Segment::Literal(" \"".to_string()),
+                Segment::Directive {
+                    spec: "%>s".to_string(),
+                    arg: None,
+                    letter: 's',
+                },
+                Segment::Literal("\" 100%".to_string()),
+            ]
+        );
+        assert_eq!(parse_layout("combined")?.len(), 18);
+        assert!(ApacheFormat::new("%h %>s").is_err());
+        assert!(ApacheFormat::new(r#"%h%u %t "%r" %>s"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn apache_format_should_parse_access_logs() -> anyhow::Result<()> {
+        let format = ApacheFormat::new("combined")?;
+        let s = concat!(
+            r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 - "#,
+            r#""http://www.example.com/start.html" "Mozilla/4.08 [en] (Win98; I ;Nav)""#,
+        );
+        let log = format.parse(s).unwrap();
+        assert_eq!(log.datetime.to_rfc3339(), "2000-10-10T20:55:36+00:00");
+        assert_eq!(log.url, "/apache_pb.gif");
+        assert_eq!(log.body_bytes, 0);
+        assert_eq!(log.referer, "http://www.example.com/start.html");
+        assert_eq!(log.user_agent, "Mozilla/4.08 [en] (Win98; I ;Nav)");
+
+        let format = ApacheFormat::new("vhost_combined")?;
+        let log = format
+            .parse(&format!(
+                "www.example.com:443 {}",
+                s.replace(" 200 - ", " 200 2326 ")
+            ))
+            .unwrap();
+        assert_eq!(log.host.as_deref(), Some("www.example.com"));
+        assert_eq!(log.body_bytes, 2326);
+
+        let format = ApacheFormat::new(r#"%{c}a %t "%r" %>s"#)?;
+        let log = format
+            .parse(r#"10.0.0.1 [10/Oct/2000:13:55:36 -0700] "GET / HTTP/1.0" 200"#)
+            .unwrap();
+        assert_eq!(log.addr.to_string(), "10.0.0.1");
+        assert!(ApacheFormat::new(r#"%{x}a %t "%r" %>s"#).is_err());
+
+        let format = ApacheFormat::new(concat!(
+            r#"%a [%{%Y-%m-%d %H:%M:%S}t.%{msec_frac}t] %V "%r" %>s %B %D "#,
+            r#""%{X-Forwarded-For}i" %{Cookie}i"#,
+        ))?;
+        let s = concat!(
+            r#"2001:db8::1 [2024-03-01 12:00:00.250] shop.example.com "POST /cart HTTP/1.1" "#,
+            r#"302 18 175000 "203.0.113.7, 10.0.0.1" sid=abc"#,
+        );
+        let (log, extra) = format.parse_with_extra(s).unwrap();
+        assert_eq!(log.datetime.to_rfc3339(), "2024-03-01T12:00:00.250+00:00");
+        assert_eq!(log.method, HttpMethod::Post);
+        assert_eq!(log.host.as_deref(), Some("shop.example.com"));
+        assert_eq!(log.request_time, Some(0.175));
+        assert_eq!(log.forwarded_for.len(), 2);
+        assert_eq!(extra, vec![("%{Cookie}i", "sid=abc")]);
+        assert!(format.parse(&s.replace(" 18 ", " x ")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_apache_error_logs_should_fill_context() {
+        let input = concat!(
+            "[Fri Sep 09 10:42:29.902022 2011] [core:error] [pid 35708:tid 4328636416] ",
+            "[client 72.15.99.187:51234] AH00128: File does not exist: /srv/favicon.ico, ",
+            "referer: http://example.com/\n",
+            "[Fri Sep 09 10:42:30.000000 2011] [mpm_prefork:notice] [pid 1] AH00163: resuming\n",
+            "[Fri Sep 09 10:42:31.000000 2011] [php7:trace3] [pid 9] stack:\n",
+            "  #0 main\n",
+        );
+        let logs = parse_apache_error_logs(input);
+        assert_eq!(logs.len(), 3);
+        assert_eq!(logs[0].level, Level::Error);
+        assert_eq!((logs[0].pid, logs[0].tid), (35708, 4328636416));
+        assert_eq!(logs[0].message, "File does not exist: /srv/favicon.ico");
+        assert_eq!(logs[0].client(), Some("72.15.99.187".parse().unwrap()));
+        assert_eq!(logs[0].get("code"), Some("AH00128"));
+        assert_eq!(logs[0].get("referer"), Some("http://example.com/"));
+        assert_eq!(logs[1].get("module"), Some("mpm_prefork"));
+        assert_eq!(logs[1].message, "resuming");
+        assert_eq!(logs[2].level, Level::Debug);
+        assert_eq!(logs[2].message, "stack:\n  #0 main");
+    }
+}
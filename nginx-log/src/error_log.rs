@@ -0,0 +1,348 @@
+use std::{fs::File, net::IpAddr, sync::Arc};
+
+use arrow::{
+    array::{
+        Array, Int64Array, MapBuilder, RecordBatch, StringArray, StringBuilder, UInt32Array,
+        UInt64Array,
+    },
+    datatypes::{DataType, Field, Schema},
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use clap::ValueEnum;
+use parquet::arrow::ArrowWriter;
+use strum_macros::Display;
+use winnow::{
+    ascii::digit1,
+    combinator::{alt, delimited, opt, separated, terminated},
+    token::{take, take_till, take_until, take_while},
+    PResult, Parser,
+};
+
+// nginx starts the context of http errors with the client address
+const CONTEXT_START: &str = ", client: ";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum Level {
+    Debug,
+    Info,
+    Notice,
+    Warn,
+    Error,
+    Crit,
+    Alert,
+    Emerg,
+}
+
+/// One `error.log` entry, which may span several lines.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorLog {
+    /// nginx writes local time without an offset; it is read as UTC
+    pub datetime: DateTime<Utc>,
+    pub level: Level,
+    pub pid: u32,
+    pub tid: u64,
+    /// The `*N` connection number, absent for process-level messages
+    pub connection: Option<u64>,
+    pub message: String,
+    /// Trailing `key: value` pairs such as `client`, `server` and `request`
+    pub context: Vec<(String, String)>,
+}
+
+impl ErrorLog {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.context
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn client(&self) -> Option<IpAddr> {
+        self.get("client")?.parse().ok()
+    }
+}
+
+/// Parse a whole error log. Lines that do not start with a timestamp are
+/// continuations of the entry above them.
+pub fn parse_error_logs(input: &str) -> Vec<ErrorLog> {
+    let mut entries: Vec<String> = Vec::new();
+    for line in input.lines() {
+        match entries.last_mut() {
+            Some(entry) if parse_header.parse_next(&mut &*line).is_err() => {
+                entry.push('\n');
+                entry.push_str(line);
+            }
+            _ => entries.push(line.to_string()),
+        }
+    }
+    entries
+        .iter()
+        .filter_map(|v| parse_error_log(v).ok())
+        .collect()
+}
+
+pub fn parse_error_log(s: &str) -> PResult<ErrorLog> {
+    let input = &mut &*s;
+    let mut log = parse_header(input)?;
+    // the message may itself contain `, client: `, so the context starts at
+    // the first one followed by well-formed pairs led by a client address
+    let context_at = |i: usize| {
+        let context = parse_context.parse(&input[i + 2..]).ok()?;
+        let client = &context.first()?.1;
+        (client.parse::<IpAddr>().is_ok() || client.starts_with("unix:")).then_some(context)
+    };
+    let (message, context) = input
+        .match_indices(CONTEXT_START)
+        .find_map(|(i, _)| Some((&input[..i], context_at(i)?)))
+        .unwrap_or((*input, Vec::new()));
+    log.message = message.to_string();
+    log.context = context;
+    Ok(log)
+}
+
+// `2024/03/01 12:00:00 [error] 1234#5678: *99 `, leaving message and context empty
+fn parse_header(s: &mut &str) -> PResult<ErrorLog> {
+    let datetime = take(19usize)
+        .try_map(|v| NaiveDateTime::parse_from_str(v, "%Y/%m/%d %H:%M:%S"))
+        .parse_next(s)?;
+    let level = delimited(" [", parse_level, "] ").parse_next(s)?;
+    let pid = terminated(digit1.parse_to(), '#').parse_next(s)?;
+    let tid = terminated(digit1.parse_to(), ": ").parse_next(s)?;
+    let connection = opt(delimited('*', digit1.parse_to(), ' ')).parse_next(s)?;
+    Ok(ErrorLog {
+        datetime: datetime.and_utc(),
+        level,
+        pid,
+        tid,
+        connection,
+        message: String::new(),
+        context: Vec::new(),
+    })
+}
+
+fn parse_level(s: &mut &str) -> PResult<Level> {
+    alt((
+        "debug".value(Level::Debug),
+        "info".value(Level::Info),
+        "notice".value(Level::Notice),
+        "warn".value(Level::Warn),
+        "error".value(Level::Error),
+        "crit".value(Level::Crit),
+        "alert".value(Level::Alert),
+        "emerg".value(Level::Emerg),
+    ))
+    .parse_next(s)
+}
+
+// `client: 1.2.3.4, server: x, request: "GET / HTTP/1.1"`
+fn parse_context(s: &mut &str) -> PResult<Vec<(String, String)>> {
+    let key = take_while(1.., |c: char| c.is_ascii_lowercase() || c == '_');
+    let value = alt((
+        delimited('"', take_until(0.., '"'), '"'),
+        take_till(0.., [',', '\n']),
+    ));
+    separated(
+        1..,
+        (key, ": ", value).map(|(k, _, v): (&str, _, &str)| (k.to_string(), v.to_string())),
+        ", ",
+    )
+    .parse_next(s)
+}
+
+pub fn write_error_logs_to_parquet(logs: &[ErrorLog], filename: &str) -> anyhow::Result<String> {
+    let datetimes = logs
+        .iter()
+        .map(|v| v.datetime.timestamp())
+        .collect::<Vec<i64>>();
+    let levels = logs
+        .iter()
+        .map(|v| v.level.to_string())
+        .collect::<Vec<String>>();
+    let pids = logs.iter().map(|v| v.pid).collect::<Vec<u32>>();
+    let tids = logs.iter().map(|v| v.tid).collect::<Vec<u64>>();
+    let connections = logs
+        .iter()
+        .map(|v| v.connection)
+        .collect::<Vec<Option<u64>>>();
+    let messages = logs
+        .iter()
+        .map(|v| v.message.clone())
+        .collect::<Vec<String>>();
+    let column = |key: &str| {
+        let values = logs
+            .iter()
+            .map(|v| v.get(key).map(str::to_string))
+            .collect::<Vec<Option<String>>>();
+        Arc::new(StringArray::from(values)) as Arc<dyn Array>
+    };
+    let mut context = MapBuilder::new(None, StringBuilder::new(), StringBuilder::new());
+    for log in logs {
+        for (k, v) in &log.context {
+            context.keys().append_value(k);
+            context.values().append_value(v);
+        }
+        context.append(true)?;
+    }
+    let context = context.finish();
+
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("datetime", DataType::Int64, false),
+        Field::new("level", DataType::Utf8, false),
+        Field::new("pid", DataType::UInt32, false),
+        Field::new("tid", DataType::UInt64, false),
+        Field::new("connection", DataType::UInt64, true),
+        Field::new("message", DataType::Utf8, false),
+        Field::new("client", DataType::Utf8, true),
+        Field::new("server", DataType::Utf8, true),
+        Field::new("request", DataType::Utf8, true),
+        Field::new("upstream", DataType::Utf8, true),
+        Field::new("host", DataType::Utf8, true),
+        Field::new("context", context.data_type().clone(), false),
+    ]));
+    let batch = RecordBatch::try_new(
+        schema.clone(),
+        vec![
+            Arc::new(Int64Array::from(datetimes)) as Arc<dyn Array>,
+            Arc::new(StringArray::from(levels)) as Arc<dyn Array>,
+            Arc::new(UInt32Array::from(pids)) as Arc<dyn Array>,
+            Arc::new(UInt64Array::from(tids)) as Arc<dyn Array>,
+            Arc::new(UInt64Array::from(connections)) as Arc<dyn Array>,
+            Arc::new(StringArray::from(messages)) as Arc<dyn Array>,
+            column("client"),
+            column("server"),
+            column("request"),
+            column("upstream"),
+            column("host"),
+            Arc::new(context) as Arc<dyn Array>,
+        ],
+    )?;
+
+    let file = File::create(filename)?;
+    let mut writer = ArrowWriter::try_new(file, schema, None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+
+    Ok(filename.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::MapArray;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use super::*;
+
+    #[test]
+    fn parse_error_log_should_split_message_and_context() {
+        let s = concat!(
+            "2024/03/01 12:00:00 [error] 1234#5678: *99 connect() failed (111: Connection refused) ",
+            "while connecting to upstream, client: 1.2.3.4, server: x, ",
+            r#"request: "GET / HTTP/1.1", upstream: "http://10.0.0.5:8080/", host: "example.com""#,
+        );
+        let log = parse_error_log(s).unwrap();
+        assert_eq!(log.datetime.to_rfc3339(), "2024-03-01T12:00:00+00:00");
+        assert_eq!(log.level, Level::Error);
+        assert_eq!((log.pid, log.tid, log.connection), (1234, 5678, Some(99)));
+        assert_eq!(
+            log.message,
+            "connect() failed (111: Connection refused) while connecting to upstream"
+        );
+        assert_eq!(log.client(), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(log.get("request"), Some("GET / HTTP/1.1"));
+        assert_eq!(log.get("upstream"), Some("http://10.0.0.5:8080/"));
+        assert_eq!(log.context.len(), 5);
+
+        let log = parse_error_log(concat!(
+            "2024/03/01 12:00:00 [error] 1#1: *2 upstream sent \"bad, client: gone\", ",
+            "client: 1.2.3.4, server: x, request: \"GET /?q=a, client: b HTTP/1.1\"",
+        ))
+        .unwrap();
+        assert_eq!(log.message, r#"upstream sent "bad, client: gone""#);
+        assert_eq!(log.client(), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(log.get("request"), Some("GET /?q=a, client: b HTTP/1.1"));
+
+        let log =
+            parse_error_log("2024/03/01 12:00:01 [notice] 1#1: signal process started").unwrap();
+        assert_eq!(log.connection, None);
+        assert!(log.context.is_empty());
+        assert!(parse_error_log("2024/03/01 12:00:01 [loud] 1#1: x").is_err());
+    }
+
+    #[test]
+    fn parse_error_logs_should_join_continuation_lines() {
+        let input = concat!(
+            "2024/03/01 12:00:00 [error] 7#7: *3 lua entry thread aborted: runtime error: boom\n",
+            "stack traceback:\n",
+            "\tcoroutine 0:\n",
+            "\t[C]: in function 'error', client: 1.2.3.4, server: api, request: \"POST /x HTTP/1.1\"\n",
+            "2024/03/01 12:00:02 [warn] 7#7: *4 an upstream response is buffered to a temporary file\n",
+        );
+        let logs = parse_error_logs(input);
+        assert_eq!(logs.len(), 2);
+        assert_eq!(
+            logs[0].message,
+            concat!(
+                "lua entry thread aborted: runtime error: boom\nstack traceback:\n",
+                "\tcoroutine 0:\n\t[C]: in function 'error'"
+            )
+        );
+        assert_eq!(logs[0].get("server"), Some("api"));
+        assert_eq!(logs[1].level, Level::Warn);
+        assert!(logs[1].level < logs[0].level);
+    }
+
+    #[test]
+    fn write_error_logs_to_parquet_should_work() -> anyhow::Result<()> {
+        let logs = parse_error_logs(concat!(
+            "2024/03/01 12:00:00 [error] 1#2: *3 open() failed, client: 1.2.3.4, server: x\n",
+            "2024/03/01 12:00:01 [notice] 1#1: reconfiguring\n",
+        ));
+        let path =
+            std::env::temp_dir().join(format!("nginx-log-errors-{}.parquet", std::process::id()));
+        write_error_logs_to_parquet(&logs, path.to_str().unwrap())?;
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&path)?)?.build()?;
+        let batches = reader.collect::<Result<Vec<_>, _>>()?;
+        std::fs::remove_file(&path)?;
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), 2);
+        let string_column = |name: &str| {
+            batch
+                .column_by_name(name)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(string_column("level").value(1), "notice");
+        assert_eq!(string_column("message").value(0), "open() failed");
+        let client = string_column("client");
+        assert_eq!(client.value(0), "1.2.3.4");
+        assert!(client.is_null(1));
+
+        let context = batch.column_by_name("context").unwrap();
+        let context = context.as_any().downcast_ref::<MapArray>().unwrap();
+        let entries = context.value(0);
+        let keys = entries
+            .column(0)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        let values = entries
+            .column(1)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(
+            keys.iter().flatten().collect::<Vec<_>>(),
+            ["client", "server"]
+        );
+        assert_eq!(
+            values.iter().flatten().collect::<Vec<_>>(),
+            ["1.2.3.4", "x"]
+        );
+        assert!(context.value(1).is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,526 @@
+use std::{net::IpAddr, str::FromStr};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use winnow::{
+    ascii::{digit1, float, multispace0, Caseless},
+    combinator::{
+        alt, cut_err, delimited, eof, fail, not, opt, preceded, repeat, separated_pair, terminated,
+    },
+    error::{StrContext, StrContextValue},
+    stream::AsChar,
+    token::{any, none_of, one_of, take_while},
+    PResult, Parser,
+};
+
+use crate::{HttpProto, NginxLog};
+
+const FIELD_NAMES: &str = "addr, client, time, method, url, route, protocol, status, bytes, \
+                           referer, user_agent or request_time";
+
+/// A parsed `--where` expression such as
+/// `status >= 500 and method == "POST" and url ~ "^/api/"`.
+#[derive(Debug)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Cmp(Comparison),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    /// `$remote_addr` as logged
+    Addr,
+    /// The client resolved through trusted proxies, else `$remote_addr`
+    Client,
+    Time,
+    Method,
+    Url,
+    Route,
+    Protocol,
+    Status,
+    Bytes,
+    Referer,
+    UserAgent,
+    RequestTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FieldKind {
+    Ip,
+    Time,
+    Number,
+    Text,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Ge,
+    Le,
+    Gt,
+    Lt,
+    Match,
+    NotMatch,
+    Like,
+    In,
+}
+
+#[derive(Debug)]
+pub enum Comparison {
+    Number(Field, Op, f64),
+    Text(Field, Op, String),
+    /// `~`, `!~` and `like`, the latter compiled from a glob
+    Pattern(Field, bool, Regex),
+    Ip(Field, Op, IpAddr),
+    Cidr(Field, IpAddr, u8),
+    Time(Op, DateTime<Utc>),
+    /// half-open `start..end`
+    TimeRange(DateTime<Utc>, DateTime<Utc>),
+}
+
+impl Filter {
+    pub fn matches(&self, log: &NginxLog) -> bool {
+        match self {
+            Filter::And(a, b) => a.matches(log) && b.matches(log),
+            Filter::Or(a, b) => a.matches(log) || b.matches(log),
+            Filter::Not(f) => !f.matches(log),
+            Filter::Cmp(c) => c.matches(log),
+        }
+    }
+}
+
+impl Comparison {
+    fn matches(&self, log: &NginxLog) -> bool {
+        match self {
+            Comparison::Number(field, op, v) => match number_value(*field, log) {
+                Some(n) => compare(op, &n, v),
+                None => false,
+            },
+            Comparison::Text(field, op, v) => {
+                let text = text_value(*field, log);
+                let eq = if *field == Field::Method {
+                    text.eq_ignore_ascii_case(v)
+                } else {
+                    text == *v
+                };
+                eq == (*op == Op::Eq)
+            }
+            Comparison::Pattern(field, negate, re) => {
+                re.is_match(&text_value(*field, log)) != *negate
+            }
+            Comparison::Ip(field, op, ip) => (ip_value(*field, log) == *ip) == (*op == Op::Eq),
+            Comparison::Cidr(field, net, prefix) => in_cidr(&ip_value(*field, log), net, *prefix),
+            Comparison::Time(op, t) => compare(op, &log.datetime, t),
+            Comparison::TimeRange(start, end) => *start <= log.datetime && log.datetime < *end,
+        }
+    }
+}
+
+impl Field {
+    fn kind(&self) -> FieldKind {
+        match self {
+            Field::Addr | Field::Client => FieldKind::Ip,
+            Field::Time => FieldKind::Time,
+            Field::Status | Field::Bytes | Field::RequestTime => FieldKind::Number,
+            _ => FieldKind::Text,
+        }
+    }
+}
+
+impl FromStr for Field {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "addr" => Ok(Field::Addr),
+            "client" => Ok(Field::Client),
+            "time" | "datetime" => Ok(Field::Time),
+            "method" => Ok(Field::Method),
+            "url" => Ok(Field::Url),
+            "route" => Ok(Field::Route),
+            "protocol" => Ok(Field::Protocol),
+            "status" => Ok(Field::Status),
+            "bytes" | "body_bytes" => Ok(Field::Bytes),
+            "referer" => Ok(Field::Referer),
+            "user_agent" => Ok(Field::UserAgent),
+            "request_time" => Ok(Field::RequestTime),
+            _ => Err(anyhow!("Unknown field: {}", s)),
+        }
+    }
+}
+
+fn parse_field(s: &str) -> Option<Field> {
+    s.parse().ok()
+}
+
+fn ip_value(field: Field, log: &NginxLog) -> IpAddr {
+    match field {
+        Field::Client => log.client(),
+        _ => log.addr,
+    }
+}
+
+fn number_value(field: Field, log: &NginxLog) -> Option<f64> {
+    match field {
+        Field::Status => Some(log.status as f64),
+        Field::Bytes => Some(log.body_bytes as f64),
+        Field::RequestTime => log.request_time,
+        _ => None,
+    }
+}
+
+fn text_value(field: Field, log: &NginxLog) -> String {
+    match field {
+        Field::Method => log.method.to_string().to_uppercase(),
+        Field::Url => log.url.clone(),
+        Field::Route => log.route.clone().unwrap_or_default(),
+        Field::Protocol => match log.protocol {
+            HttpProto::HTTP1_0 => "HTTP/1.0",
+            HttpProto::HTTP1_1 => "HTTP/1.1",
+            HttpProto::HTTP2_0 => "HTTP/2.0",
+            HttpProto::HTTP3_0 => "HTTP/3.0",
+        }
+        .to_string(),
+        Field::Referer => log.referer.clone(),
+        Field::UserAgent => log.user_agent.clone(),
+        _ => String::new(),
+    }
+}
+
+fn compare<T: PartialOrd>(op: &Op, a: &T, b: &T) -> bool {
+    match op {
+        Op::Eq => a == b,
+        Op::Ne => a != b,
+        Op::Ge => a >= b,
+        Op::Le => a <= b,
+        Op::Gt => a > b,
+        Op::Lt => a < b,
+        _ => false,
+    }
+}
+
+pub fn in_cidr(addr: &IpAddr, net: &IpAddr, prefix: u8) -> bool {
+    match (addr, net) {
+        (IpAddr::V4(a), IpAddr::V4(n)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(*a) & mask == u32::from(*n) & mask
+        }
+        (IpAddr::V6(a), IpAddr::V6(n)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(*a) & mask == u128::from(*n) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Parse a filter expression. Errors point at the offending token.
+pub fn parse_filter(s: &str) -> anyhow::Result<Filter> {
+    terminated(
+        parse_or,
+        (
+            multispace0,
+            cut_err(eof).context(expected("`and`, `or` or end of expression")),
+        ),
+    )
+    .parse(s)
+    .map_err(|e| anyhow!("Invalid filter expression\n{}", e))
+}
+
+fn expected(desc: &'static str) -> StrContext {
+    StrContext::Expected(StrContextValue::Description(desc))
+}
+
+fn parse_or(s: &mut &str) -> PResult<Filter> {
+    let first = parse_and(s)?;
+    let rest: Vec<Filter> =
+        repeat(0.., preceded(keyword("or"), cut_err(parse_and))).parse_next(s)?;
+    Ok(rest
+        .into_iter()
+        .fold(first, |a, b| Filter::Or(Box::new(a), Box::new(b))))
+}
+
+fn parse_and(s: &mut &str) -> PResult<Filter> {
+    let first = parse_not(s)?;
+    let rest: Vec<Filter> =
+        repeat(0.., preceded(keyword("and"), cut_err(parse_not))).parse_next(s)?;
+    Ok(rest
+        .into_iter()
+        .fold(first, |a, b| Filter::And(Box::new(a), Box::new(b))))
+}
+
+fn parse_not(s: &mut &str) -> PResult<Filter> {
+    alt((
+        preceded(keyword("not"), cut_err(parse_not)).map(|f| Filter::Not(Box::new(f))),
+        parse_primary,
+    ))
+    .parse_next(s)
+}
+
+fn parse_primary(s: &mut &str) -> PResult<Filter> {
+    preceded(
+        multispace0,
+        alt((
+            delimited(
+                '(',
+                cut_err(parse_or),
+                cut_err(preceded(multispace0, ')')).context(expected("`)`")),
+            ),
+            parse_comparison.map(Filter::Cmp),
+        )),
+    )
+    .parse_next(s)
+}
+
+fn parse_comparison(s: &mut &str) -> PResult<Comparison> {
+    let field = cut_err(take_while(1.., is_ident_char).verify_map(parse_field))
+        .context(StrContext::Label("field"))
+        .context(expected(FIELD_NAMES))
+        .parse_next(s)?;
+    multispace0(s)?;
+
+    let op_start = *s;
+    let op = cut_err(parse_op)
+        .context(expected("==, !=, >=, <=, >, <, ~, !~, like or in"))
+        .parse_next(s)?;
+    multispace0(s)?;
+
+    let cmp = match (field.kind(), op) {
+        (FieldKind::Number, Op::Eq | Op::Ne | Op::Ge | Op::Le | Op::Gt | Op::Lt) => {
+            let v = cut_err(float).context(expected("number")).parse_next(s)?;
+            Comparison::Number(field, op, v)
+        }
+        (FieldKind::Text, Op::Eq | Op::Ne) => {
+            let v = cut_err(parse_string)
+                .context(expected("string"))
+                .parse_next(s)?;
+            Comparison::Text(field, op, v)
+        }
+        (FieldKind::Text, Op::Match | Op::NotMatch) => {
+            let re = cut_err(parse_string.try_map(|v| Regex::new(&v)))
+                .context(StrContext::Label("regex"))
+                .parse_next(s)?;
+            Comparison::Pattern(field, op == Op::NotMatch, re)
+        }
+        (FieldKind::Text, Op::Like) => {
+            let re = cut_err(parse_string.try_map(|v| glob_to_regex(&v)))
+                .context(StrContext::Label("glob"))
+                .parse_next(s)?;
+            Comparison::Pattern(field, false, re)
+        }
+        (FieldKind::Ip, Op::Eq | Op::Ne) => {
+            let ip = cut_err(parse_ip_literal)
+                .context(expected("ip address"))
+                .parse_next(s)?;
+            Comparison::Ip(field, op, ip)
+        }
+        (FieldKind::Ip, Op::In) => {
+            let (net, prefix) = cut_err(parse_cidr)
+                .context(expected("CIDR such as 10.0.0.0/8"))
+                .parse_next(s)?;
+            Comparison::Cidr(field, net, prefix)
+        }
+        (FieldKind::Time, Op::Eq | Op::Ne | Op::Ge | Op::Le | Op::Gt | Op::Lt) => {
+            let t = cut_err(parse_time_literal)
+                .context(expected("quoted RFC 3339 or nginx timestamp"))
+                .parse_next(s)?;
+            Comparison::Time(op, t)
+        }
+        (FieldKind::Time, Op::In) => {
+            let (start, end) = cut_err(separated_pair(
+                parse_time_literal,
+                (multispace0, "..", multispace0),
+                parse_time_literal,
+            ))
+            .context(expected(
+                "time range such as \"2015-05-17T08:00:00Z\"..\"2015-05-17T09:00:00Z\"",
+            ))
+            .parse_next(s)?;
+            Comparison::TimeRange(start, end)
+        }
+        _ => {
+            *s = op_start;
+            return cut_err(fail)
+                .context(StrContext::Label("operator for this field"))
+                .parse_next(s);
+        }
+    };
+    Ok(cmp)
+}
+
+fn parse_op(s: &mut &str) -> PResult<Op> {
+    alt((
+        "==".value(Op::Eq),
+        "!=".value(Op::Ne),
+        ">=".value(Op::Ge),
+        "<=".value(Op::Le),
+        ">".value(Op::Gt),
+        "<".value(Op::Lt),
+        "!~".value(Op::NotMatch),
+        "~".value(Op::Match),
+        keyword("like").value(Op::Like),
+        keyword("in").value(Op::In),
+    ))
+    .parse_next(s)
+}
+
+fn keyword<'s>(kw: &'static str) -> impl Parser<&'s str, &'s str, winnow::error::ContextError> {
+    delimited(multispace0, Caseless(kw), not(one_of(is_ident_char)))
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanum() || c == '_'
+}
+
+// a double-quoted string with `\` escapes, or a bare word like `POST`
+fn parse_string(s: &mut &str) -> PResult<String> {
+    alt((
+        delimited(
+            '"',
+            repeat(0.., alt((none_of(['"', '\\']), preceded('\\', any)))),
+            cut_err('"').context(expected("closing quote")),
+        ),
+        take_while(1.., |c: char| !c.is_whitespace() && c != '(' && c != ')')
+            .map(|v: &str| v.to_string()),
+    ))
+    .parse_next(s)
+}
+
+fn parse_ip_literal(s: &mut &str) -> PResult<IpAddr> {
+    take_while(1.., |c: char| c.is_hex_digit() || c == '.' || c == ':')
+        .parse_to()
+        .parse_next(s)
+}
+
+pub fn parse_cidr(s: &mut &str) -> PResult<(IpAddr, u8)> {
+    (
+        parse_ip_literal,
+        opt(preceded('/', digit1.parse_to::<u8>())),
+    )
+        .verify_map(|(ip, prefix)| {
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            match prefix {
+                Some(p) if p > max => None,
+                Some(p) => Some((ip, p)),
+                None => Some((ip, max)),
+            }
+        })
+        .parse_next(s)
+}
+
+fn parse_time_literal(s: &mut &str) -> PResult<DateTime<Utc>> {
+    parse_string
+        .try_map(|v| {
+            DateTime::parse_from_rfc3339(&v)
+                .or_else(|_| DateTime::parse_from_str(&v, "%d/%b/%Y:%H:%M:%S %z"))
+                .map(|dt| dt.with_timezone(&Utc))
+        })
+        .parse_next(s)
+}
+
+pub fn glob_to_regex(glob: &str) -> Result<Regex, regex::Error> {
+    let pattern = glob
+        .chars()
+        .map(|c| match c {
+            '*' => ".*".to_string(),
+            '?' => ".".to_string(),
+            c => regex::escape(&c.to_string()),
+        })
+        .collect::<String>();
+    Regex::new(&format!("^{}$", pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_should_evaluate_boolean_logic() -> anyhow::Result<()> {
+        let f = parse_filter(r#"status >= 500 and method == "POST" and url ~ "^/api/""#)?;
+        assert!(f.matches(
+            &NginxLog::builder()
+                .method("POST")
+                .url("/api/x")
+                .status(502)
+                .build()
+        ));
+        assert!(!f.matches(&NginxLog::builder().url("/api/x").status(502).build()));
+        assert!(!f.matches(
+            &NginxLog::builder()
+                .method("POST")
+                .url("/web")
+                .status(502)
+                .build()
+        ));
+
+        let f = parse_filter("not (status < 400 or request_time > 1) and method == post")?;
+        assert!(f.matches(
+            &NginxLog::builder()
+                .method("POST")
+                .status(404)
+                .request_time("0.25")
+                .build()
+        ));
+        assert!(!f.matches(
+            &NginxLog::builder()
+                .method("POST")
+                .request_time("0.25")
+                .build()
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn filter_should_match_cidr_glob_and_time() -> anyhow::Result<()> {
+        let f = parse_filter("addr in 10.0.0.0/8 and url like \"/static/*.js\"")?;
+        assert!(f.matches(
+            &NginxLog::builder()
+                .addr("10.1.2.3")
+                .url("/static/app.js")
+                .build()
+        ));
+        assert!(!f.matches(
+            &NginxLog::builder()
+                .addr("11.1.2.3")
+                .url("/static/app.js")
+                .build()
+        ));
+        assert!(!f.matches(
+            &NginxLog::builder()
+                .addr("10.1.2.3")
+                .url("/static/app.css")
+                .build()
+        ));
+
+        let f = parse_filter(r#"time in "2015-05-17T08:00:00Z".."2015-05-17T09:00:00Z""#)?;
+        assert!(f.matches(&NginxLog::builder().build()));
+        let f = parse_filter(r#"datetime < "17/May/2015:08:00:00 +0000""#)?;
+        assert!(!f.matches(&NginxLog::builder().build()));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_filter_should_point_at_offending_token() {
+        let err = parse_filter("status >= 500 and methd == \"GET\"").unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("\n                  ^\n"), "{}", msg);
+        assert!(msg.contains("invalid field"), "{}", msg);
+
+        let err = parse_filter("status ~ \"5..\"").unwrap_err().to_string();
+        assert!(
+            err.contains("\n       ^\ninvalid operator for this field"),
+            "{}",
+            err
+        );
+
+        let err = parse_filter("url ~ \"(\"").unwrap_err().to_string();
+        assert!(err.contains("invalid regex"), "{}", err);
+
+        assert!(parse_filter("status >= 500 500").is_err());
+        assert!(parse_filter("addr in 10.0.0.0/33").is_err());
+        assert!(parse_filter("(status == 200").is_err());
+    }
+}
@@ -0,0 +1,620 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{
+    log_format::{self, LogFormat, Segment},
+    sample::SplitMix64,
+};
+
+const METHODS: &[&str] = &[
+    "GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS", "CONNECT", "TRACE", "PATCH",
+];
+const DEFAULT_METHODS: &[(&str, f64)] = &[
+    ("GET", 85.0),
+    ("POST", 9.0),
+    ("HEAD", 3.0),
+    ("PUT", 2.0),
+    ("DELETE", 1.0),
+];
+const DEFAULT_STATUSES: &[(&str, f64)] = &[
+    ("200", 80.0),
+    ("304", 6.0),
+    ("301", 2.0),
+    ("404", 7.0),
+    ("403", 1.5),
+    ("500", 2.0),
+    ("502", 1.0),
+    ("503", 0.5),
+];
+const DEFAULT_PATHS: &[(&str, f64)] = &[
+    ("/", 10.0),
+    ("/api/v1/items/{id}", 30.0),
+    ("/api/v1/users/{id}/orders/{uuid}", 8.0),
+    ("/static/app.{hash}.js", 15.0),
+    ("/downloads/product_{id}", 10.0),
+    ("/search?q={word}&page={id}", 10.0),
+    ("/login", 5.0),
+];
+const DEFAULT_USER_AGENTS: &[(&str, f64)] = &[
+    (
+        "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36",
+        40.0,
+    ),
+    (
+        "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0",
+        15.0,
+    ),
+    (
+        "Mozilla/5.0 (iPhone; CPU iPhone OS 17_5 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.5 Mobile/15E148 Safari/604.1",
+        20.0,
+    ),
+    (
+        "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+        5.0,
+    ),
+    ("curl/8.4.0", 10.0),
+    ("Debian APT-HTTP/1.3 (1.0.1ubuntu2)", 10.0),
+];
+const WORDS: &[&str] = &["nginx", "winnow", "parser", "rust", "logs", "arrow"];
+const HOST: &str = "example.com";
+
+// variables `Generator::render` knows how to fill
+const SUPPORTED_VARS: &[&str] = &[
+    "remote_addr",
+    "remote_user",
+    "time_local",
+    "time_iso8601",
+    "msec",
+    "request",
+    "request_method",
+    "request_uri",
+    "uri",
+    "args",
+    "server_protocol",
+    "status",
+    "body_bytes_sent",
+    "bytes_sent",
+    "request_length",
+    "request_time",
+    "http_referer",
+    "http_user_agent",
+    "http_x_forwarded_for",
+    "host",
+    "request_id",
+    "req_id",
+    "proxy_upstream_name",
+    "proxy_alternative_upstream_name",
+    "upstream_addr",
+    "upstream_status",
+    "upstream_response_time",
+    "upstream_response_length",
+    "upstream_connect_time",
+    "upstream_header_time",
+];
+
+/// Parse a `value=weight` pair; without a numeric weight the whole string is
+/// the value with weight 1.
+pub fn parse_weighted(s: &str) -> anyhow::Result<(String, f64)> {
+    match s.rsplit_once('=').map(|(v, w)| (v, w.parse::<f64>())) {
+        Some((value, Ok(weight))) => {
+            anyhow::ensure!(weight >= 0.0, "Weight must not be negative: {}", s);
+            Ok((value.to_string(), weight))
+        }
+        _ => Ok((s.to_string(), 1.0)),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratorConfig {
+    pub seed: u64,
+    pub start: DateTime<Utc>,
+    /// Mean requests per second; arrivals are exponentially spaced
+    pub rate: f64,
+    /// Size of the client address pool
+    pub clients: usize,
+    pub ipv6_ratio: f64,
+    pub malformed_rate: f64,
+    pub methods: Vec<(String, f64)>,
+    pub statuses: Vec<(String, f64)>,
+    /// Paths with `{id}`, `{uuid}`, `{hash}` and `{word}` placeholders
+    pub paths: Vec<(String, f64)>,
+    pub user_agents: Vec<(String, f64)>,
+    /// A `log_format` layout, or `combined`
+    pub layout: String,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            start: DateTime::parse_from_rfc3339("2015-05-17T08:00:00Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            rate: 10.0,
+            clients: 500,
+            ipv6_ratio: 0.1,
+            malformed_rate: 0.0,
+            methods: Vec::new(),
+            statuses: Vec::new(),
+            paths: Vec::new(),
+            user_agents: Vec::new(),
+            layout: "combined".to_string(),
+        }
+    }
+}
+
+/// One synthetic request, before it is rendered with the layout.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub addr: IpAddr,
+    /// Second precision, like `$time_local`
+    pub time: DateTime<Utc>,
+    pub msec: f64,
+    pub method: String,
+    pub url: String,
+    pub protocol: &'static str,
+    pub status: u16,
+    pub body_bytes: u64,
+    pub referer: String,
+    pub user_agent: String,
+    /// Millisecond precision, like `$request_time`
+    pub request_time: f64,
+    pub request_length: u64,
+    pub request_id: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Generated {
+    pub request: Request,
+    pub line: String,
+    /// The line was damaged on purpose and does not parse with the layout
+    pub malformed: bool,
+}
+
+struct Weighted {
+    items: Vec<(String, f64)>,
+    total: f64,
+}
+
+impl Weighted {
+    fn new(name: &str, items: &[(String, f64)], defaults: &[(&str, f64)]) -> anyhow::Result<Self> {
+        let items = if items.is_empty() {
+            defaults
+                .iter()
+                .map(|(v, w)| (v.to_string(), *w))
+                .collect::<Vec<_>>()
+        } else {
+            items.to_vec()
+        };
+        let total = items.iter().map(|(_, w)| w).sum::<f64>();
+        anyhow::ensure!(total > 0.0, "At least one {} needs a positive weight", name);
+        Ok(Self { items, total })
+    }
+
+    fn pick(&self, rng: &mut SplitMix64) -> &str {
+        let mut x = rng.next_f64() * self.total;
+        for (value, weight) in &self.items {
+            if x < *weight {
+                return value;
+            }
+            x -= weight;
+        }
+        &self.items.last().unwrap().0
+    }
+}
+
+/// Deterministic generator of access log lines.
+pub struct Generator {
+    rng: SplitMix64,
+    layout: Vec<Segment>,
+    // checks damaged lines; `None` for layouts `LogFormat` cannot read
+    format: Option<LogFormat>,
+    start: DateTime<Utc>,
+    rate: f64,
+    malformed_rate: f64,
+    clients: Vec<IpAddr>,
+    methods: Weighted,
+    statuses: Weighted,
+    paths: Weighted,
+    user_agents: Weighted,
+    elapsed: f64,
+}
+
+impl Generator {
+    pub fn new(config: &GeneratorConfig) -> anyhow::Result<Self> {
+        let layout = log_format::parse_layout(&config.layout)?;
+        for segment in &layout {
+            if let Segment::Var(var) = segment {
+                anyhow::ensure!(
+                    SUPPORTED_VARS.contains(&var.as_str()),
+                    "Unsupported variable in log_format: ${}",
+                    var
+                );
+            }
+        }
+        anyhow::ensure!(config.rate > 0.0, "Rate must be positive");
+        anyhow::ensure!(config.clients > 0, "At least one client is needed");
+        anyhow::ensure!(
+            (0.0..=1.0).contains(&config.ipv6_ratio),
+            "IPv6 ratio must be between 0 and 1"
+        );
+        anyhow::ensure!(
+            (0.0..=1.0).contains(&config.malformed_rate),
+            "Malformed rate must be between 0 and 1"
+        );
+
+        let methods = Weighted::new("method", &config.methods, DEFAULT_METHODS)?;
+        if let Some((m, _)) = methods
+            .items
+            .iter()
+            .find(|(m, _)| !METHODS.contains(&m.as_str()))
+        {
+            return Err(anyhow!("Unsupported method: {}", m));
+        }
+        let statuses = Weighted::new("status", &config.statuses, DEFAULT_STATUSES)?;
+        for (status, _) in &statuses.items {
+            status
+                .parse::<u16>()
+                .map_err(|_| anyhow!("Invalid status: {}", status))?;
+        }
+        let user_agents = Weighted::new("user agent", &config.user_agents, DEFAULT_USER_AGENTS)?;
+        anyhow::ensure!(
+            user_agents
+                .items
+                .iter()
+                .all(|(ua, _)| !ua.is_empty() && !ua.contains('"')),
+            "User agents must be non-empty and contain no `\"`"
+        );
+
+        let mut rng = SplitMix64(config.seed);
+        let clients = (0..config.clients)
+            .map(|_| {
+                if rng.next_f64() < config.ipv6_ratio {
+                    IpAddr::V6(Ipv6Addr::new(
+                        0x2001,
+                        0xdb8,
+                        rng.below(0x10000) as u16,
+                        rng.below(0x10000) as u16,
+                        0,
+                        0,
+                        0,
+                        rng.below(0xffff) as u16 + 1,
+                    ))
+                } else {
+                    let v = rng.next_u64() as u32;
+                    // keep the first octet away from 0 and the multicast range
+                    IpAddr::V4(Ipv4Addr::from(v % 0xd000_0000 + 0x0100_0000))
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            rng,
+            layout,
+            format: LogFormat::new(&config.layout).ok(),
+            start: config.start,
+            rate: config.rate,
+            malformed_rate: config.malformed_rate,
+            clients,
+            methods,
+            statuses,
+            paths: Weighted::new("path", &config.paths, DEFAULT_PATHS)?,
+            user_agents,
+            elapsed: 0.0,
+        })
+    }
+
+    pub fn next_request(&mut self) -> Request {
+        // exponential inter-arrival times give a Poisson request stream
+        self.elapsed += -(1.0 - self.rng.next_f64()).ln() / self.rate;
+        let time = self.start + Duration::seconds(self.elapsed as i64);
+        let msec = self.start.timestamp() as f64 + (self.elapsed * 1000.0).floor() / 1000.0;
+
+        let addr = self.clients[self.rng.below(self.clients.len() as u64) as usize];
+        let method = self.methods.pick(&mut self.rng).to_string();
+        let path = self.paths.pick(&mut self.rng).to_string();
+        let url = self.fill_placeholders(&path);
+        let protocol = if self.rng.next_f64() < 0.8 {
+            "HTTP/1.1"
+        } else {
+            "HTTP/2.0"
+        };
+        let status = self.statuses.pick(&mut self.rng).parse().unwrap_or(200);
+        let body_bytes = if method == "HEAD" || status == 204 || status == 304 {
+            0
+        } else {
+            self.exponential(5000.0).round() as u64
+        };
+        let referer = if self.rng.next_f64() < 0.6 {
+            "-".to_string()
+        } else {
+            let path = self.paths.pick(&mut self.rng).to_string();
+            format!("https://{}{}", HOST, self.fill_placeholders(&path))
+        };
+        let user_agent = self.user_agents.pick(&mut self.rng).to_string();
+        let request_time = (self.exponential(0.05) * 1000.0).round() / 1000.0;
+        let request_length = 200 + self.rng.below(800);
+        let request_id = format!("{:016x}{:016x}", self.rng.next_u64(), self.rng.next_u64());
+
+        Request {
+            addr,
+            time,
+            msec,
+            method,
+            url,
+            protocol,
+            status,
+            body_bytes,
+            referer,
+            user_agent,
+            request_time,
+            request_length,
+            request_id,
+        }
+    }
+
+    /// Render a request with the layout. `corrupt` names a variable whose
+    /// value is replaced by `-`.
+    pub fn render(&self, req: &Request, corrupt: Option<&str>) -> String {
+        let (uri, args) = req.url.split_once('?').unwrap_or((&req.url, ""));
+        self.layout
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(v) => v.clone(),
+                Segment::Var(var) if Some(var.as_str()) == corrupt => "-".to_string(),
+                Segment::Var(var) => match var.as_str() {
+                    "remote_addr" => req.addr.to_string(),
+                    "remote_user" | "http_x_forwarded_for" => "-".to_string(),
+                    "time_local" => req.time.format("%d/%b/%Y:%H:%M:%S %z").to_string(),
+                    "time_iso8601" => req.time.format("%Y-%m-%dT%H:%M:%S%:z").to_string(),
+                    "msec" => format!("{:.3}", req.msec),
+                    "request" => format!("{} {} {}", req.method, req.url, req.protocol),
+                    "request_method" => req.method.clone(),
+                    "request_uri" => req.url.clone(),
+                    "uri" => uri.to_string(),
+                    "args" => args.to_string(),
+                    "server_protocol" => req.protocol.to_string(),
+                    "status" | "upstream_status" => req.status.to_string(),
+                    "body_bytes_sent" => req.body_bytes.to_string(),
+                    "bytes_sent" => (req.body_bytes + 250).to_string(),
+                    "request_length" => req.request_length.to_string(),
+                    "request_time" => format!("{:.3}", req.request_time),
+                    "upstream_response_time" => format!("{:.3}", req.request_time * 0.9),
+                    "upstream_connect_time" => format!("{:.3}", req.request_time * 0.1),
+                    "upstream_header_time" => format!("{:.3}", req.request_time * 0.5),
+                    "upstream_addr" => "10.0.0.1:8080".to_string(),
+                    "http_referer" => req.referer.clone(),
+                    "http_user_agent" => req.user_agent.clone(),
+                    "host" => HOST.to_string(),
+                    "request_id" | "req_id" => req.request_id.clone(),
+                    "proxy_upstream_name" => "default-web-80".to_string(),
+                    "proxy_alternative_upstream_name" => String::new(),
+                    "upstream_response_length" => req.body_bytes.to_string(),
+                    _ => "-".to_string(),
+                },
+            })
+            .collect()
+    }
+
+    // Damage a line so that the layout's parser rejects it. The damage is
+    // picked from what the layout contains, and a line that still parses is
+    // replaced by garbage.
+    fn malform(&mut self, req: &Request) -> String {
+        let garbage = |rng: &mut SplitMix64| format!("%% garbage {} %%", rng.next_u64());
+        let line = match self.rng.below(4) {
+            0 => {
+                // cut inside the first quoted value, or anywhere without quotes
+                let line = self.render(req, None);
+                let (start, end) = match line.find('"') {
+                    Some(q) => (
+                        q + 1,
+                        line[q + 1..].find('"').map_or(line.len(), |i| q + 1 + i),
+                    ),
+                    None => (0, line.len()),
+                };
+                let mut cut = start + self.rng.below((end - start + 1) as u64) as usize;
+                while !line.is_char_boundary(cut) {
+                    cut -= 1;
+                }
+                line[..cut].to_string()
+            }
+            1 if self.has_var(&["status"]) => self.render(req, Some("status")),
+            2 => match ["time_local", "time_iso8601", "msec"]
+                .into_iter()
+                .find(|v| self.has_var(&[v]))
+            {
+                Some(var) => self.render(req, Some(var)),
+                None => garbage(&mut self.rng),
+            },
+            _ => garbage(&mut self.rng),
+        };
+        match &self.format {
+            Some(format) if format.parse(&line).is_ok() => garbage(&mut self.rng),
+            _ => line,
+        }
+    }
+
+    fn has_var(&self, names: &[&str]) -> bool {
+        self.layout
+            .iter()
+            .any(|v| matches!(v, Segment::Var(var) if names.contains(&var.as_str())))
+    }
+
+    fn fill_placeholders(&mut self, path: &str) -> String {
+        let mut out = String::new();
+        let mut rest = path;
+        while let Some(open) = rest.find('{') {
+            let Some(close) = rest[open..].find('}').map(|i| open + i) else {
+                break;
+            };
+            out.push_str(&rest[..open]);
+            match &rest[open + 1..close] {
+                "id" => out.push_str(&(self.rng.below(100_000) + 1).to_string()),
+                "uuid" => {
+                    let (a, b) = (self.rng.next_u64(), self.rng.next_u64());
+                    out.push_str(&format!(
+                        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+                        a >> 32,
+                        (a >> 16) & 0xffff,
+                        a & 0xffff,
+                        b >> 48,
+                        b & 0xffff_ffff_ffff
+                    ));
+                }
+                "hash" => out.push_str(&format!("{:016x}", self.rng.next_u64())),
+                "word" => out.push_str(WORDS[self.rng.below(WORDS.len() as u64) as usize]),
+                other => out.push_str(&format!("{{{}}}", other)),
+            }
+            rest = &rest[close + 1..];
+        }
+        out.push_str(rest);
+        out
+    }
+
+    fn exponential(&mut self, mean: f64) -> f64 {
+        -(1.0 - self.rng.next_f64()).ln() * mean
+    }
+}
+
+impl Iterator for Generator {
+    type Item = Generated;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let request = self.next_request();
+        let malformed = self.rng.next_f64() < self.malformed_rate;
+        let line = if malformed {
+            self.malform(&request)
+        } else {
+            self.render(&request, None)
+        };
+        Some(Generated {
+            request,
+            line,
+            malformed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_nginx_log;
+
+    #[test]
+    fn parse_weighted_should_work() -> anyhow::Result<()> {
+        assert_eq!(parse_weighted("GET=90")?, ("GET".to_string(), 90.0));
+        assert_eq!(
+            parse_weighted("/search?q=x")?,
+            ("/search?q=x".to_string(), 1.0)
+        );
+        assert_eq!(parse_weighted("/a?b=c=2.5")?, ("/a?b=c".to_string(), 2.5));
+        assert!(parse_weighted("200=-1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn generator_should_round_trip_through_parser() -> anyhow::Result<()> {
+        let config = GeneratorConfig {
+            seed: 42,
+            ipv6_ratio: 0.3,
+            malformed_rate: 0.2,
+            ..Default::default()
+        };
+        let mut malformed = 0;
+        for generated in Generator::new(&config)?.take(2000) {
+            let parsed = parse_nginx_log(&generated.line);
+            if generated.malformed {
+                assert!(parsed.is_err(), "{}", generated.line);
+                malformed += 1;
+                continue;
+            }
+            let log = parsed.unwrap_or_else(|e| panic!("{}: {:?}", generated.line, e));
+            let req = generated.request;
+            assert_eq!(log.addr, req.addr);
+            assert_eq!(log.datetime, req.time);
+            assert_eq!(log.method.to_string().to_uppercase(), req.method);
+            assert_eq!(log.url, req.url);
+            assert_eq!(log.status, req.status);
+            assert_eq!(log.body_bytes, req.body_bytes);
+            assert_eq!(log.referer, req.referer);
+            assert_eq!(log.user_agent, req.user_agent);
+            assert_eq!(log.request_time, None);
+        }
+        assert!((300..500).contains(&malformed), "{}", malformed);
+        Ok(())
+    }
+
+    #[test]
+    fn generator_should_be_deterministic_and_follow_layout() -> anyhow::Result<()> {
+        let config = GeneratorConfig {
+            layout: format!("{} $request_time", log_format::COMBINED),
+            statuses: vec![("418".to_string(), 1.0)],
+            ..Default::default()
+        };
+        let lines = |config: &GeneratorConfig| -> anyhow::Result<Vec<String>> {
+            Ok(Generator::new(config)?.take(50).map(|g| g.line).collect())
+        };
+        let first = lines(&config)?;
+        assert_eq!(first, lines(&config)?);
+        assert_ne!(
+            first,
+            lines(&GeneratorConfig {
+                seed: 1,
+                ..config.clone()
+            })?
+        );
+
+        let mut generator = Generator::new(&config)?;
+        let generated = generator.next().unwrap();
+        let log = parse_nginx_log(&generated.line).unwrap();
+        assert_eq!(log.status, 418);
+        assert_eq!(log.request_time, Some(generated.request.request_time));
+
+        let bad = GeneratorConfig {
+            layout: "$remote_addr $nope".to_string(),
+            ..Default::default()
+        };
+        assert!(Generator::new(&bad).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_lines_should_fail_their_layout() -> anyhow::Result<()> {
+        let layout = "$remote_addr [$time_iso8601] $status $body_bytes_sent $request";
+        let config = GeneratorConfig {
+            layout: layout.to_string(),
+            malformed_rate: 0.5,
+            paths: vec![("/straße/{id}?q=ü".to_string(), 1.0)],
+            user_agents: vec![("Navigateur/1.0 (é)".to_string(), 1.0)],
+            ..Default::default()
+        };
+        let format = LogFormat::new(layout)?;
+        for generated in Generator::new(&config)?.take(1000) {
+            let parsed = format.parse(&generated.line);
+            assert_eq!(parsed.is_err(), generated.malformed, "{}", generated.line);
+        }
+
+        // cuts inside a non-ASCII request line land on a char boundary
+        let config = GeneratorConfig {
+            layout: log_format::COMBINED.to_string(),
+            malformed_rate: 1.0,
+            ..config
+        };
+        for generated in Generator::new(&config)?.take(500) {
+            assert!(
+                parse_nginx_log(&generated.line).is_err(),
+                "{}",
+                generated.line
+            );
+        }
+
+        let bad = GeneratorConfig {
+            ipv6_ratio: 1.5,
+            ..Default::default()
+        };
+        assert!(Generator::new(&bad).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use anyhow::anyhow;
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use serde::Deserialize;
+
+use crate::NginxLog;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeoInfo {
+    pub country: Option<String>,
+    pub city: Option<String>,
+    pub asn: Option<u32>,
+    pub as_org: Option<String>,
+}
+
+// Superset of the GeoIP2/GeoLite2 City, Country and ASN layouts, so any of
+// those databases (or one that combines them) can be used.
+#[derive(Debug, Deserialize)]
+struct GeoRecord<'a> {
+    #[serde(borrow)]
+    country: Option<geoip2::city::Country<'a>>,
+    #[serde(borrow)]
+    city: Option<geoip2::city::City<'a>>,
+    autonomous_system_number: Option<u32>,
+    autonomous_system_organization: Option<&'a str>,
+}
+
+/// Offline GeoIP/ASN lookups against local `.mmdb` files with a per-address cache.
+pub struct GeoIp {
+    readers: Vec<Reader<Vec<u8>>>,
+    cache: HashMap<IpAddr, GeoInfo>,
+}
+
+impl GeoIp {
+    pub fn open(paths: &[String]) -> anyhow::Result<Self> {
+        let readers = paths
+            .iter()
+            .map(|p| Reader::open_readfile(p).map_err(|e| anyhow!("Failed to open {}: {}", p, e)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self::new(readers))
+    }
+
+    fn new(readers: Vec<Reader<Vec<u8>>>) -> Self {
+        Self {
+            readers,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Fill in `geo` for every log record.
+    pub fn apply(&mut self, logs: &mut [NginxLog]) -> anyhow::Result<()> {
+        for log in logs {
+            log.geo = Some(self.lookup(log.client())?);
+        }
+        Ok(())
+    }
+
+    /// Look up an address in every database; later databases only fill the
+    /// fields earlier ones left empty.
+    pub fn lookup(&mut self, addr: IpAddr) -> anyhow::Result<GeoInfo> {
+        if let Some(info) = self.cache.get(&addr) {
+            return Ok(info.clone());
+        }
+
+        let mut info = GeoInfo::default();
+        for reader in &self.readers {
+            let record: GeoRecord = match reader.lookup(addr) {
+                Ok(record) => record,
+                Err(MaxMindDBError::AddressNotFoundError(_)) => continue,
+                Err(e) => return Err(anyhow!("GeoIP lookup of {} failed: {}", addr, e)),
+            };
+            info.country = info.country.or_else(|| {
+                record
+                    .country
+                    .and_then(|c| c.iso_code)
+                    .map(|v| v.to_string())
+            });
+            info.city = info.city.or_else(|| {
+                record
+                    .city
+                    .and_then(|c| c.names)
+                    .and_then(|names| names.get("en").map(|v| v.to_string()))
+            });
+            info.asn = info.asn.or(record.autonomous_system_number);
+            info.as_org = info
+                .as_org
+                .or_else(|| record.autonomous_system_organization.map(|v| v.to_string()));
+        }
+
+        self.cache.insert(addr, info.clone());
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Minimal MaxMind DB writer: an IPv4 tree with a single node whose right
+    // branch (128.0.0.0/1) points at one data record.
+    fn string(s: &str) -> Vec<u8> {
+        let mut v = match s.len() {
+            n if n < 29 => vec![0x40 | n as u8],
+            n => vec![0x40 | 29, (n - 29) as u8],
+        };
+        v.extend_from_slice(s.as_bytes());
+        v
+    }
+
+    fn map(entries: Vec<(&str, Vec<u8>)>) -> Vec<u8> {
+        let mut v = vec![0xe0 | entries.len() as u8];
+        for (k, val) in entries {
+            v.extend(string(k));
+            v.extend(val);
+        }
+        v
+    }
+
+    fn uint16(n: u16) -> Vec<u8> {
+        let mut v = vec![0xa0 | 2];
+        v.extend_from_slice(&n.to_be_bytes());
+        v
+    }
+
+    fn uint32(n: u32) -> Vec<u8> {
+        let mut v = vec![0xc0 | 4];
+        v.extend_from_slice(&n.to_be_bytes());
+        v
+    }
+
+    fn build_db(record: Vec<u8>) -> Vec<u8> {
+        let mut db = vec![0, 0, 1, 0, 0, 17];
+        db.extend([0u8; 16]);
+        db.extend(record);
+        db.extend(b"\xab\xcd\xefMaxMind.com");
+        db.extend(map(vec![
+            ("binary_format_major_version", uint16(2)),
+            ("binary_format_minor_version", uint16(0)),
+            ("build_epoch", vec![0x01, 0x02, 0x00]),
+            ("database_type", string("Test")),
+            ("description", map(vec![])),
+            ("ip_version", uint16(4)),
+            ("languages", vec![0x00, 0x04]),
+            ("node_count", uint32(1)),
+            ("record_size", uint16(24)),
+        ]));
+        db
+    }
+
+    fn test_geoip() -> GeoIp {
+        let city = build_db(map(vec![
+            (
+                "country",
+                map(vec![
+                    ("iso_code", string("AU")),
+                    ("names", map(vec![("en", string("Australia"))])),
+                ]),
+            ),
+            (
+                "city",
+                map(vec![("names", map(vec![("en", string("Sydney"))]))]),
+            ),
+        ]));
+        let asn = build_db(map(vec![
+            ("autonomous_system_number", uint32(13335)),
+            ("autonomous_system_organization", string("Cloudflare")),
+        ]));
+        GeoIp::new(vec![
+            Reader::from_source(city).unwrap(),
+            Reader::from_source(asn).unwrap(),
+        ])
+    }
+
+    #[test]
+    fn lookup_should_merge_databases() -> anyhow::Result<()> {
+        let mut geoip = test_geoip();
+        let info = geoip.lookup("203.0.113.7".parse()?)?;
+        assert_eq!(
+            info,
+            GeoInfo {
+                country: Some("AU".to_string()),
+                city: Some("Sydney".to_string()),
+                asn: Some(13335),
+                as_org: Some("Cloudflare".to_string()),
+            }
+        );
+        assert_eq!(geoip.cache.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn lookup_should_tolerate_unknown_addresses() -> anyhow::Result<()> {
+        let mut geoip = test_geoip();
+        assert_eq!(geoip.lookup("10.0.0.1".parse()?)?, GeoInfo::default());
+        Ok(())
+    }
+
+    #[test]
+    fn open_should_fail_on_missing_file() {
+        assert!(GeoIp::open(&["/nonexistent/GeoLite2-City.mmdb".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,488 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use winnow::{
+    ascii::digit1,
+    combinator::{alt, delimited, eof, opt, preceded, rest, separated_pair, terminated},
+    token::{any, take, take_till, take_while},
+    PResult, Parser,
+};
+
+use crate::{parse_http_method, parse_http_proto, parse_http_url, HttpMethod, HttpProto, NginxLog};
+
+// the server of requests HAProxy answered itself
+const NO_SERVER: &str = "<NOSRV>";
+
+/// A timer of the `Tq/Tw/Tc/Tr/Ta` group. HAProxy writes `-1` when the
+/// session ended before the step was reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timer {
+    Elapsed(Duration),
+    Aborted,
+}
+
+impl Timer {
+    pub fn elapsed(&self) -> Option<Duration> {
+        match self {
+            Timer::Elapsed(v) => Some(*v),
+            Timer::Aborted => None,
+        }
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        *self == Timer::Aborted
+    }
+}
+
+impl fmt::Display for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timer::Elapsed(v) => write!(f, "{}", v.as_millis()),
+            Timer::Aborted => write!(f, "-1"),
+        }
+    }
+}
+
+/// `hostname process[pid]: ` of the syslog header. Its timestamp has no
+/// year and is dropped in favour of `accept_date`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Syslog {
+    pub host: String,
+    pub process: String,
+    pub pid: Option<u32>,
+}
+
+/// The four characters of the termination state, `-` where nothing happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TerminationState {
+    /// What ended the session, e.g. `C` client abort, `S` server error
+    pub cause: char,
+    /// The session state then, e.g. `R` request, `H` headers, `D` data
+    pub state: char,
+    pub persistence_cookie: char,
+    pub set_cookie: char,
+}
+
+impl TerminationState {
+    /// Whether the session ended normally, `--` in the first two places.
+    pub fn is_normal(&self) -> bool {
+        self.cause == '-' && self.state == '-'
+    }
+}
+
+impl fmt::Display for TerminationState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}{}",
+            self.cause, self.state, self.persistence_cookie, self.set_cookie
+        )
+    }
+}
+
+/// One line of HAProxy's `option httplog` format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HaproxyLog {
+    pub syslog: Option<Syslog>,
+    pub client: SocketAddr,
+    /// HAProxy writes local time without an offset; it is read as UTC
+    pub accept_date: DateTime<Utc>,
+    pub frontend: String,
+    /// The `~` suffix of frontends that accepted the connection over SSL
+    pub ssl: bool,
+    pub backend: String,
+    /// `None` for `<NOSRV>`, when no server was picked
+    pub server: Option<String>,
+    /// Time to receive the full request headers
+    pub tq: Timer,
+    /// Time waiting in the queues
+    pub tw: Timer,
+    /// Time to connect to the server
+    pub tc: Timer,
+    /// Time for the server to send the response headers
+    pub tr: Timer,
+    /// Total time of the session
+    pub ta: Timer,
+    /// `None` for `-1`, when no response was sent
+    pub status: Option<u16>,
+    pub bytes_read: u64,
+    /// `+` before `Ta` and the byte count: `option logasap` logged the line
+    /// before the transfer ended
+    pub logasap: bool,
+    pub captured_request_cookie: Option<String>,
+    pub captured_response_cookie: Option<String>,
+    pub termination_state: TerminationState,
+    pub actconn: u32,
+    pub feconn: u32,
+    pub beconn: u32,
+    pub srv_conn: u32,
+    pub retries: u32,
+    /// `+` before the retries: the request was redispatched to another server
+    pub redispatched: bool,
+    pub srv_queue: u32,
+    pub backend_queue: u32,
+    /// The `capture request header` values, in declaration order
+    pub request_headers: Vec<String>,
+    /// The `capture response header` values, in declaration order
+    pub response_headers: Vec<String>,
+    /// The request line as logged, e.g. `GET / HTTP/1.1` or `<BADREQ>`
+    pub request: String,
+}
+
+impl HaproxyLog {
+    /// Split the request line, if it is a complete HTTP request.
+    pub fn http_request(&self) -> Option<(HttpMethod, String, HttpProto)> {
+        (parse_http_method, parse_http_url, parse_http_proto, eof)
+            .parse(self.request.as_str())
+            .ok()
+            .map(|(method, url, protocol, _)| (method, url, protocol))
+    }
+
+    /// The access log record of a complete request that got a response, with
+    /// `Ta` as the request time. Captured headers are left out as their order
+    /// depends on the configuration.
+    pub fn to_nginx_log(&self) -> Option<NginxLog> {
+        let (method, url, protocol) = self.http_request()?;
+        Some(NginxLog {
+            addr: self.client.ip(),
+            datetime: self.accept_date,
+            method,
+            url,
+            protocol,
+            status: self.status?,
+            body_bytes: self.bytes_read,
+            referer: "-".to_string(),
+            user_agent: "-".to_string(),
+            route: None,
+            ua: None,
+            geo: None,
+            request_time: self.ta.elapsed().map(|v| v.as_secs_f64()),
+            source: None,
+            request_length: None,
+            host: None,
+            request_id: None,
+            upstream: None,
+            forwarded_for: Vec::new(),
+            client_ip: None,
+        })
+    }
+}
+
+/// Parse a whole HAProxy log into access log records, skipping lines that
+/// do not parse or carry no complete request.
+pub fn parse_haproxy_logs(input: &str) -> Vec<NginxLog> {
+    input
+        .lines()
+        .filter_map(|v| parse_haproxy_log(v).ok()?.to_nginx_log())
+        .collect()
+}
+
+/// Parse a line such as
+/// `Feb  6 12:14:14 localhost haproxy[14389]: 10.0.1.2:33317 [06/Feb/2009:12:14:14.655] http-in static/srv1 10/0/30/69/109 200 2750 - - ---- 1/1/1/1/0 0/0 {1wt.eu} {} "GET /index.html HTTP/1.1"`.
+/// The syslog header is optional.
+pub fn parse_haproxy_log(s: &str) -> PResult<HaproxyLog> {
+    let input = &mut &*s;
+    let syslog = opt(parse_syslog).parse_next(input)?;
+    let client = terminated(take_till(1.., ' '), ' ')
+        .verify_map(parse_client)
+        .parse_next(input)?;
+    let accept_date = terminated(delimited('[', take_till(1.., ']'), ']'), ' ')
+        .try_map(|v| NaiveDateTime::parse_from_str(v, "%d/%b/%Y:%H:%M:%S%.f"))
+        .parse_next(input)?;
+    let frontend = parse_word(input)?;
+    let (backend, server) = terminated(
+        separated_pair(take_till(1.., '/'), '/', take_till(1.., ' ')),
+        ' ',
+    )
+    .parse_next(input)?;
+    let (tq, _, tw, _, tc, _, tr, _, (logasap, ta)) = terminated(
+        (
+            parse_timer,
+            '/',
+            parse_timer,
+            '/',
+            parse_timer,
+            '/',
+            parse_timer,
+            '/',
+            (parse_plus, parse_timer),
+        ),
+        ' ',
+    )
+    .parse_next(input)?;
+    let status =
+        terminated(alt(("-1".value(None), digit1.parse_to().map(Some))), ' ').parse_next(input)?;
+    let (_, bytes_read) = terminated((parse_plus, digit1.parse_to()), ' ').parse_next(input)?;
+    let captured_request_cookie = parse_word.map(captured).parse_next(input)?;
+    let captured_response_cookie = parse_word.map(captured).parse_next(input)?;
+    let termination_state = terminated((any, any, any, any), ' ')
+        .map(
+            |(cause, state, persistence_cookie, set_cookie)| TerminationState {
+                cause,
+                state,
+                persistence_cookie,
+                set_cookie,
+            },
+        )
+        .parse_next(input)?;
+    let (actconn, _, feconn, _, beconn, _, srv_conn, _, (redispatched, retries)) = terminated(
+        (
+            parse_count,
+            '/',
+            parse_count,
+            '/',
+            parse_count,
+            '/',
+            parse_count,
+            '/',
+            (parse_plus, parse_count),
+        ),
+        ' ',
+    )
+    .parse_next(input)?;
+    let (srv_queue, backend_queue) =
+        terminated(separated_pair(parse_count, '/', parse_count), ' ').parse_next(input)?;
+    // each capture group is only there when configured; a lone group cannot
+    // be told apart and is taken as the more common request captures
+    let headers = (opt(parse_captures), opt(parse_captures)).parse_next(input)?;
+    // a long request line is truncated without its closing quote
+    let request = preceded('"', rest)
+        .map(|v: &str| v.strip_suffix('"').unwrap_or(v).to_string())
+        .parse_next(input)?;
+
+    let (frontend, ssl) = match frontend.strip_suffix('~') {
+        Some(v) => (v, true),
+        None => (frontend, false),
+    };
+    Ok(HaproxyLog {
+        syslog,
+        client,
+        accept_date: accept_date.and_utc(),
+        frontend: frontend.to_string(),
+        ssl,
+        backend: backend.to_string(),
+        server: Some(server.to_string()).filter(|v| v != NO_SERVER),
+        tq,
+        tw,
+        tc,
+        tr,
+        ta,
+        status,
+        bytes_read,
+        logasap,
+        captured_request_cookie,
+        captured_response_cookie,
+        termination_state,
+        actconn,
+        feconn,
+        beconn,
+        srv_conn,
+        retries,
+        redispatched,
+        srv_queue,
+        backend_queue,
+        request_headers: headers.0.unwrap_or_default(),
+        response_headers: headers.1.unwrap_or_default(),
+        request,
+    })
+}
+
+// `<134>Feb  6 12:14:14 localhost haproxy[14389]: `, with an optional
+// priority and either a BSD or an RFC 3339 timestamp
+fn parse_syslog(s: &mut &str) -> PResult<Syslog> {
+    let _ = opt(('<', digit1, '>')).parse_next(s)?;
+    alt((
+        take(15usize).verify(|v: &str| v.as_bytes()[3] == b' ' && v.as_bytes()[9] == b':'),
+        take_till(1.., ' '),
+    ))
+    .parse_next(s)?;
+    let host = preceded(' ', take_till(1.., ' ')).parse_next(s)?;
+    let process = preceded(
+        ' ',
+        take_while(1.., |c: char| !matches!(c, '[' | ':' | ' ')),
+    )
+    .parse_next(s)?;
+    let pid = opt(delimited('[', digit1.parse_to(), ']')).parse_next(s)?;
+    ": ".parse_next(s)?;
+    Ok(Syslog {
+        host: host.to_string(),
+        process: process.to_string(),
+        pid,
+    })
+}
+
+// `10.0.1.2:33317`; IPv6 clients are logged without brackets
+fn parse_client(s: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = s.parse() {
+        return Some(addr);
+    }
+    let (ip, port) = s.rsplit_once(':')?;
+    Some(SocketAddr::new(
+        ip.parse::<IpAddr>().ok()?,
+        port.parse().ok()?,
+    ))
+}
+
+fn parse_word<'s>(s: &mut &'s str) -> PResult<&'s str> {
+    terminated(take_till(1.., ' '), ' ').parse_next(s)
+}
+
+fn parse_timer(s: &mut &str) -> PResult<Timer> {
+    alt((
+        "-1".value(Timer::Aborted),
+        digit1
+            .parse_to()
+            .map(|v| Timer::Elapsed(Duration::from_millis(v))),
+    ))
+    .parse_next(s)
+}
+
+fn parse_count(s: &mut &str) -> PResult<u32> {
+    digit1.parse_to().parse_next(s)
+}
+
+fn parse_plus(s: &mut &str) -> PResult<bool> {
+    opt('+').map(|v| v.is_some()).parse_next(s)
+}
+
+// `{1wt.eu|Mozilla/5.0} `, the values separated by `|`
+fn parse_captures(s: &mut &str) -> PResult<Vec<String>> {
+    terminated(delimited('{', take_till(0.., '}'), '}'), ' ')
+        .map(|v: &str| match v {
+            "" => Vec::new(),
+            v => v.split('|').map(str::to_string).collect(),
+        })
+        .parse_next(s)
+}
+
+fn captured(v: &str) -> Option<String> {
+    Some(v.to_string()).filter(|v| v != "-")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_haproxy_log_should_work() {
+        let s = concat!(
+            "Feb  6 12:14:14 localhost haproxy[14389]: 10.0.1.2:33317 ",
+            "[06/Feb/2009:12:14:14.655] http-in static/srv1 10/0/30/69/109 200 2750 - - ---- ",
+            r#"1/1/1/1/0 0/0 {1wt.eu} {} "GET /index.html HTTP/1.1""#,
+        );
+        let log = parse_haproxy_log(s).unwrap();
+        assert_eq!(
+            log.syslog,
+            Some(Syslog {
+                host: "localhost".to_string(),
+                process: "haproxy".to_string(),
+                pid: Some(14389),
+            })
+        );
+        assert_eq!(log.client, "10.0.1.2:33317".parse().unwrap());
+        assert_eq!(
+            log.accept_date.to_rfc3339(),
+            "2009-02-06T12:14:14.655+00:00"
+        );
+        assert_eq!((log.frontend.as_str(), log.ssl), ("http-in", false));
+        assert_eq!(log.backend, "static");
+        assert_eq!(log.server.as_deref(), Some("srv1"));
+        assert_eq!(log.tc, Timer::Elapsed(Duration::from_millis(30)));
+        assert_eq!(log.ta.elapsed(), Some(Duration::from_millis(109)));
+        assert_eq!(log.status, Some(200));
+        assert_eq!(log.bytes_read, 2750);
+        assert_eq!(log.captured_request_cookie, None);
+        assert!(log.termination_state.is_normal());
+        assert_eq!(
+            (
+                log.actconn,
+                log.feconn,
+                log.beconn,
+                log.srv_conn,
+                log.retries
+            ),
+            (1, 1, 1, 1, 0)
+        );
+        assert_eq!(log.request_headers, vec!["1wt.eu"]);
+        assert!(log.response_headers.is_empty());
+        let (method, url, protocol) = log.http_request().unwrap();
+        assert_eq!(
+            (method, url.as_str(), protocol),
+            (HttpMethod::Get, "/index.html", HttpProto::HTTP1_1)
+        );
+    }
+
+    #[test]
+    fn parse_haproxy_log_should_keep_aborted_timers_apart() {
+        let s = concat!(
+            "<134>2024-03-01T12:00:00+00:00 lb1 haproxy[7]: 10.0.1.2:33313 ",
+            "[01/Mar/2024:12:00:00.443] fe1 fe1/<NOSRV> -1/-1/-1/-1/8490 -1 0 - - CR-- ",
+            r#"2/2/2/0/0 0/0 "<BADREQ>""#,
+        );
+        let log = parse_haproxy_log(s).unwrap();
+        assert_eq!(log.syslog.as_ref().unwrap().host, "lb1");
+        assert_eq!(log.server, None);
+        assert!(log.tq.is_aborted() && log.tr.is_aborted());
+        assert_eq!(log.tr.elapsed(), None);
+        assert_eq!(log.ta.to_string(), "8490");
+        assert_eq!(log.status, None);
+        assert_eq!(log.termination_state.cause, 'C');
+        assert_eq!(log.termination_state.to_string(), "CR--");
+        assert!(!log.termination_state.is_normal());
+        assert_eq!(log.request, "<BADREQ>");
+        assert_eq!(log.http_request(), None);
+        assert!(log.to_nginx_log().is_none());
+    }
+
+    #[test]
+    fn parse_haproxy_log_should_handle_variants() {
+        // no syslog header, IPv6, SSL, logasap, a redispatch, a single
+        // capture group and a truncated request line
+        let s = concat!(
+            "2001:db8::7:51234 [01/Mar/2024:12:00:00.001] https~ api/app2 0/3/1/12/+16 502 +411 ",
+            "sid=1 - sH-- 40/20/8/3/+1 2/5 {api.example.com|curl/8.4.0} ",
+            r#""POST /api/orders?page=2 HTTP/1.1"#,
+        );
+        let log = parse_haproxy_log(s).unwrap();
+        assert_eq!(log.syslog, None);
+        assert_eq!(log.client.ip().to_string(), "2001:db8::7");
+        assert_eq!(log.client.port(), 51234);
+        assert_eq!((log.frontend.as_str(), log.ssl), ("https", true));
+        assert!(log.logasap);
+        assert_eq!(log.bytes_read, 411);
+        assert_eq!(log.captured_request_cookie.as_deref(), Some("sid=1"));
+        assert_eq!((log.retries, log.redispatched), (1, true));
+        assert_eq!((log.srv_queue, log.backend_queue), (2, 5));
+        assert_eq!(log.request_headers, vec!["api.example.com", "curl/8.4.0"]);
+        assert!(log.response_headers.is_empty());
+        assert_eq!(log.request, "POST /api/orders?page=2 HTTP/1.1");
+        assert_eq!(log.http_request().unwrap().1, "/api/orders?page=2");
+
+        assert!(parse_haproxy_log(&s.replace("0/3/1/12", "0/3/x/12")).is_err());
+        assert!(parse_haproxy_log("not a haproxy line").is_err());
+    }
+
+    #[test]
+    fn parse_haproxy_logs_should_map_to_access_logs() {
+        let input = concat!(
+            "10.0.1.2:33317 [06/Feb/2009:12:14:14.655] http-in static/srv1 10/0/30/69/109 ",
+            "404 2750 - - ---- 1/1/1/1/0 0/0 \"GET /missing HTTP/1.1\"\n",
+            "10.0.1.3:33313 [06/Feb/2009:12:14:15.000] fe1 fe1/<NOSRV> -1/-1/-1/-1/8490 -1 0 ",
+            "- - CR-- 2/2/2/0/0 0/0 \"<BADREQ>\"\n",
+            "not a haproxy line\n",
+        );
+        let logs = parse_haproxy_logs(input);
+        assert_eq!(logs.len(), 1);
+        let log = &logs[0];
+        assert_eq!(log.addr.to_string(), "10.0.1.2");
+        assert_eq!(log.datetime.to_rfc3339(), "2009-02-06T12:14:14.655+00:00");
+        assert_eq!(log.method, HttpMethod::Get);
+        assert_eq!(log.url, "/missing");
+        assert_eq!((log.status, log.body_bytes), (404, 2750));
+        assert_eq!(log.request_time, Some(0.109));
+    }
+}
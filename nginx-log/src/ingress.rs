@@ -0,0 +1,238 @@
+use std::{
+    fs::File,
+    sync::{Arc, LazyLock},
+};
+
+use arrow::array::{ArrayRef, RecordBatch, StringArray};
+use parquet::arrow::ArrowWriter;
+use winnow::PResult;
+
+use crate::{log_format::LogFormat, logs_to_record_batch, NginxLog};
+
+/// The default `log-format-upstream` of the ingress-nginx controller.
+pub const LAYOUT: &str = concat!(
+    r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "#,
+    r#""$http_referer" "$http_user_agent" $request_length $request_time "#,
+    r#"[$proxy_upstream_name] [$proxy_alternative_upstream_name] $upstream_addr "#,
+    r#"$upstream_response_length $upstream_response_time $upstream_status $req_id"#,
+);
+
+const DEFAULT_BACKEND: &str = "upstream-default-backend";
+
+static FORMAT: LazyLock<LogFormat> = LazyLock::new(|| LogFormat::new(LAYOUT).unwrap());
+
+/// An ingress-nginx access log record: the nginx fields, including the
+/// upstream lists, plus the Kubernetes backend the request was routed to.
+#[derive(Debug)]
+pub struct IngressLog {
+    pub log: NginxLog,
+    /// `$proxy_upstream_name`, e.g. `shop-frontend-80`
+    pub upstream_name: Option<String>,
+    /// The canary backend, if the request could have gone to one
+    pub alternative_upstream_name: Option<String>,
+    pub namespace: Option<String>,
+    pub service: Option<String>,
+    pub service_port: Option<String>,
+}
+
+/// Parse a line, splitting the upstream name with `split_upstream_name`.
+pub fn parse_ingress_log(s: &str, namespaces: &[String]) -> PResult<IngressLog> {
+    let (log, extra) = FORMAT.parse_with_extra(s)?;
+    let var = |name: &str| {
+        extra
+            .iter()
+            .find(|(var, _)| *var == name)
+            .map(|(_, value)| *value)
+            .filter(|v| !v.is_empty() && *v != "-")
+            .map(str::to_string)
+    };
+    let upstream_name = var("proxy_upstream_name");
+    let backend = upstream_name
+        .as_deref()
+        .and_then(|v| split_upstream_name(v, namespaces));
+    Ok(IngressLog {
+        log,
+        alternative_upstream_name: var("proxy_alternative_upstream_name"),
+        namespace: backend.map(|v| v.0.to_string()),
+        service: backend.map(|v| v.1.to_string()),
+        service_port: backend.map(|v| v.2.to_string()),
+        upstream_name,
+    })
+}
+
+/// Split a `<namespace>-<service>-<port>` upstream name. Both names may
+/// contain `-`, so a name with more than one `-` before the port is only split
+/// when it starts with one of the known `namespaces`, the longest winning.
+pub fn split_upstream_name<'a>(
+    name: &'a str,
+    namespaces: &[String],
+) -> Option<(&'a str, &'a str, &'a str)> {
+    if name == DEFAULT_BACKEND {
+        return None;
+    }
+    let (rest, port) = name.rsplit_once('-')?;
+    let known = namespaces
+        .iter()
+        .filter(|ns| {
+            rest.strip_prefix(ns.as_str())
+                .is_some_and(|v| v.len() > 1 && v.starts_with('-'))
+        })
+        .map(String::len)
+        .max();
+    let (namespace, service) = match known {
+        Some(len) => (&rest[..len], &rest[len + 1..]),
+        None if rest.matches('-').count() == 1 => rest.split_once('-')?,
+        None => return None,
+    };
+    if namespace.is_empty() || service.is_empty() || port.is_empty() {
+        return None;
+    }
+    Some((namespace, service, port))
+}
+
+/// Write the records with the columns of `write_logs_to_parquet` followed by
+/// the backend columns.
+pub fn write_ingress_logs_to_parquet(
+    logs: Vec<IngressLog>,
+    filename: &str,
+) -> anyhow::Result<String> {
+    let (logs, backends): (Vec<_>, Vec<_>) = logs
+        .into_iter()
+        .map(|v| {
+            let backend = [
+                v.upstream_name,
+                v.alternative_upstream_name,
+                v.namespace,
+                v.service,
+                v.service_port,
+            ];
+            (v.log, backend)
+        })
+        .unzip();
+
+    let batch = logs_to_record_batch(&logs)?;
+    let mut columns = batch
+        .schema()
+        .fields()
+        .iter()
+        .map(|f| f.name().clone())
+        .zip(batch.columns().iter().cloned())
+        .collect::<Vec<(String, ArrayRef)>>();
+    let names = [
+        "proxy_upstream_name",
+        "proxy_alternative_upstream_name",
+        "namespace",
+        "service",
+        "service_port",
+    ];
+    for (i, name) in names.iter().enumerate() {
+        let values = backends.iter().map(|v| v[i].clone()).collect::<Vec<_>>();
+        columns.push((name.to_string(), Arc::new(StringArray::from(values))));
+    }
+    let batch = RecordBatch::try_from_iter(columns)?;
+
+    let mut writer = ArrowWriter::try_new(File::create(filename)?, batch.schema(), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(filename.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use super::*;
+
+    const RETRIED: &str = concat!(
+        r#"10.244.0.1 - - [01/Mar/2024:12:00:00 +0000] "POST /api/orders HTTP/1.1" 200 512 "#,
+        r#""-" "okhttp/4.12.0" 734 0.135 [shop-order-api-8080] [] "#,
+        r#"10.244.1.5:8080, 10.244.2.7:8080 0, 512 0.003, 0.124 502, 200 "#,
+        r#"5b7e0f3c4a9d2e1f8a6b7c9d0e1f2a3b"#,
+    );
+
+    #[test]
+    fn parse_ingress_log_should_handle_retries() {
+        let record = parse_ingress_log(RETRIED, &["shop".to_string()]).unwrap();
+        assert_eq!(record.log.status, 200);
+        assert_eq!(record.log.request_length, Some(734));
+        assert_eq!(record.log.request_time, Some(0.135));
+        assert_eq!(
+            record.log.request_id.as_deref(),
+            Some("5b7e0f3c4a9d2e1f8a6b7c9d0e1f2a3b")
+        );
+        assert_eq!(record.upstream_name.as_deref(), Some("shop-order-api-8080"));
+        assert_eq!(record.alternative_upstream_name, None);
+        assert_eq!(record.namespace.as_deref(), Some("shop"));
+        assert_eq!(record.service.as_deref(), Some("order-api"));
+        assert_eq!(record.service_port.as_deref(), Some("8080"));
+
+        let upstream = record.log.upstream.unwrap();
+        assert_eq!(upstream.tries(), 2);
+        assert_eq!(upstream.status.to_string(), "502, 200");
+        assert_eq!(
+            upstream.addr.last().map(String::as_str),
+            Some("10.244.2.7:8080")
+        );
+        assert_eq!(
+            upstream.response_length.attempts().collect::<Vec<_>>(),
+            vec![Some(&0), Some(&512)]
+        );
+
+        // a request the controller answered itself, with no upstream
+        let s = concat!(
+            r#"10.244.0.1 - - [01/Mar/2024:12:00:01 +0000] "GET /missing HTTP/1.1" 404 146 "#,
+            r#""-" "curl/8.4.0" 81 0.000 [upstream-default-backend] [] - - - - "#,
+            r#"0d8a5f3c"#,
+        );
+        let record = parse_ingress_log(s, &[]).unwrap();
+        assert_eq!(record.namespace, None);
+        assert!(record.log.upstream.unwrap().status.is_empty());
+        assert!(parse_ingress_log(&RETRIED.replace("[shop", "shop"), &[]).is_err());
+    }
+
+    #[test]
+    fn split_upstream_name_should_work() {
+        assert_eq!(
+            split_upstream_name("default-web-80", &[]),
+            Some(("default", "web", "80"))
+        );
+        assert_eq!(split_upstream_name(DEFAULT_BACKEND, &[]), None);
+        assert_eq!(split_upstream_name("web-80", &[]), None);
+
+        // hyphens in the namespace or the service make the split ambiguous
+        assert_eq!(split_upstream_name("kube-system-dashboard-443", &[]), None);
+        assert_eq!(split_upstream_name("prod-payment-gateway-https", &[]), None);
+        let known = [
+            "kube".to_string(),
+            "kube-system".to_string(),
+            "prod".to_string(),
+        ];
+        assert_eq!(
+            split_upstream_name("kube-system-dashboard-443", &known),
+            Some(("kube-system", "dashboard", "443"))
+        );
+        assert_eq!(
+            split_upstream_name("prod-payment-gateway-https", &known),
+            Some(("prod", "payment-gateway", "https"))
+        );
+    }
+
+    #[test]
+    fn write_ingress_logs_to_parquet_should_add_backend_columns() -> anyhow::Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("nginx-log-ingress-{}.parquet", std::process::id()));
+        let records = vec![parse_ingress_log(RETRIED, &["shop".to_string()]).unwrap()];
+        write_ingress_logs_to_parquet(records, path.to_str().unwrap())?;
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&path)?)?.build()?;
+        let batches = reader.collect::<Result<Vec<_>, _>>()?;
+        std::fs::remove_file(&path)?;
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), 1);
+        let service = batch.column_by_name("service").unwrap();
+        let service = service.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(service.value(0), "order-api");
+        assert!(batch.column_by_name("upstream_response_length").is_some());
+        Ok(())
+    }
+}
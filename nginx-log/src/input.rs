@@ -0,0 +1,127 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Cursor, Seek, SeekFrom},
+    thread,
+    time::Duration,
+};
+
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Read the whole input, which may be a http(s) URL, `-` for stdin or a file path.
+pub async fn read_input(input: &str) -> anyhow::Result<String> {
+    if is_url(input) {
+        Ok(reqwest::get(input).await?.text().await?)
+    } else if input == "-" {
+        Ok(io::read_to_string(io::stdin())?)
+    } else {
+        Ok(std::fs::read_to_string(input)?)
+    }
+}
+
+/// Open the input line by line. With `follow`, a file is tailed like `tail -f`
+/// instead of ending at EOF; stdin always runs until it is closed.
+pub async fn open(input: &str, follow: bool) -> anyhow::Result<LineReader> {
+    let reader: Box<dyn BufRead + Send> = if is_url(input) {
+        anyhow::ensure!(!follow, "Cannot follow a URL: {}", input);
+        Box::new(Cursor::new(read_input(input).await?))
+    } else if input == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(BufReader::new(File::open(input)?))
+    };
+    Ok(LineReader::new(reader, follow && input != "-"))
+}
+
+/// Follow a file from its current end, skipping what is already written,
+/// the way `tail -f` does; stdin is read as it comes.
+pub async fn tail(input: &str) -> anyhow::Result<LineReader> {
+    if is_url(input) || input == "-" {
+        return open(input, true).await;
+    }
+    let mut file = File::open(input)?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(LineReader::new(Box::new(BufReader::new(file)), true))
+}
+
+fn is_url(input: &str) -> bool {
+    input.starts_with("http://") || input.starts_with("https://")
+}
+
+pub struct LineReader {
+    reader: Box<dyn BufRead + Send>,
+    follow: bool,
+    buf: String,
+}
+
+impl LineReader {
+    pub fn new(reader: Box<dyn BufRead + Send>, follow: bool) -> Self {
+        Self {
+            reader,
+            follow,
+            buf: String::new(),
+        }
+    }
+}
+
+impl Iterator for LineReader {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.reader.read_line(&mut self.buf) {
+                // `read_line` consumes an invalid UTF-8 line before failing,
+                // so reading on continues with the next one
+                Err(e) => {
+                    self.buf.clear();
+                    return Some(Err(e));
+                }
+                // a partially written line stays in `buf` until the rest arrives
+                Ok(_) if self.follow && !self.buf.ends_with('\n') => {
+                    thread::sleep(FOLLOW_POLL_INTERVAL)
+                }
+                Ok(0) if self.buf.is_empty() => return None,
+                Ok(_) => {
+                    let line = std::mem::take(&mut self.buf);
+                    return Some(Ok(line.trim_end_matches(['\r', '\n']).to_string()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_reader_should_split_lines() {
+        let reader = LineReader::new(Box::new(Cursor::new("a\r\nb\nc")), false);
+        let lines = reader.collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(lines, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn line_reader_should_continue_after_invalid_utf8() {
+        let input: &[u8] = b"a\n\xff\nb\n";
+        let mut reader = LineReader::new(Box::new(Cursor::new(input)), false);
+        assert_eq!(reader.next().unwrap().unwrap(), "a");
+        let err = reader.next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(reader.next().unwrap().unwrap(), "b");
+        assert!(reader.next().is_none());
+    }
+
+    #[tokio::test]
+    async fn tail_should_start_at_the_end() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("nginx-log-tail-{}", std::process::id()));
+        std::fs::write(&path, "old\n")?;
+        let mut reader = tail(path.to_str().unwrap()).await?;
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut f| io::Write::write_all(&mut f, b"new\n"))?;
+        assert_eq!(reader.next().unwrap()?, "new");
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
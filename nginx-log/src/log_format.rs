@@ -0,0 +1,340 @@
+use std::net::IpAddr;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use winnow::{
+    combinator::{alt, delimited, eof, preceded, repeat, rest},
+    error::{ContextError, ErrMode},
+    token::{take_till, take_until, take_while},
+    PResult, Parser,
+};
+
+use crate::{
+    ingress, parse_http_method, parse_http_proto, parse_http_url, realip,
+    upstream::{self, Upstream},
+    HttpMethod, HttpProto, NginxLog,
+};
+
+/// The predefined `combined` format of `ngx_http_log_module`.
+pub const COMBINED: &str = r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#;
+
+/// A piece of a `log_format` layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Literal(String),
+    /// A variable name without the `$`
+    Var(String),
+}
+
+/// Split a layout such as `$remote_addr [$time_local] "${request}"` into
+/// literals and variables. `combined` names the predefined format and
+/// `ingress` the ingress-nginx default.
+pub fn parse_layout(layout: &str) -> anyhow::Result<Vec<Segment>> {
+    let layout = match layout {
+        "combined" => COMBINED,
+        "ingress" => ingress::LAYOUT,
+        _ => layout,
+    };
+    repeat(0.., parse_segment)
+        .parse(layout)
+        .map_err(|e| anyhow!("Invalid log_format layout\n{}", e))
+}
+
+/// A `log_format` layout compiled into a parser of `NginxLog` records.
+/// Variables without a typed field are matched and skipped, or returned by
+/// `parse_with_extra`.
+#[derive(Debug, Clone)]
+pub struct LogFormat {
+    segments: Vec<Segment>,
+}
+
+// fields collected while walking the layout
+#[derive(Default)]
+struct Fields<'f, 's> {
+    addr: Option<IpAddr>,
+    datetime: Option<DateTime<Utc>>,
+    request: Option<(HttpMethod, String, HttpProto)>,
+    status: Option<u16>,
+    body_bytes: Option<u64>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_time: Option<f64>,
+    request_length: Option<u64>,
+    host: Option<String>,
+    request_id: Option<String>,
+    upstream: Option<Upstream>,
+    forwarded_for: Vec<Option<IpAddr>>,
+    extra: Vec<(&'f str, &'s str)>,
+}
+
+impl LogFormat {
+    pub fn new(layout: &str) -> anyhow::Result<Self> {
+        let segments = parse_layout(layout)?;
+        for pair in segments.windows(2) {
+            if let [Segment::Var(a), Segment::Var(b)] = pair {
+                anyhow::bail!("${} and ${} need a literal between them", a, b);
+            }
+        }
+        let has = |names: &[&str]| {
+            segments
+                .iter()
+                .any(|v| matches!(v, Segment::Var(var) if names.contains(&var.as_str())))
+        };
+        for names in [
+            &["remote_addr"][..],
+            &["time_local", "time_iso8601", "msec"],
+            &["request"],
+            &["status"],
+        ] {
+            anyhow::ensure!(
+                has(names),
+                "log_format must contain ${}",
+                names.join(" or $")
+            );
+        }
+        Ok(Self { segments })
+    }
+
+    pub fn parse(&self, s: &str) -> PResult<NginxLog> {
+        self.parse_with_extra(s).map(|(log, _)| log)
+    }
+
+    /// Parse a line, also returning the text of each variable that has no
+    /// field in `NginxLog`.
+    pub fn parse_with_extra<'f, 's>(
+        &'f self,
+        s: &'s str,
+    ) -> PResult<(NginxLog, Vec<(&'f str, &'s str)>)> {
+        let input = &mut &*s;
+        let mut fields = Fields::default();
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Literal(literal) => {
+                    literal.as_str().parse_next(input)?;
+                }
+                Segment::Var(var) => {
+                    let next = match self.segments.get(i + 1) {
+                        Some(Segment::Literal(literal)) => Some(literal.as_str()),
+                        _ => None,
+                    };
+                    parse_var(var, next, input, &mut fields)?;
+                }
+            }
+        }
+
+        let missing = || ErrMode::Backtrack(ContextError::new());
+        let (method, url, protocol) = fields.request.ok_or_else(missing)?;
+        let log = NginxLog {
+            addr: fields.addr.ok_or_else(missing)?,
+            datetime: fields.datetime.ok_or_else(missing)?,
+            method,
+            url,
+            protocol,
+            status: fields.status.ok_or_else(missing)?,
+            body_bytes: fields.body_bytes.unwrap_or(0),
+            referer: fields.referer.unwrap_or_else(|| "-".to_string()),
+            user_agent: fields.user_agent.unwrap_or_else(|| "-".to_string()),
+            route: None,
+            ua: None,
+            geo: None,
+            request_time: fields.request_time,
+            source: None,
+            request_length: fields.request_length,
+            host: fields.host,
+            request_id: fields.request_id,
+            upstream: fields.upstream,
+            forwarded_for: fields.forwarded_for,
+            client_ip: None,
+        };
+        Ok((log, fields.extra))
+    }
+}
+
+// The text of a variable runs up to the next literal, or to the end of the line.
+fn value<'s>(s: &mut &'s str, next: Option<&str>) -> PResult<&'s str> {
+    match next {
+        Some(literal) => take_until(0.., literal).parse_next(s),
+        None => rest.parse_next(s),
+    }
+}
+
+fn parse_var<'f, 's>(
+    var: &'f str,
+    next: Option<&str>,
+    s: &mut &'s str,
+    fields: &mut Fields<'f, 's>,
+) -> PResult<()> {
+    let mut field = |s: &mut &'s str| value(s, next);
+    match var {
+        "remote_addr" => fields.addr = Some(field.parse_to().parse_next(s)?),
+        "time_local" => {
+            let dt = field
+                .try_map(|v| DateTime::parse_from_str(v, "%d/%b/%Y:%H:%M:%S %z"))
+                .parse_next(s)?;
+            fields.datetime = Some(dt.with_timezone(&Utc));
+        }
+        "time_iso8601" => {
+            let dt = field.try_map(DateTime::parse_from_rfc3339).parse_next(s)?;
+            fields.datetime = Some(dt.with_timezone(&Utc));
+        }
+        "msec" => {
+            let dt = field
+                .parse_to()
+                .verify_map(|v: f64| DateTime::from_timestamp_millis((v * 1000.0).round() as i64))
+                .parse_next(s)?;
+            fields.datetime = Some(dt);
+        }
+        "request" => {
+            let request = field
+                .and_then((parse_http_method, parse_http_url, parse_http_proto, eof))
+                .parse_next(s)?;
+            fields.request = Some((request.0, request.1, request.2));
+        }
+        "status" => fields.status = Some(field.parse_to().parse_next(s)?),
+        "body_bytes_sent" => fields.body_bytes = Some(field.parse_to().parse_next(s)?),
+        "http_referer" => fields.referer = Some(field.parse_next(s)?.to_string()),
+        "http_user_agent" => fields.user_agent = Some(field.parse_next(s)?.to_string()),
+        "request_time" => fields.request_time = Some(field.parse_to().parse_next(s)?),
+        "request_length" => fields.request_length = Some(field.parse_to().parse_next(s)?),
+        // `-` is how nginx writes an empty value
+        "host" => fields.host = Some(field.parse_next(s)?.to_string()).filter(|v| v != "-"),
+        "request_id" | "req_id" => {
+            fields.request_id = Some(field.parse_next(s)?.to_string()).filter(|v| v != "-")
+        }
+        "http_x_forwarded_for" => {
+            fields.forwarded_for = realip::parse_forwarded_for(field.parse_next(s)?)
+        }
+        "upstream_addr"
+        | "upstream_status"
+        | "upstream_response_time"
+        | "upstream_response_length"
+        | "upstream_connect_time"
+        | "upstream_header_time" => {
+            let upstream = fields.upstream.get_or_insert_with(Upstream::default);
+            match var {
+                "upstream_addr" => upstream.addr = upstream::parse_addr_list(s)?,
+                "upstream_status" => upstream.status = upstream::parse_status_list(s)?,
+                "upstream_response_time" => upstream.response_time = upstream::parse_time_list(s)?,
+                "upstream_response_length" => {
+                    upstream.response_length = upstream::parse_length_list(s)?
+                }
+                "upstream_connect_time" => upstream.connect_time = upstream::parse_time_list(s)?,
+                _ => upstream.header_time = upstream::parse_time_list(s)?,
+            }
+        }
+        _ => fields.extra.push((var, field.parse_next(s)?)),
+    }
+    Ok(())
+}
+
+fn parse_segment(s: &mut &str) -> PResult<Segment> {
+    alt((
+        preceded(
+            '$',
+            alt((delimited('{', parse_var_name, '}'), parse_var_name)),
+        )
+        .map(|v: &str| Segment::Var(v.to_string())),
+        take_till(1.., '$').map(|v: &str| Segment::Literal(v.to_string())),
+    ))
+    .parse_next(s)
+}
+
+fn parse_var_name<'s>(s: &mut &'s str) -> PResult<&'s str> {
+    take_while(1.., |c: char| c.is_ascii_alphanumeric() || c == '_').parse_next(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_layout_should_work() -> anyhow::Result<()> {
+        let segments = parse_layout(r#"$remote_addr [${time_local}] "$request"$status"#)?;
+        assert_eq!(
+            segments,
+            vec![
+                Segment::Var("remote_addr".to_string()),
+                Segment::Literal(" [".to_string()),
+                Segment::Var("time_local".to_string()),
+                Segment::Literal("] \"".to_string()),
+                Segment::Var("request".to_string()),
+                Segment::Literal("\"".to_string()),
+                Segment::Var("status".to_string()),
+            ]
+        );
+        assert_eq!(parse_layout("combined")?.len(), 16);
+        assert!(parse_layout("$ oops").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn log_format_should_parse_extended_fields() -> anyhow::Result<()> {
+        let format = LogFormat::new(concat!(
+            r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "#,
+            r#""$http_referer" "$http_user_agent" $request_length $request_time "#,
+            r#"$upstream_response_time $upstream_connect_time $upstream_status "#,
+            r#""$upstream_addr" $host $request_id"#,
+        ))?;
+        let s = concat!(
+            r#"2001:db8::1 - alice [17/May/2015:08:05:32 +0000] "POST /api/items HTTP/2.0" 201 "#,
+            r#"512 "-" "curl/8.0" 734 0.175 0.004, 0.120 : 0.050 -, 0.001 : 0.001 "#,
+            r#"502, 504 : 201 "10.0.0.1:80, 10.0.0.2:80 : unix:/run/app.sock" "#,
+            r#"api.example.com 9f86d081884c7d65"#,
+        );
+        let log = format.parse(s).unwrap();
+        assert_eq!(log.addr.to_string(), "2001:db8::1");
+        assert_eq!(log.method, HttpMethod::Post);
+        assert_eq!(log.status, 201);
+        assert_eq!(log.user_agent, "curl/8.0");
+        assert_eq!(log.request_length, Some(734));
+        assert_eq!(log.request_time, Some(0.175));
+        assert_eq!(log.host.as_deref(), Some("api.example.com"));
+        assert_eq!(log.request_id.as_deref(), Some("9f86d081884c7d65"));
+
+        let upstream = log.upstream.unwrap();
+        assert_eq!(upstream.tries(), 3);
+        assert_eq!(upstream.response_time.last(), Some(&0.05));
+        assert_eq!(
+            upstream.connect_time.attempts().collect::<Vec<_>>(),
+            vec![None, Some(&0.001), Some(&0.001)]
+        );
+        assert_eq!(upstream.status.to_string(), "502, 504 : 201");
+        assert_eq!(
+            upstream.addr.last().map(String::as_str),
+            Some("unix:/run/app.sock")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn log_format_should_agree_with_combined_parser() -> anyhow::Result<()> {
+        let format = LogFormat::new("combined")?;
+        let s = r#"93.180.71.3 - - [17/May/2015:08:05:32 +0000] "GET /downloads/product_1 HTTP/1.1" 304 0 "-" "Debian APT-HTTP/1.3 (0.8.16~exp12ubuntu10.21)""#;
+        let log = format.parse(s).unwrap();
+        let expected = crate::parse_nginx_log(s).unwrap();
+        assert_eq!(
+            (log.addr, log.datetime, log.url, log.status, log.user_agent),
+            (
+                expected.addr,
+                expected.datetime,
+                expected.url,
+                expected.status,
+                expected.user_agent
+            )
+        );
+        assert!(log.upstream.is_none());
+        assert!(format.parse(&s.replace("304", "abc")).is_err());
+        assert!(format.parse(&s.replace("[17/May", "[May")).is_err());
+
+        let format = LogFormat::new(r#"$remote_addr [$time_iso8601] "$request" $status"#)?;
+        let log = format
+            .parse(r#"1.1.1.1 [2015-05-17T10:05:32+02:00] "GET / HTTP/1.1" 200"#)
+            .unwrap();
+        assert_eq!(log.datetime, expected.datetime);
+        assert_eq!(log.referer, "-");
+
+        assert!(LogFormat::new("$remote_addr $status").is_err());
+        assert!(LogFormat::new("$remote_addr$status [$time_local] $request").is_err());
+        Ok(())
+    }
+}
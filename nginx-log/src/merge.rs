@@ -0,0 +1,276 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+};
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{parse_nginx_log, NginxLog};
+
+/// A parsed line and the original text, tagged with the input it came from.
+#[derive(Debug)]
+pub struct Record {
+    pub source: String,
+    pub line: String,
+    pub log: NginxLog,
+}
+
+// heap entry ordered by (datetime, source index, sequence) so ties keep input order
+struct Entry {
+    key: (DateTime<Utc>, usize, u64),
+    record: Record,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+/// Split `name=path` into its tag and input; a plain input, including an
+/// existing file whose name contains `=`, is its own tag.
+pub fn parse_source(input: &str) -> (String, String) {
+    if std::path::Path::new(input).is_file() {
+        return (input.to_string(), input.to_string());
+    }
+    match input.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !name.contains(['/', ':']) => {
+            (name.to_string(), path.to_string())
+        }
+        _ => (input.to_string(), input.to_string()),
+    }
+}
+
+/// Parse lines into tagged records, skipping lines that do not parse.
+pub fn records(
+    source: String,
+    lines: impl Iterator<Item = String>,
+) -> impl Iterator<Item = Record> {
+    lines.filter_map(move |line| {
+        let mut log = parse_nginx_log(&line).ok()?;
+        log.source = Some(source.clone());
+        Some(Record {
+            source: source.clone(),
+            line,
+            log,
+        })
+    })
+}
+
+/// Restores time order within one input, assuming no record is more than
+/// `tolerance` older than a record before it.
+pub struct Reorder<I> {
+    inner: I,
+    index: usize,
+    tolerance: Duration,
+    pending: BinaryHeap<Reverse<Entry>>,
+    latest: Option<DateTime<Utc>>,
+    seq: u64,
+    done: bool,
+}
+
+impl<I: Iterator<Item = Record>> Reorder<I> {
+    pub fn new(inner: I, index: usize, tolerance: Duration) -> Self {
+        Self {
+            inner,
+            index,
+            tolerance,
+            pending: BinaryHeap::new(),
+            latest: None,
+            seq: 0,
+            done: false,
+        }
+    }
+
+    fn next_entry(&mut self) -> Option<Entry> {
+        loop {
+            match (self.pending.peek(), self.latest) {
+                (Some(Reverse(top)), Some(latest))
+                    if self.done || latest - top.key.0 >= self.tolerance =>
+                {
+                    return self.pending.pop().map(|Reverse(entry)| entry);
+                }
+                (None, _) if self.done => return None,
+                _ => {}
+            }
+
+            match self.inner.next() {
+                Some(record) => {
+                    let time = record.log.datetime;
+                    self.latest = Some(self.latest.map_or(time, |t| t.max(time)));
+                    self.pending.push(Reverse(Entry {
+                        key: (time, self.index, self.seq),
+                        record,
+                    }));
+                    self.seq += 1;
+                }
+                None => self.done = true,
+            }
+        }
+    }
+}
+
+impl<I: Iterator<Item = Record>> Iterator for Reorder<I> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().map(|entry| entry.record)
+    }
+}
+
+/// K-way merge of several inputs into one time-ordered stream.
+pub struct Merge<I> {
+    sources: Vec<Reorder<I>>,
+    heap: BinaryHeap<Reverse<Entry>>,
+    last: Option<DateTime<Utc>>,
+    out_of_order: usize,
+}
+
+impl<I: Iterator<Item = Record>> Merge<I> {
+    pub fn new(inputs: Vec<I>, tolerance: Duration) -> Self {
+        let mut sources = inputs
+            .into_iter()
+            .enumerate()
+            .map(|(index, inner)| Reorder::new(inner, index, tolerance))
+            .collect::<Vec<_>>();
+        let heap = sources
+            .iter_mut()
+            .filter_map(|source| source.next_entry().map(Reverse))
+            .collect();
+        Self {
+            sources,
+            heap,
+            last: None,
+            out_of_order: 0,
+        }
+    }
+
+    /// Records emitted earlier than their predecessor because an input was
+    /// more out of order than the tolerance allows.
+    pub fn out_of_order(&self) -> usize {
+        self.out_of_order
+    }
+}
+
+impl<I: Iterator<Item = Record>> Iterator for Merge<I> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse(entry) = self.heap.pop()?;
+        let (time, index, _) = entry.key;
+        if let Some(next) = self.sources[index].next_entry() {
+            self.heap.push(Reverse(next));
+        }
+        if self.last.is_some_and(|last| time < last) {
+            self.out_of_order += 1;
+        }
+        self.last = Some(self.last.map_or(time, |t| t.max(time)));
+        Some(entry.record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(secs: &[u32]) -> Vec<String> {
+        secs.iter()
+            .map(|s| {
+                format!(
+                    r#"1.1.1.1 - - [17/May/2015:08:00:{:02} +0000] "GET /{} HTTP/1.1" 200 0 "-" "x""#,
+                    s, s
+                )
+            })
+            .collect()
+    }
+
+    fn merged(inputs: Vec<(&str, Vec<String>)>, tolerance: i64) -> (Vec<(String, u32)>, usize) {
+        let inputs = inputs
+            .into_iter()
+            .map(|(name, lines)| records(name.to_string(), lines.into_iter()))
+            .collect::<Vec<_>>();
+        let mut merge = Merge::new(inputs, Duration::seconds(tolerance));
+        let out = merge
+            .by_ref()
+            .map(|r| (r.source, (r.log.datetime.timestamp() % 60) as u32))
+            .collect();
+        (out, merge.out_of_order())
+    }
+
+    #[test]
+    fn parse_source_should_work() -> anyhow::Result<()> {
+        assert_eq!(
+            parse_source("web01=/var/log/a.log"),
+            ("web01".to_string(), "/var/log/a.log".to_string())
+        );
+        assert_eq!(
+            parse_source("/var/log/a=b.log"),
+            (
+                "/var/log/a=b.log".to_string(),
+                "/var/log/a=b.log".to_string()
+            )
+        );
+
+        // a file named like a tag is read, not split
+        let name = format!("nginx-log-a={}.log", std::process::id());
+        let dir = std::env::current_dir()?;
+        std::fs::write(dir.join(&name), "")?;
+        let source = parse_source(&name);
+        std::fs::remove_file(dir.join(&name))?;
+        assert_eq!(source, (name.clone(), name));
+        Ok(())
+    }
+
+    #[test]
+    fn merge_should_order_and_tag_records() {
+        let (out, late) = merged(
+            vec![
+                ("a", lines(&[1, 4, 4, 9])),
+                ("b", lines(&[2, 3, 10])),
+                ("c", vec!["garbage".to_string()]),
+            ],
+            0,
+        );
+        assert_eq!(
+            out,
+            vec![
+                ("a".to_string(), 1),
+                ("b".to_string(), 2),
+                ("b".to_string(), 3),
+                ("a".to_string(), 4),
+                ("a".to_string(), 4),
+                ("a".to_string(), 9),
+                ("b".to_string(), 10),
+            ]
+        );
+        assert_eq!(late, 0);
+    }
+
+    #[test]
+    fn merge_should_reorder_within_tolerance() {
+        let inputs = || vec![("a", lines(&[1, 5, 3, 4, 8])), ("b", lines(&[2, 6]))];
+        let (out, late) = merged(inputs(), 2);
+        let secs = out.iter().map(|v| v.1).collect::<Vec<_>>();
+        assert_eq!(secs, vec![1, 2, 3, 4, 5, 6, 8]);
+        assert_eq!(late, 0);
+
+        // without tolerance the late lines are passed through and counted
+        let (out, late) = merged(inputs(), 0);
+        assert_eq!(out.len(), 7);
+        assert_eq!(late, 2);
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{Arc, Mutex},
+};
+
+use clap::ValueEnum;
+use strum_macros::Display;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::NginxLog;
+
+const BYTES_BUCKETS: &[f64] = &[
+    100.0,
+    1_000.0,
+    10_000.0,
+    100_000.0,
+    1_000_000.0,
+    10_000_000.0,
+];
+const REQUEST_TIME_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+// value of every label of the series that absorbs label sets beyond the limit
+const OVERFLOW_LABEL: &str = "other";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum MetricLabel {
+    Status,
+    /// `2xx`, `4xx`, ... instead of the exact status
+    StatusClass,
+    Method,
+    Route,
+}
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Series {
+    requests: u64,
+    bytes: Histogram,
+    request_time: Histogram,
+}
+
+/// Prometheus counters and histograms built from parsed records.
+pub struct Metrics {
+    labels: Vec<MetricLabel>,
+    max_series: usize,
+    series: BTreeMap<Vec<String>, Series>,
+    parse_errors: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, v: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if v <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += v;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            let _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"{bound}\"}} {count}");
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {}",
+            self.count
+        );
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+impl Metrics {
+    /// `max_series` caps the number of label sets; further ones are counted
+    /// under a single series whose labels are all `other`. A label given
+    /// twice is kept once, as Prometheus rejects repeated label names.
+    pub fn new(labels: Vec<MetricLabel>, max_series: usize) -> Self {
+        let mut unique = Vec::new();
+        for label in labels {
+            if !unique.contains(&label) {
+                unique.push(label);
+            }
+        }
+        Self {
+            labels: unique,
+            max_series,
+            series: BTreeMap::new(),
+            parse_errors: 0,
+        }
+    }
+
+    pub fn observe(&mut self, log: &NginxLog) {
+        let mut key = self
+            .labels
+            .iter()
+            .map(|label| match label {
+                MetricLabel::Status => log.status.to_string(),
+                MetricLabel::StatusClass => format!("{}xx", log.status / 100),
+                MetricLabel::Method => log.method.to_string().to_uppercase(),
+                MetricLabel::Route => log.route.clone().unwrap_or_else(|| log.url.clone()),
+            })
+            .collect::<Vec<_>>();
+        if !self.series.contains_key(&key) && self.series.len() >= self.max_series {
+            key = vec![OVERFLOW_LABEL.to_string(); self.labels.len()];
+        }
+
+        let series = self.series.entry(key).or_insert_with(|| Series {
+            requests: 0,
+            bytes: Histogram::new(BYTES_BUCKETS),
+            request_time: Histogram::new(REQUEST_TIME_BUCKETS),
+        });
+        series.requests += 1;
+        series.bytes.observe(log.body_bytes as f64);
+        if let Some(t) = log.request_time {
+            series.request_time.observe(t);
+        }
+    }
+
+    pub fn observe_parse_error(&mut self) {
+        self.parse_errors += 1;
+    }
+
+    /// Render in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let labels = self
+            .series
+            .iter()
+            .map(|(key, series)| (self.render_labels(key), series))
+            .collect::<Vec<_>>();
+
+        out.push_str("# HELP nginx_http_requests_total Requests by label set.\n");
+        out.push_str("# TYPE nginx_http_requests_total counter\n");
+        for (l, series) in &labels {
+            let _ = writeln!(out, "nginx_http_requests_total{{{l}}} {}", series.requests);
+        }
+
+        out.push_str("# HELP nginx_http_response_bytes Response body bytes sent.\n");
+        out.push_str("# TYPE nginx_http_response_bytes histogram\n");
+        for (l, series) in &labels {
+            series
+                .bytes
+                .render(&mut out, "nginx_http_response_bytes", l);
+        }
+
+        out.push_str("# HELP nginx_http_request_duration_seconds Request time, when logged.\n");
+        out.push_str("# TYPE nginx_http_request_duration_seconds histogram\n");
+        for (l, series) in labels.iter().filter(|(_, s)| s.request_time.count > 0) {
+            series
+                .request_time
+                .render(&mut out, "nginx_http_request_duration_seconds", l);
+        }
+
+        out.push_str("# HELP nginx_log_parse_errors_total Lines that could not be parsed.\n");
+        out.push_str("# TYPE nginx_log_parse_errors_total counter\n");
+        let _ = writeln!(out, "nginx_log_parse_errors_total {}", self.parse_errors);
+        out
+    }
+
+    fn render_labels(&self, key: &[String]) -> String {
+        self.labels
+            .iter()
+            .zip(key)
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape_label(value)))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+fn escape_label(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve `GET /metrics` until the process is stopped.
+pub async fn serve(listener: TcpListener, metrics: Arc<Mutex<Metrics>>) -> anyhow::Result<()> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap_or(0);
+            let request = String::from_utf8_lossy(&buf[..n]);
+            let response = if request.starts_with("GET /metrics ") {
+                let body = metrics.lock().unwrap().render();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string()
+            };
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_should_include_counters_and_histograms() {
+        let mut metrics = Metrics::new(vec![MetricLabel::StatusClass, MetricLabel::Method], 10);
+        metrics.observe(
+            &NginxLog::builder()
+                .url("/a")
+                .bytes(500)
+                .request_time("0.020")
+                .build(),
+        );
+        metrics.observe(&NginxLog::builder().url("/b").status(201).bytes(50).build());
+        metrics.observe(&NginxLog::builder().url("/c").status(404).build());
+        let text = metrics.render();
+
+        assert!(text.contains("nginx_http_requests_total{status_class=\"2xx\",method=\"GET\"} 2\n"));
+        assert!(text.contains("nginx_http_requests_total{status_class=\"4xx\",method=\"GET\"} 1\n"));
+        assert!(text.contains(
+            "nginx_http_response_bytes_bucket{status_class=\"2xx\",method=\"GET\",le=\"100\"} 1\n"
+        ));
+        assert!(text
+            .contains("nginx_http_response_bytes_sum{status_class=\"2xx\",method=\"GET\"} 550\n"));
+        assert!(text.contains(
+            "nginx_http_request_duration_seconds_bucket{status_class=\"2xx\",method=\"GET\",le=\"0.025\"} 1\n"
+        ));
+        assert!(!text.contains("nginx_http_request_duration_seconds_count{status_class=\"4xx\""));
+    }
+
+    #[test]
+    fn observe_should_cap_label_cardinality() {
+        let mut metrics = Metrics::new(vec![MetricLabel::Route], 2);
+        for url in ["/a", "/b", "/c", "/d", "/a"] {
+            metrics.observe(&NginxLog::builder().url(url).build());
+        }
+        let text = metrics.render();
+        assert!(text.contains("nginx_http_requests_total{route=\"/a\"} 2\n"));
+        assert!(text.contains("nginx_http_requests_total{route=\"other\"} 2\n"));
+        assert!(!text.contains("route=\"/c\""));
+
+        let mut metrics = Metrics::new(vec![MetricLabel::Method, MetricLabel::Method], 2);
+        metrics.observe(&NginxLog::builder().build());
+        assert!(metrics
+            .render()
+            .contains("nginx_http_requests_total{method=\"GET\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn serve_should_expose_metrics() -> anyhow::Result<()> {
+        let metrics = Arc::new(Mutex::new(Metrics::new(vec![MetricLabel::Status], 10)));
+        metrics
+            .lock()
+            .unwrap()
+            .observe(&NginxLog::builder().url("/a").build());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(serve(listener, metrics));
+
+        let body = reqwest::get(format!("http://{}/metrics", addr))
+            .await?
+            .text()
+            .await?;
+        assert!(body.contains("nginx_http_requests_total{status=\"200\"} 1\n"));
+
+        let resp = reqwest::get(format!("http://{}/other", addr)).await?;
+        assert_eq!(resp.status(), 404);
+        Ok(())
+    }
+}
@@ -0,0 +1,375 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use winnow::{
+    ascii::{multispace1, till_line_ending},
+    combinator::{alt, cut_err, delimited, preceded, repeat, terminated},
+    error::{StrContext, StrContextValue},
+    token::{any, none_of, take_till, take_while},
+    PResult, Parser,
+};
+
+use crate::filter;
+
+// guards against include cycles
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// A directive with its arguments and, for `http { ... }` and the like, the
+/// directives of its block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Directive {
+    pub name: String,
+    pub args: Vec<String>,
+    pub block: Option<Vec<Directive>>,
+}
+
+/// An `access_log` and the layout of the `log_format` it names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessLog {
+    pub path: PathBuf,
+    pub format: String,
+    pub layout: String,
+}
+
+/// Parse configuration text, without following `include`.
+pub fn parse_config(s: &str) -> anyhow::Result<Vec<Directive>> {
+    terminated(parse_directives, parse_space)
+        .parse(s)
+        .map_err(|e| anyhow!("Invalid nginx configuration\n{}", e))
+}
+
+/// Read a configuration file and splice in the files its `include`
+/// directives match. Relative includes are resolved against the directory
+/// of `path`, as nginx does against its configuration prefix.
+pub fn load(path: &Path) -> anyhow::Result<Vec<Directive>> {
+    let base = path.parent().unwrap_or(Path::new("."));
+    load_file(path, base, 0)
+}
+
+fn load_file(path: &Path, base: &Path, depth: usize) -> anyhow::Result<Vec<Directive>> {
+    anyhow::ensure!(
+        depth <= MAX_INCLUDE_DEPTH,
+        "Includes nested too deeply at {}",
+        path.display()
+    );
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+    let directives = parse_config(&text).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    expand_includes(directives, base, depth)
+}
+
+fn expand_includes(
+    directives: Vec<Directive>,
+    base: &Path,
+    depth: usize,
+) -> anyhow::Result<Vec<Directive>> {
+    let mut out = Vec::new();
+    for mut directive in directives {
+        if directive.name == "include" && directive.block.is_none() {
+            for arg in &directive.args {
+                for path in glob(&base.join(arg))? {
+                    out.extend(load_file(&path, base, depth + 1)?);
+                }
+            }
+            continue;
+        }
+        if let Some(block) = directive.block.take() {
+            directive.block = Some(expand_includes(block, base, depth)?);
+        }
+        out.push(directive);
+    }
+    Ok(out)
+}
+
+// Expand `*` and `?` in any component of the path, matching directories
+// before the last component and files in it; nginx sorts the matches.
+fn glob(pattern: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let components = pattern.components().collect::<Vec<_>>();
+    let mut paths = vec![PathBuf::new()];
+    for (i, component) in components.iter().enumerate() {
+        let name = component.as_os_str().to_str().unwrap_or_default();
+        if !name.contains(['*', '?']) {
+            paths.iter_mut().for_each(|v| v.push(component));
+            continue;
+        }
+        let re = filter::glob_to_regex(name)?;
+        let last = i + 1 == components.len();
+        let mut matches = Vec::new();
+        for dir in &paths {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            // a directory that does not exist matches nothing
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                continue;
+            };
+            matches.extend(
+                entries
+                    .filter_map(Result::ok)
+                    .filter(|entry| entry.file_name().to_str().is_some_and(|v| re.is_match(v)))
+                    .map(|entry| entry.path())
+                    .filter(|path| if last { path.is_file() } else { path.is_dir() }),
+            );
+        }
+        matches.sort();
+        paths = matches;
+    }
+    Ok(paths)
+}
+
+/// The prefix nginx resolves relative `access_log` paths against when it is
+/// not given: the parent of the configuration directory, as in the
+/// `<prefix>/conf/nginx.conf` layout of a source build.
+pub fn default_prefix(conf: &Path) -> PathBuf {
+    let dir = conf
+        .parent()
+        .filter(|v| !v.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    match dir.parent().filter(|v| !v.as_os_str().is_empty()) {
+        Some(parent) => parent.to_path_buf(),
+        None => dir.join(".."),
+    }
+}
+
+/// Find every file `access_log` with the layout it is written in. Relative
+/// paths are resolved against `prefix`, nginx's `--prefix`. Logs sent to
+/// syslog or with variables in the path are skipped.
+pub fn access_logs(directives: &[Directive], prefix: &Path) -> anyhow::Result<Vec<AccessLog>> {
+    let mut formats = BTreeMap::new();
+    walk(directives, &mut |d| {
+        if d.name == "log_format" && d.args.len() >= 2 {
+            // the layout strings are concatenated, after an optional `escape=`
+            let layout = d.args[1..]
+                .iter()
+                .filter(|v| !v.starts_with("escape="))
+                .map(String::as_str)
+                .collect::<String>();
+            formats.insert(d.args[0].clone(), layout);
+        }
+    });
+
+    let mut logs = Vec::new();
+    let mut error = None;
+    walk(directives, &mut |d| {
+        let Some(path) = d.args.first().filter(|_| d.name == "access_log") else {
+            return;
+        };
+        if path == "off" || path.contains('$') || path.starts_with("syslog:") {
+            return;
+        }
+        // `buffer=`, `gzip`, `flush=` and `if=` follow the format name
+        let format = d
+            .args
+            .get(1)
+            .filter(|v| !v.contains('=') && *v != "gzip")
+            .cloned()
+            .unwrap_or_else(|| "combined".to_string());
+        let layout = match formats.get(&format) {
+            Some(layout) => layout.clone(),
+            None if format == "combined" => format.clone(),
+            None => {
+                error.get_or_insert(anyhow!(
+                    "access_log {} uses unknown log_format {}",
+                    path,
+                    format
+                ));
+                return;
+            }
+        };
+        let log = AccessLog {
+            path: prefix.join(path),
+            format,
+            layout,
+        };
+        if !logs.contains(&log) {
+            logs.push(log);
+        }
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(logs),
+    }
+}
+
+fn walk(directives: &[Directive], f: &mut impl FnMut(&Directive)) {
+    for directive in directives {
+        f(directive);
+        if let Some(block) = &directive.block {
+            walk(block, f);
+        }
+    }
+}
+
+fn parse_directives(s: &mut &str) -> PResult<Vec<Directive>> {
+    repeat(0.., preceded(parse_space, parse_directive)).parse_next(s)
+}
+
+fn parse_directive(s: &mut &str) -> PResult<Directive> {
+    let name = parse_word.parse_next(s)?;
+    let args = repeat(0.., preceded(parse_space, parse_arg)).parse_next(s)?;
+    parse_space(s)?;
+    let block = cut_err(alt((
+        ';'.map(|_| None),
+        delimited('{', parse_directives, (parse_space, cut_err('}'))).map(Some),
+    )))
+    .context(StrContext::Expected(StrContextValue::Description(
+        "`;` or a block",
+    )))
+    .parse_next(s)?;
+    Ok(Directive { name, args, block })
+}
+
+// whitespace and `#` comments
+fn parse_space(s: &mut &str) -> PResult<()> {
+    repeat(
+        0..,
+        alt((multispace1.void(), ('#', till_line_ending).void())),
+    )
+    .parse_next(s)
+}
+
+fn parse_arg(s: &mut &str) -> PResult<String> {
+    alt((parse_quoted('"'), parse_quoted('\''), parse_word)).parse_next(s)
+}
+
+fn parse_quoted<'s>(quote: char) -> impl Parser<&'s str, String, winnow::error::ContextError> {
+    // like nginx, only these escapes are unescaped; `\.` in a regex stays
+    let escaped = ('\\', any).take().map(|s: &str| match s {
+        "\\n" => "\n",
+        "\\t" => "\t",
+        "\\r" => "\r",
+        "\\\"" => "\"",
+        "\\'" => "'",
+        "\\\\" => "\\",
+        s => s,
+    });
+    delimited(
+        quote,
+        repeat(0.., alt((escaped, none_of([quote, '\\']).take()))),
+        cut_err(quote).context(StrContext::Expected(StrContextValue::Description(
+            "closing quote",
+        ))),
+    )
+}
+
+// A bare word; `${var}` may contain the braces that otherwise end a word.
+fn parse_word(s: &mut &str) -> PResult<String> {
+    repeat(
+        1..,
+        alt((
+            ("${", take_till(0.., '}'), '}').take(),
+            take_while(1.., |c: char| {
+                !c.is_whitespace() && !matches!(c, ';' | '{' | '}' | '"' | '\'' | '$')
+            }),
+            "$",
+        )),
+    )
+    .parse_next(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONF: &str = r#"
+user nginx;
+worker_processes auto;  # one per core
+
+http {
+    log_format main escape=default '$remote_addr - $remote_user [$time_local] "$request" '
+                    '$status $body_bytes_sent "$http_referer" '
+                    "\"$http_user_agent\" $request_time";
+    access_log /var/log/nginx/access.log main buffer=32k;
+
+    server {
+        listen 80;
+        location ~ ^/static/(.+\.js)$ { root /srv; }
+        access_log logs/static.log;
+        access_log syslog:server=10.0.0.1 main;
+        access_log /var/log/nginx/$host.log main;
+        access_log off;
+    }
+}
+"#;
+
+    #[test]
+    fn parse_config_should_build_tree() -> anyhow::Result<()> {
+        let directives = parse_config(CONF)?;
+        assert_eq!(directives.len(), 3);
+        assert_eq!(directives[1].args, vec!["auto"]);
+        let http = directives[2].block.as_ref().unwrap();
+        assert_eq!(http[0].args.len(), 5);
+        let server = http[2].block.as_ref().unwrap();
+        assert_eq!(server[1].name, "location");
+        assert_eq!(server[1].args, vec!["~", r"^/static/(.+\.js)$"]);
+
+        assert!(parse_config("http { listen 80; ").is_err());
+        assert!(parse_config("listen 80").is_err());
+        assert!(parse_config("log_format x 'unterminated;").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_quoted_should_keep_unknown_escapes() -> anyhow::Result<()> {
+        let directives = parse_config(r#"location ~ "^/a\.js$" 'it\'s\t\\' { }"#)?;
+        assert_eq!(directives[0].args, vec!["~", r"^/a\.js$", "it's\t\\"]);
+        Ok(())
+    }
+
+    #[test]
+    fn access_logs_should_pair_paths_with_layouts() -> anyhow::Result<()> {
+        let prefix = default_prefix(Path::new("/usr/local/nginx/conf/nginx.conf"));
+        assert_eq!(prefix, PathBuf::from("/usr/local/nginx"));
+        let logs = access_logs(&parse_config(CONF)?, &prefix)?;
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0].path, PathBuf::from("/var/log/nginx/access.log"));
+        assert_eq!(logs[0].format, "main");
+        assert_eq!(
+            logs[0].layout,
+            concat!(
+                r#"$remote_addr - $remote_user [$time_local] "$request" "#,
+                r#"$status $body_bytes_sent "$http_referer" "$http_user_agent" $request_time"#
+            )
+        );
+        assert_eq!(
+            logs[1].path,
+            PathBuf::from("/usr/local/nginx/logs/static.log")
+        );
+        assert_eq!(logs[1].layout, "combined");
+
+        let missing = parse_config("access_log /tmp/a.log nope;")?;
+        assert!(access_logs(&missing, Path::new("/")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn load_should_follow_include_globs() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("nginx-log-conf-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("conf.d"))?;
+        std::fs::write(
+            dir.join("nginx.conf"),
+            "http { include conf.d/*.conf; include mime.types; include sites-*/*; }",
+        )?;
+        std::fs::write(dir.join("mime.types"), "types { text/html html; }")?;
+        std::fs::write(
+            dir.join("conf.d/b.conf"),
+            "server { access_log /tmp/b.log; }",
+        )?;
+        std::fs::write(dir.join("conf.d/a.conf"), "log_format tiny '$status';")?;
+        std::fs::write(dir.join("conf.d/skip.txt"), "not { valid")?;
+        std::fs::create_dir_all(dir.join("sites-enabled"))?;
+        std::fs::write(dir.join("sites-enabled/shop"), "server { listen 80; }")?;
+
+        let directives = load(&dir.join("nginx.conf"));
+        std::fs::remove_dir_all(&dir)?;
+        let http = directives?.remove(0).block.unwrap();
+        let names = http.iter().map(|d| d.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["log_format", "server", "types", "server"]);
+        Ok(())
+    }
+}
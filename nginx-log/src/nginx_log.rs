@@ -1,17 +1,53 @@
 #![allow(unused)]
+mod anomaly;
+mod anonymize;
+mod apache;
+mod attack;
+mod cli;
+mod dashboard;
+mod error_log;
+mod filter;
+mod generate;
+mod geoip;
+mod haproxy;
+mod ingress;
+mod input;
+mod log_format;
+mod merge;
+mod metrics;
+mod nginx_conf;
+mod percent;
+mod query;
+mod realip;
+mod report;
+mod route;
+mod sample;
+mod session;
+mod sqlite;
+mod timerange;
+mod upstream;
+mod user_agent;
+mod w3c;
+
 use std::{
-    fs::File,
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::Write,
     net::{IpAddr, Ipv4Addr},
     str::FromStr,
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
 use anyhow::anyhow;
 use arrow::{
-    array::{Array, Int64Array, RecordBatch, StringArray, UInt16Array, UInt64Array},
-    datatypes::{DataType, Field, Schema},
+    array::{
+        Array, ArrayRef, Float64Array, Int64Array, ListArray, ListBuilder, RecordBatch,
+        StringArray, StringBuilder, UInt16Array, UInt32Array, UInt64Array,
+    },
+    datatypes::{DataType, Field, Float64Type, Schema, UInt16Type, UInt64Type},
 };
 use chrono::{format::Pad, DateTime, Utc};
+use clap::Parser as _;
 use parquet::{
     arrow::ArrowWriter,
     column::writer::ColumnWriter,
@@ -21,9 +57,9 @@ use parquet::{
 };
 use strum_macros::Display;
 use winnow::{
-    ascii::{digit1, space0},
-    combinator::{alt, delimited, separated, terminated},
-    token::take_until,
+    ascii::{digit1, float, space0},
+    combinator::{alt, delimited, opt, separated, terminated},
+    token::{take_until, take_while},
     PResult, Parser,
 };
 
@@ -60,6 +96,26 @@ struct NginxLog {
     body_bytes: u64,
     referer: String,
     user_agent: String,
+    route: Option<String>,
+    ua: Option<user_agent::UserAgentInfo>,
+    geo: Option<geoip::GeoInfo>,
+    request_time: Option<f64>,
+    source: Option<String>,
+    request_length: Option<u64>,
+    host: Option<String>,
+    request_id: Option<String>,
+    upstream: Option<upstream::Upstream>,
+    /// `X-Forwarded-For` hops, `None` where a hop is not an address
+    forwarded_for: Vec<Option<IpAddr>>,
+    client_ip: Option<IpAddr>,
+}
+
+impl NginxLog {
+    /// The visitor's address: `client_ip` when trusted proxies resolved one,
+    /// otherwise the connecting `addr`.
+    fn client(&self) -> IpAddr {
+        self.client_ip.unwrap_or(self.addr)
+    }
 }
 
 // we need to parse:
@@ -67,17 +123,486 @@ struct NginxLog {
 // with winnow parser combinator
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    println!("{:?}", parse_one_nginx_log().await?);
+    match cli::Cli::parse().into_command() {
+        cli::Command::Parse(opts) => run_parse(*opts).await,
+        cli::Command::Detect(opts) => run_detect(opts).await,
+        cli::Command::Audit(opts) => run_audit(opts).await,
+        cli::Command::Serve(opts) => run_serve(opts).await,
+        cli::Command::Query(opts) => run_query(opts).await,
+        cli::Command::Report(opts) => run_report(opts).await,
+        cli::Command::Dashboard(opts) => run_dashboard(opts).await,
+        cli::Command::Merge(opts) => run_merge(opts).await,
+        cli::Command::Generate(opts) => run_generate(opts).await,
+        cli::Command::Ingress(opts) => run_ingress(opts).await,
+        cli::Command::Errors(opts) => run_errors(opts).await,
+    }
+}
+
+async fn run_parse(opts: cli::ParseOpts) -> anyhow::Result<()> {
+    let normalizer = route::RouteNormalizer::new(&opts.routes)?;
+    let anonymizer = anonymize::Anonymizer::new(
+        opts.anonymize.ip_mode,
+        opts.anonymize.hmac_key.as_deref(),
+        &opts.anonymize.redact_params,
+        opts.anonymize.drop_user_agent,
+    )?;
+    let filter = opts
+        .filter
+        .as_deref()
+        .map(filter::parse_filter)
+        .transpose()?;
+
+    let range = timerange::TimeRange {
+        since: opts.since,
+        until: opts.until,
+    };
+    let format = opts
+        .log_format
+        .as_deref()
+        .map(log_format::LogFormat::new)
+        .transpose()?;
+    let apache_format = opts
+        .apache_format
+        .as_deref()
+        .map(apache::ApacheFormat::new)
+        .transpose()?;
 
-    let logs = parse_nginx_logs().await?;
-    println!("{:?}", logs[1]);
+    // the binary search only understands the combined format
+    let seekable = format.is_none()
+        && apache_format.is_none()
+        && !opts.w3c
+        && !opts.haproxy
+        && std::path::Path::new(&opts.input).is_file();
+    // the W3C columns with no record field, kept in step with `logs`
+    let mut extras = Vec::new();
+    let mut logs = if let Some(conf) = &opts.nginx_conf {
+        let conf = std::path::Path::new(conf);
+        let prefix = match &opts.nginx_prefix {
+            Some(prefix) => std::path::PathBuf::from(prefix),
+            None => nginx_conf::default_prefix(conf),
+        };
+        let mut logs = parse_configured_logs(conf, &prefix)?;
+        logs.retain(|log| range.contains(&log.datetime));
+        logs
+    } else if seekable && !range.is_unbounded() {
+        let (logs, scan) = timerange::extract(
+            File::open(&opts.input)?,
+            &range,
+            chrono::Duration::seconds(timerange::SORT_TOLERANCE_SECS),
+        )?;
+        if scan == timerange::Scan::Linear {
+            eprintln!("{} is not sorted by time, scanned it linearly", opts.input);
+        }
+        logs
+    } else {
+        let mut logs = match (&format, &apache_format) {
+            (Some(format), _) => input::read_input(&opts.input)
+                .await?
+                .lines()
+                .filter_map(|v| format.parse(v).ok())
+                .collect(),
+            (None, Some(format)) => input::read_input(&opts.input)
+                .await?
+                .lines()
+                .filter_map(|v| format.parse(v).ok())
+                .collect(),
+            (None, None) if opts.w3c => {
+                let (logs, rows) = w3c::parse_w3c_logs(&input::read_input(&opts.input).await?)
+                    .into_iter()
+                    .filter(|v| range.contains(&v.log.datetime))
+                    .map(|v| (v.log, v.extra))
+                    .unzip();
+                extras = rows;
+                logs
+            }
+            (None, None) if opts.haproxy => {
+                haproxy::parse_haproxy_logs(&input::read_input(&opts.input).await?)
+            }
+            (None, None) => parse_nginx_logs(&opts.input).await?,
+        };
+        logs.retain(|log| range.contains(&log.datetime));
+        logs
+    };
+    extras.resize_with(logs.len(), Vec::new);
+    normalizer.apply(&mut logs);
+    if !opts.real_ip.trusted_proxies.is_empty() {
+        realip::TrustedProxies::new(&opts.real_ip.trusted_proxies, opts.real_ip.recursive)?
+            .apply(&mut logs);
+    }
+    if let Some(filter) = &filter {
+        retain_logs(&mut logs, &mut extras, |log| filter.matches(log));
+    }
+    if let Some(rate) = opts.sample.sample_rate {
+        let sampler = sample::HashSampler::new(opts.sample.sample_key, rate, opts.sample.seed)?;
+        retain_logs(&mut logs, &mut extras, |log| sampler.keep(log));
+    }
+    if let Some(size) = opts.sample.reservoir {
+        let mut reservoir = sample::Reservoir::new(size, opts.sample.seed)?;
+        reservoir.extend(logs.into_iter().zip(extras));
+        (logs, extras) = reservoir.into_vec().into_iter().unzip();
+    }
+    user_agent::apply(&mut logs);
+    if !opts.geoip.is_empty() {
+        geoip::GeoIp::open(&opts.geoip)?.apply(&mut logs)?;
+    }
+    // sessions are built from the raw records so that anonymization does
+    // not merge visitors; only the exported rows are anonymized
+    if let Some(output) = &opts.session.sessions {
+        let timeout = chrono::Duration::minutes(opts.session.session_timeout);
+        let mut sessions = session::sessionize(&logs, opts.session.session_key, timeout);
+        anonymizer.apply_sessions(&mut sessions);
+        let filename = session::write_sessions_to_parquet(&sessions, output)?;
+        println!("{} sessions: {}", sessions.len(), filename);
+    }
+    anonymizer.apply(&mut logs);
+    println!("parsed {} logs", logs.len());
 
-    let filename = write_logs_to_parquet(logs)?;
+    let filename = if sqlite::is_sqlite_path(&opts.output) {
+        sqlite::write_logs_to_sqlite(&logs, &opts.output)?
+    } else if opts.output.ends_with(".csv") {
+        write_logs_to_csv(&logs, &opts.output)?
+    } else if opts.w3c {
+        let logs = logs
+            .into_iter()
+            .zip(extras)
+            .map(|(log, extra)| w3c::W3cLog { log, extra })
+            .collect();
+        w3c::write_w3c_logs_to_parquet(logs, &opts.output)?
+    } else {
+        write_logs_to_parquet(logs, &opts.output)?
+    };
     println!("{}", filename);
     Ok(())
 }
 
-fn write_logs_to_parquet(logs: Vec<NginxLog>) -> anyhow::Result<String> {
+// `Vec::retain` over the records and their extra columns together
+fn retain_logs<T>(logs: &mut Vec<NginxLog>, extras: &mut Vec<T>, keep: impl Fn(&NginxLog) -> bool) {
+    let kept = logs.iter().map(keep).collect::<Vec<_>>();
+    let mut mask = kept.iter();
+    extras.retain(|_| *mask.next().unwrap());
+    let mut mask = kept.iter();
+    logs.retain(|_| *mask.next().unwrap());
+}
+
+// Parse every file access log of an nginx configuration with its own layout,
+// tagging records with the log path.
+fn parse_configured_logs(
+    conf: &std::path::Path,
+    prefix: &std::path::Path,
+) -> anyhow::Result<Vec<NginxLog>> {
+    let mut logs = Vec::new();
+    for access_log in nginx_conf::access_logs(&nginx_conf::load(conf)?, prefix)? {
+        let path = access_log.path.display().to_string();
+        let format = match log_format::LogFormat::new(&access_log.layout) {
+            Ok(format) => format,
+            Err(e) => {
+                eprintln!("skipping {} ({}): {}", path, access_log.format, e);
+                continue;
+            }
+        };
+        let text = match std::fs::read_to_string(&access_log.path) {
+            Ok(text) => text,
+            Err(e) => {
+                eprintln!("skipping {}: {}", path, e);
+                continue;
+            }
+        };
+        let before = logs.len();
+        logs.extend(
+            text.lines()
+                .filter_map(|v| format.parse(v).ok())
+                .map(|mut log| {
+                    log.source = Some(path.clone());
+                    log
+                }),
+        );
+        eprintln!(
+            "{}: {} logs ({})",
+            path,
+            logs.len() - before,
+            access_log.format
+        );
+    }
+    Ok(logs)
+}
+
+async fn run_detect(opts: cli::DetectOpts) -> anyhow::Result<()> {
+    let mut detector = anomaly::Detector::new(anomaly::DetectorConfig {
+        window: chrono::Duration::seconds(opts.window),
+        max_rate_per_min: opts.max_rate,
+        max_client_error_ratio: opts.max_4xx_ratio,
+        min_requests: opts.min_requests,
+        max_auth_failures: opts.max_auth_failures,
+    });
+    let mut out: Box<dyn Write + Send> = match &opts.output {
+        Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        None => Box::new(std::io::stdout()),
+    };
+
+    // following a file sleeps between polls, so keep it off the runtime
+    let lines = input::open(&opts.input, opts.follow).await?;
+    tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        for line in lines {
+            let Ok(log) = parse_nginx_log(&line?) else {
+                continue;
+            };
+            for alert in detector.push(&log) {
+                serde_json::to_writer(&mut out, &alert)?;
+                writeln!(out)?;
+                out.flush()?;
+            }
+        }
+        Ok(())
+    })
+    .await?
+}
+
+async fn run_audit(opts: cli::AuditOpts) -> anyhow::Result<()> {
+    let detector = attack::AttackDetector::default();
+    let mut out: Box<dyn Write> = match &opts.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(std::io::stdout()),
+    };
+
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for log in parse_nginx_logs(&opts.input).await? {
+        let Some(attack) = detector.scan(&log) else {
+            continue;
+        };
+        if attack.severity < opts.min_severity {
+            continue;
+        }
+        for rule in &attack.rules {
+            *counts.entry(rule).or_default() += 1;
+        }
+        serde_json::to_writer(&mut out, &attack::AuditRecord::new(&log, attack))?;
+        writeln!(out)?;
+    }
+    out.flush()?;
+
+    for (rule, count) in counts {
+        eprintln!("{}: {}", rule, count);
+    }
+    Ok(())
+}
+
+async fn run_serve(opts: cli::ServeOpts) -> anyhow::Result<()> {
+    let normalizer = route::RouteNormalizer::new(&opts.routes)?;
+    let metrics = Arc::new(Mutex::new(metrics::Metrics::new(
+        opts.labels,
+        opts.max_series,
+    )));
+    let listener = tokio::net::TcpListener::bind(&opts.listen).await?;
+    println!("serving http://{}/metrics", listener.local_addr()?);
+
+    let lines = input::open(&opts.input, opts.follow).await?;
+    let ingest_metrics = metrics.clone();
+    let ingest = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        for line in lines {
+            match parse_nginx_log(&line?) {
+                Ok(mut log) => {
+                    log.route = Some(normalizer.normalize(&log.url));
+                    ingest_metrics.lock().unwrap().observe(&log);
+                }
+                Err(_) => ingest_metrics.lock().unwrap().observe_parse_error(),
+            }
+        }
+        Ok(())
+    });
+
+    // keep serving after a finite input has been consumed
+    tokio::try_join!(metrics::serve(listener, metrics), async { ingest.await? })?;
+    Ok(())
+}
+
+async fn run_query(opts: cli::QueryOpts) -> anyhow::Result<()> {
+    let ctx = datafusion::prelude::SessionContext::new();
+    if opts.input.ends_with(".parquet") {
+        query::register_parquet(&ctx, &opts.input).await?;
+    } else {
+        let normalizer = route::RouteNormalizer::new(&opts.routes)?;
+        let mut logs = parse_nginx_logs(&opts.input).await?;
+        normalizer.apply(&mut logs);
+        user_agent::apply(&mut logs);
+        query::register_logs(&ctx, &logs)?;
+    }
+
+    let df = ctx.sql(&opts.sql).await?;
+    match &opts.output {
+        Some(output) => {
+            query::write(df, output).await?;
+            println!("{}", output);
+        }
+        None => df.show().await?,
+    }
+    Ok(())
+}
+
+async fn run_report(opts: cli::ReportOpts) -> anyhow::Result<()> {
+    let normalizer = route::RouteNormalizer::new(&opts.routes)?;
+    let mut logs = parse_nginx_logs(&opts.input).await?;
+    normalizer.apply(&mut logs);
+
+    let report = report::Report::new(&logs, opts.top);
+    std::fs::write(&opts.output, report.to_html())?;
+    println!("{}", opts.output);
+    Ok(())
+}
+
+async fn run_dashboard(opts: cli::DashboardOpts) -> anyhow::Result<()> {
+    let normalizer = route::RouteNormalizer::new(&opts.routes)?;
+    let lines = if opts.follow && !opts.from_start {
+        input::tail(&opts.input).await?
+    } else {
+        input::open(&opts.input, opts.follow).await?
+    };
+    // a full channel holds the reader back until the UI catches up
+    let (tx, rx) = std::sync::mpsc::sync_channel(dashboard::CHANNEL_CAPACITY);
+    std::thread::spawn(move || {
+        for line in lines {
+            // an undecodable line counts as a parse error; any other read
+            // error ends the input, after being counted the same way
+            let (log, stop) = match line {
+                Ok(line) => {
+                    let log = parse_nginx_log(&line).ok().map(|mut log| {
+                        log.route = Some(normalizer.normalize(&log.url));
+                        log
+                    });
+                    (log, false)
+                }
+                Err(e) => (None, e.kind() != std::io::ErrorKind::InvalidData),
+            };
+            if tx.send(log).is_err() || stop {
+                break;
+            }
+        }
+    });
+
+    let app = dashboard::App::new(opts.max_records, opts.top);
+    let result = dashboard::run(ratatui::init(), rx, app);
+    ratatui::restore();
+    result
+}
+
+async fn run_merge(opts: cli::MergeOpts) -> anyhow::Result<()> {
+    // undecodable lines are skipped like unparsable ones; any other read
+    // error ends its input and is reported once the merge is done
+    let read_error = std::rc::Rc::new(std::cell::RefCell::new(None));
+    let mut inputs = Vec::new();
+    for input in &opts.inputs {
+        let (source, path) = merge::parse_source(input);
+        let error = read_error.clone();
+        let lines = input::open(&path, false)
+            .await?
+            .filter(|line| !matches!(line, Err(e) if e.kind() == std::io::ErrorKind::InvalidData))
+            .map_while(move |line| {
+                line.map_err(|e| {
+                    error
+                        .borrow_mut()
+                        .get_or_insert_with(|| anyhow!("Failed to read {}: {}", path, e));
+                })
+                .ok()
+            });
+        inputs.push(merge::records(source, lines));
+    }
+    let tolerance = chrono::Duration::seconds(opts.tolerance);
+    let mut merged = merge::Merge::new(inputs, tolerance);
+
+    if opts.output == "-" {
+        let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+        for record in merged.by_ref() {
+            writeln!(out, "{}\t{}", record.source, record.line)?;
+        }
+        out.flush()?;
+    } else {
+        let logs = merged.by_ref().map(|r| r.log).collect::<Vec<_>>();
+        if let Some(e) = read_error.take() {
+            return Err(e);
+        }
+        println!("merged {} logs", logs.len());
+        println!("{}", write_logs_to_parquet(logs, &opts.output)?);
+    }
+    if let Some(e) = read_error.take() {
+        return Err(e);
+    }
+    if merged.out_of_order() > 0 {
+        eprintln!(
+            "{} records were further out of order than the tolerance",
+            merged.out_of_order()
+        );
+    }
+    Ok(())
+}
+
+async fn run_generate(opts: cli::GenerateOpts) -> anyhow::Result<()> {
+    let generator = generate::Generator::new(&generate::GeneratorConfig {
+        seed: opts.seed,
+        start: opts.start,
+        rate: opts.rate,
+        clients: opts.clients,
+        ipv6_ratio: opts.ipv6_ratio,
+        malformed_rate: opts.malformed_rate,
+        methods: opts.methods,
+        statuses: opts.statuses,
+        paths: opts.paths,
+        user_agents: opts.user_agents,
+        layout: opts.log_format,
+    })?;
+
+    let out: Box<dyn Write> = if opts.output == "-" {
+        Box::new(std::io::stdout().lock())
+    } else {
+        Box::new(File::create(&opts.output)?)
+    };
+    let mut out = std::io::BufWriter::new(out);
+    for generated in generator.take(opts.count) {
+        writeln!(out, "{}", generated.line)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+async fn run_ingress(opts: cli::IngressOpts) -> anyhow::Result<()> {
+    let mut namespaces = opts.known_namespaces.clone();
+    namespaces.extend(opts.namespace.clone());
+    let mut logs = input::read_input(&opts.input)
+        .await?
+        .lines()
+        .filter_map(|v| ingress::parse_ingress_log(v, &namespaces).ok())
+        .collect::<Vec<_>>();
+    if let Some(namespace) = &opts.namespace {
+        logs.retain(|v| v.namespace.as_ref() == Some(namespace));
+    }
+    if let Some(service) = &opts.service {
+        logs.retain(|v| v.service.as_ref() == Some(service));
+    }
+    println!("parsed {} logs", logs.len());
+    println!(
+        "{}",
+        ingress::write_ingress_logs_to_parquet(logs, &opts.output)?
+    );
+    Ok(())
+}
+
+async fn run_errors(opts: cli::ErrorsOpts) -> anyhow::Result<()> {
+    let input = input::read_input(&opts.input).await?;
+    let mut logs = if opts.apache {
+        apache::parse_apache_error_logs(&input)
+    } else {
+        error_log::parse_error_logs(&input)
+    };
+    if let Some(level) = opts.level {
+        logs.retain(|v| v.level >= level);
+    }
+    println!("parsed {} errors", logs.len());
+    println!(
+        "{}",
+        error_log::write_error_logs_to_parquet(&logs, &opts.output)?
+    );
+    Ok(())
+}
+
+fn write_logs_to_parquet(logs: Vec<NginxLog>, filename: &str) -> anyhow::Result<String> {
     let schema = Schema::new(vec![
         Field::new("addr", DataType::Utf8, false),
         Field::new("datetime", DataType::Int64, false),
@@ -88,12 +613,75 @@ fn write_logs_to_parquet(logs: Vec<NginxLog>) -> anyhow::Result<String> {
         Field::new("body_bytes", DataType::UInt64, true),
         Field::new("referer", DataType::Utf8, true),
         Field::new("user_agent", DataType::Utf8, true),
+        Field::new("route", DataType::Utf8, true),
+        Field::new("browser", DataType::Utf8, true),
+        Field::new("browser_version", DataType::Utf8, true),
+        Field::new("os", DataType::Utf8, true),
+        Field::new("device", DataType::Utf8, true),
+        Field::new("client", DataType::Utf8, true),
+        Field::new("country", DataType::Utf8, true),
+        Field::new("city", DataType::Utf8, true),
+        Field::new("asn", DataType::UInt32, true),
+        Field::new("as_org", DataType::Utf8, true),
+        Field::new("request_time", DataType::Float64, true),
+        Field::new("source", DataType::Utf8, true),
+        Field::new("request_length", DataType::UInt64, true),
+        Field::new("host", DataType::Utf8, true),
+        Field::new("request_id", DataType::Utf8, true),
+        Field::new_list("upstream_addr", list_item(DataType::Utf8), true),
+        Field::new_list("upstream_status", list_item(DataType::UInt16), true),
+        Field::new_list("upstream_response_time", list_item(DataType::Float64), true),
+        Field::new_list(
+            "upstream_response_length",
+            list_item(DataType::UInt64),
+            true,
+        ),
+        Field::new_list("upstream_connect_time", list_item(DataType::Float64), true),
+        Field::new_list("upstream_header_time", list_item(DataType::Float64), true),
+        Field::new_list("forwarded_for", list_item(DataType::Utf8), true),
+        Field::new("client_ip", DataType::Utf8, true),
     ]);
 
-    let filename = "nginx_logs.parquet";
+    let batch = logs_to_record_batch(&logs)?;
+
     let file = File::create(filename)?;
     let mut writer = ArrowWriter::try_new(file, Arc::new(schema), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+
+    Ok(filename.to_string())
+}
+
+fn list_item(data_type: DataType) -> Field {
+    Field::new("item", data_type, true)
+}
 
+fn write_logs_to_csv(logs: &[NginxLog], filename: &str) -> anyhow::Result<String> {
+    let batch = logs_to_record_batch(logs)?;
+    // the CSV writer has no list support, so lists are written as `[a, b]`
+    let options = arrow::util::display::FormatOptions::default();
+    let mut columns = Vec::new();
+    for (field, column) in batch.schema().fields().iter().zip(batch.columns()) {
+        let column = match column.data_type() {
+            DataType::List(_) => {
+                let formatter = arrow::util::display::ArrayFormatter::try_new(column, &options)?;
+                let values = (0..column.len())
+                    .map(|i| column.is_valid(i).then(|| formatter.value(i).to_string()))
+                    .collect::<StringArray>();
+                Arc::new(values) as ArrayRef
+            }
+            _ => column.clone(),
+        };
+        columns.push((field.name().clone(), column));
+    }
+    let batch = RecordBatch::try_from_iter(columns)?;
+    let mut writer = arrow::csv::Writer::new(File::create(filename)?);
+    writer.write(&batch)?;
+    Ok(filename.to_string())
+}
+
+/// Convert records to the Arrow batch written by `write_logs_to_parquet`.
+fn logs_to_record_batch(logs: &[NginxLog]) -> anyhow::Result<RecordBatch> {
     let addrs = logs
         .iter()
         .map(|v| v.addr.to_string())
@@ -133,6 +721,128 @@ fn write_logs_to_parquet(logs: Vec<NginxLog>) -> anyhow::Result<String> {
         .map(|v| v.user_agent.to_string())
         .collect::<Vec<String>>();
 
+    let routes = logs
+        .iter()
+        .map(|v| v.route.clone())
+        .collect::<Vec<Option<String>>>();
+
+    let uas = logs.iter().map(|v| v.ua.as_ref()).collect::<Vec<_>>();
+    let browsers = uas
+        .iter()
+        .map(|v| v.and_then(|ua| ua.browser.clone()))
+        .collect::<Vec<Option<String>>>();
+    let browser_versions = uas
+        .iter()
+        .map(|v| v.and_then(|ua| ua.browser_version.clone()))
+        .collect::<Vec<Option<String>>>();
+    let oses = uas
+        .iter()
+        .map(|v| v.and_then(|ua| ua.os.clone()))
+        .collect::<Vec<Option<String>>>();
+    let devices = uas
+        .iter()
+        .map(|v| v.map(|ua| ua.device.to_string()))
+        .collect::<Vec<Option<String>>>();
+    let clients = uas
+        .iter()
+        .map(|v| v.map(|ua| ua.client.to_string()))
+        .collect::<Vec<Option<String>>>();
+
+    let geos = logs.iter().map(|v| v.geo.as_ref()).collect::<Vec<_>>();
+    let countries = geos
+        .iter()
+        .map(|v| v.and_then(|geo| geo.country.clone()))
+        .collect::<Vec<Option<String>>>();
+    let cities = geos
+        .iter()
+        .map(|v| v.and_then(|geo| geo.city.clone()))
+        .collect::<Vec<Option<String>>>();
+    let asns = geos
+        .iter()
+        .map(|v| v.and_then(|geo| geo.asn))
+        .collect::<Vec<Option<u32>>>();
+    let as_orgs = geos
+        .iter()
+        .map(|v| v.and_then(|geo| geo.as_org.clone()))
+        .collect::<Vec<Option<String>>>();
+
+    let request_times = logs
+        .iter()
+        .map(|v| v.request_time)
+        .collect::<Vec<Option<f64>>>();
+
+    let sources = logs
+        .iter()
+        .map(|v| v.source.clone())
+        .collect::<Vec<Option<String>>>();
+
+    let request_lengths = logs
+        .iter()
+        .map(|v| v.request_length)
+        .collect::<Vec<Option<u64>>>();
+    let hosts = logs
+        .iter()
+        .map(|v| v.host.clone())
+        .collect::<Vec<Option<String>>>();
+    let request_ids = logs
+        .iter()
+        .map(|v| v.request_id.clone())
+        .collect::<Vec<Option<String>>>();
+
+    // upstream lists are flattened to one entry per attempt
+    let upstreams = logs.iter().map(|v| v.upstream.as_ref()).collect::<Vec<_>>();
+    let mut upstream_addrs = ListBuilder::new(StringBuilder::new());
+    for upstream in &upstreams {
+        match upstream.filter(|u| !u.addr.is_empty()) {
+            Some(u) => {
+                u.addr
+                    .attempts()
+                    .for_each(|v| upstream_addrs.values().append_option(v));
+                upstream_addrs.append(true);
+            }
+            None => upstream_addrs.append_null(),
+        }
+    }
+    let mut forwarded_for = ListBuilder::new(StringBuilder::new());
+    for log in logs {
+        if log.forwarded_for.is_empty() {
+            forwarded_for.append_null();
+        } else {
+            log.forwarded_for.iter().for_each(|v| {
+                forwarded_for
+                    .values()
+                    .append_option(v.map(|ip| ip.to_string()))
+            });
+            forwarded_for.append(true);
+        }
+    }
+    let client_ips = logs
+        .iter()
+        .map(|v| v.client_ip.map(|ip| ip.to_string()))
+        .collect::<Vec<Option<String>>>();
+
+    let upstream_statuses =
+        ListArray::from_iter_primitive::<UInt16Type, _, _>(upstreams.iter().map(|v| {
+            v.filter(|u| !u.status.is_empty())
+                .map(|u| u.status.attempts().map(|v| v.copied()).collect::<Vec<_>>())
+        }));
+    let upstream_lengths =
+        ListArray::from_iter_primitive::<UInt64Type, _, _>(upstreams.iter().map(|v| {
+            v.filter(|u| !u.response_length.is_empty()).map(|u| {
+                u.response_length
+                    .attempts()
+                    .map(|v| v.copied())
+                    .collect::<Vec<_>>()
+            })
+        }));
+    let time_list = |get: fn(&upstream::Upstream) -> &upstream::UpstreamList<f64>| {
+        ListArray::from_iter_primitive::<Float64Type, _, _>(upstreams.iter().map(|v| {
+            v.map(get)
+                .filter(|list| !list.is_empty())
+                .map(|list| list.attempts().map(|v| v.copied()).collect::<Vec<_>>())
+        }))
+    };
+
     let batch = RecordBatch::try_from_iter(vec![
         ("addr", Arc::new(StringArray::from(addrs)) as Arc<dyn Array>),
         (
@@ -164,17 +874,96 @@ fn write_logs_to_parquet(logs: Vec<NginxLog>) -> anyhow::Result<String> {
             "user_agent",
             Arc::new(StringArray::from(user_agents)) as Arc<dyn Array>,
         ),
+        (
+            "route",
+            Arc::new(StringArray::from(routes)) as Arc<dyn Array>,
+        ),
+        (
+            "browser",
+            Arc::new(StringArray::from(browsers)) as Arc<dyn Array>,
+        ),
+        (
+            "browser_version",
+            Arc::new(StringArray::from(browser_versions)) as Arc<dyn Array>,
+        ),
+        ("os", Arc::new(StringArray::from(oses)) as Arc<dyn Array>),
+        (
+            "device",
+            Arc::new(StringArray::from(devices)) as Arc<dyn Array>,
+        ),
+        (
+            "client",
+            Arc::new(StringArray::from(clients)) as Arc<dyn Array>,
+        ),
+        (
+            "country",
+            Arc::new(StringArray::from(countries)) as Arc<dyn Array>,
+        ),
+        (
+            "city",
+            Arc::new(StringArray::from(cities)) as Arc<dyn Array>,
+        ),
+        ("asn", Arc::new(UInt32Array::from(asns)) as Arc<dyn Array>),
+        (
+            "as_org",
+            Arc::new(StringArray::from(as_orgs)) as Arc<dyn Array>,
+        ),
+        (
+            "request_time",
+            Arc::new(Float64Array::from(request_times)) as Arc<dyn Array>,
+        ),
+        (
+            "source",
+            Arc::new(StringArray::from(sources)) as Arc<dyn Array>,
+        ),
+        (
+            "request_length",
+            Arc::new(UInt64Array::from(request_lengths)) as Arc<dyn Array>,
+        ),
+        ("host", Arc::new(StringArray::from(hosts)) as Arc<dyn Array>),
+        (
+            "request_id",
+            Arc::new(StringArray::from(request_ids)) as Arc<dyn Array>,
+        ),
+        (
+            "upstream_addr",
+            Arc::new(upstream_addrs.finish()) as Arc<dyn Array>,
+        ),
+        (
+            "upstream_status",
+            Arc::new(upstream_statuses) as Arc<dyn Array>,
+        ),
+        (
+            "upstream_response_time",
+            Arc::new(time_list(|u| &u.response_time)) as Arc<dyn Array>,
+        ),
+        (
+            "upstream_response_length",
+            Arc::new(upstream_lengths) as Arc<dyn Array>,
+        ),
+        (
+            "upstream_connect_time",
+            Arc::new(time_list(|u| &u.connect_time)) as Arc<dyn Array>,
+        ),
+        (
+            "upstream_header_time",
+            Arc::new(time_list(|u| &u.header_time)) as Arc<dyn Array>,
+        ),
+        (
+            "forwarded_for",
+            Arc::new(forwarded_for.finish()) as Arc<dyn Array>,
+        ),
+        (
+            "client_ip",
+            Arc::new(StringArray::from(client_ips)) as Arc<dyn Array>,
+        ),
     ])?;
 
-    writer.write(&batch)?;
-    writer.close()?;
-
-    Ok(filename.to_string())
+    Ok(batch)
 }
 
-async fn parse_nginx_logs() -> anyhow::Result<Vec<NginxLog>> {
-    let nginx_log_url = "https://raw.githubusercontent.com/elastic/examples/master/Common Data Formats/nginx_logs/nginx_logs";
-    let nginx_log = reqwest::get(nginx_log_url).await?.text().await?;
+async fn parse_nginx_logs(input: &str) -> anyhow::Result<Vec<NginxLog>> {
+    let nginx_log = input::read_input(input).await?;
     let logs = nginx_log
         .lines()
         .filter_map(|v| parse_nginx_log(v).ok())
@@ -199,6 +988,7 @@ fn parse_nginx_log(s: &str) -> PResult<NginxLog> {
     let body_bytes = parse_http_body_bytes(input)?;
     let referer = parse_quoted_string(input)?;
     let user_agent = parse_quoted_string(input)?;
+    let request_time = opt(parse_request_time).parse_next(input)?;
     Ok(NginxLog {
         addr: ip,
         datetime,
@@ -209,13 +999,26 @@ fn parse_nginx_log(s: &str) -> PResult<NginxLog> {
         body_bytes,
         referer,
         user_agent,
+        route: None,
+        ua: None,
+        geo: None,
+        request_time,
+        source: None,
+        request_length: None,
+        host: None,
+        request_id: None,
+        upstream: None,
+        forwarded_for: Vec::new(),
+        client_ip: None,
     })
 }
 
 fn parse_ip(s: &mut &str) -> PResult<IpAddr> {
-    let res: Vec<u8> = separated(4, digit1.parse_to::<u8>(), ".").parse_next(s)?;
+    let ret = take_while(1.., |c: char| c.is_ascii_hexdigit() || c == '.' || c == ':')
+        .parse_to()
+        .parse_next(s)?;
     space0(s)?;
-    Ok(IpAddr::V4(Ipv4Addr::new(res[0], res[1], res[2], res[3])))
+    Ok(ret)
 }
 
 fn parse_ignored(s: &mut &str) -> PResult<()> {
@@ -224,11 +1027,11 @@ fn parse_ignored(s: &mut &str) -> PResult<()> {
 }
 
 fn parse_datetime(s: &mut &str) -> PResult<DateTime<Utc>> {
-    let ret = delimited('[', take_until(1.., ']'), ']').parse_next(s)?;
+    let ret = delimited('[', take_until(1.., ']'), ']')
+        .try_map(|v| DateTime::parse_from_str(v, "%d/%b/%Y:%H:%M:%S %z"))
+        .parse_next(s)?;
     space0(s)?;
-    Ok(DateTime::parse_from_str(ret, "%d/%b/%Y:%H:%M:%S %z")
-        .map(|dt| dt.with_timezone(&Utc))
-        .unwrap())
+    Ok(ret.with_timezone(&Utc))
 }
 
 fn parse_http(s: &mut &str) -> PResult<(HttpMethod, String, HttpProto)> {
@@ -280,6 +1083,13 @@ fn parse_quoted_string(s: &mut &str) -> PResult<String> {
     Ok(ret.to_string())
 }
 
+// `$request_time` appended to the combined format, e.g. `... "curl/8.0" 0.012`
+fn parse_request_time(s: &mut &str) -> PResult<f64> {
+    let ret = float.parse_next(s)?;
+    space0(s)?;
+    Ok(ret)
+}
+
 impl FromStr for HttpProto {
     type Err = anyhow::Error;
 
@@ -313,6 +1123,116 @@ impl FromStr for HttpMethod {
     }
 }
 
+/// Builds test records by parsing a combined log line, so they come out as
+/// the parser would produce them. Unset fields default to a `GET /` from
+/// 1.1.1.1 at 2015-05-17 08:05:32 answered with `200 0` and user agent `x`.
+#[cfg(test)]
+struct LogBuilder {
+    addr: String,
+    time: String,
+    method: String,
+    url: String,
+    status: u16,
+    bytes: u64,
+    referer: String,
+    user_agent: String,
+    request_time: String,
+}
+
+#[cfg(test)]
+impl NginxLog {
+    fn builder() -> LogBuilder {
+        LogBuilder {
+            addr: "1.1.1.1".to_string(),
+            time: "08:05:32".to_string(),
+            method: "GET".to_string(),
+            url: "/".to_string(),
+            status: 200,
+            bytes: 0,
+            referer: "-".to_string(),
+            user_agent: "x".to_string(),
+            request_time: String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl LogBuilder {
+    fn addr(mut self, addr: &str) -> Self {
+        self.addr = addr.to_string();
+        self
+    }
+
+    /// Time of day on 17/May/2015, e.g. `08:05:32`.
+    fn at(mut self, time: &str) -> Self {
+        self.time = time.to_string();
+        self
+    }
+
+    /// Seconds after 08:00:00.
+    fn secs(self, secs: u32) -> Self {
+        let time = format!(
+            "{:02}:{:02}:{:02}",
+            8 + secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        );
+        self.at(&time)
+    }
+
+    fn method(mut self, method: &str) -> Self {
+        self.method = method.to_string();
+        self
+    }
+
+    fn url(mut self, url: &str) -> Self {
+        self.url = url.to_string();
+        self
+    }
+
+    fn status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    fn bytes(mut self, bytes: u64) -> Self {
+        self.bytes = bytes;
+        self
+    }
+
+    fn referer(mut self, referer: &str) -> Self {
+        self.referer = referer.to_string();
+        self
+    }
+
+    fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = user_agent.to_string();
+        self
+    }
+
+    /// `$request_time` as logged, e.g. `0.020`.
+    fn request_time(mut self, request_time: &str) -> Self {
+        self.request_time = request_time.to_string();
+        self
+    }
+
+    fn build(self) -> NginxLog {
+        let line = format!(
+            r#"{} - - [17/May/2015:{} +0000] "{} {} HTTP/1.1" {} {} "{}" "{}" {}"#,
+            self.addr,
+            self.time,
+            self.method,
+            self.url,
+            self.status,
+            self.bytes,
+            self.referer,
+            self.user_agent,
+            self.request_time
+        );
+        parse_nginx_log(line.trim_end()).unwrap()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
@@ -326,6 +1246,12 @@ mod tests {
         let ip = parse_ip(&mut s).unwrap();
         assert_eq!(s, "");
         assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)));
+
+        let mut s = "2001:db8::1 - ";
+        let ip = parse_ip(&mut s).unwrap();
+        assert_eq!(s, "- ");
+        assert_eq!(ip, "2001:db8::1".parse::<IpAddr>()?);
+        assert!(parse_ip(&mut "1.1.1").is_err());
         Ok(())
     }
 
@@ -348,4 +1274,42 @@ mod tests {
         assert_eq!(protocol, HttpProto::HTTP1_1);
         Ok(())
     }
+
+    #[test]
+    fn parse_nginx_log_should_accept_request_time() -> Result<()> {
+        let s = r#"93.180.71.3 - - [17/May/2015:08:05:32 +0000] "GET /downloads/product_1 HTTP/1.1" 304 0 "-" "curl/8.0" 0.012"#;
+        let log = parse_nginx_log(s).unwrap();
+        assert_eq!(log.user_agent, "curl/8.0");
+        assert_eq!(log.request_time, Some(0.012));
+
+        let s = r#"93.180.71.3 - - [17/May/2015:08:05:32 +0000] "GET /downloads/product_1 HTTP/1.1" 304 0 "-" "curl/8.0""#;
+        assert_eq!(parse_nginx_log(s).unwrap().request_time, None);
+        Ok(())
+    }
+
+    #[test]
+    fn csv_should_render_upstream_lists() -> Result<()> {
+        let format = log_format::LogFormat::new(
+            r#"$remote_addr [$time_local] "$request" $status $upstream_status $upstream_response_time"#,
+        )?;
+        let logs = [
+            r#"1.1.1.1 [17/May/2015:08:05:32 +0000] "GET / HTTP/1.1" 200 502 : 200 0.004 : 0.120"#,
+            r#"1.1.1.1 [17/May/2015:08:05:33 +0000] "GET / HTTP/1.1" 200 - -"#,
+        ]
+        .iter()
+        .map(|s| format.parse(s).unwrap())
+        .collect::<Vec<_>>();
+
+        let path =
+            std::env::temp_dir().join(format!("nginx-log-upstream-{}.csv", std::process::id()));
+        write_logs_to_csv(&logs, path.to_str().unwrap())?;
+        let csv = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+        let mut lines = csv.lines();
+        let header = lines.next().unwrap().split(',').collect::<Vec<_>>();
+        let column = header.iter().position(|v| *v == "upstream_status").unwrap();
+        assert!(lines.next().unwrap().contains(r#""[502, 200]""#));
+        assert_eq!(lines.next().unwrap().split(',').nth(column), Some(""));
+        Ok(())
+    }
 }
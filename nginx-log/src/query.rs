@@ -0,0 +1,117 @@
+use std::{path::Path, sync::Arc};
+
+use datafusion::{
+    common::config::CsvOptions,
+    dataframe::DataFrameWriteOptions,
+    datasource::MemTable,
+    prelude::{DataFrame, ParquetReadOptions, SessionContext},
+};
+
+use crate::{logs_to_record_batch, NginxLog};
+
+/// Name of the table queries run against.
+pub const TABLE: &str = "logs";
+
+/// Register parsed records as the `logs` table.
+pub fn register_logs(ctx: &SessionContext, logs: &[NginxLog]) -> anyhow::Result<()> {
+    let batch = logs_to_record_batch(logs)?;
+    let table = MemTable::try_new(batch.schema(), vec![vec![batch]])?;
+    ctx.register_table(TABLE, Arc::new(table))?;
+    Ok(())
+}
+
+/// Register a Parquet file written by `nginx-log parse` as the `logs` table.
+pub async fn register_parquet(ctx: &SessionContext, path: &str) -> anyhow::Result<()> {
+    ctx.register_parquet(TABLE, path, ParquetReadOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// Write a query result as Parquet or CSV, picked by the file extension.
+pub async fn write(df: DataFrame, output: &str) -> anyhow::Result<()> {
+    let options = DataFrameWriteOptions::new().with_single_file_output(true);
+    match Path::new(output).extension().and_then(|v| v.to_str()) {
+        Some("parquet") => df.write_parquet(output, options, None).await?,
+        Some("csv") => {
+            let csv = CsvOptions::default().with_has_header(true);
+            df.write_csv(output, options, Some(csv)).await?
+        }
+        _ => anyhow::bail!(
+            "Unsupported output, expected a .parquet or .csv file: {}",
+            output
+        ),
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::{AsArray, RecordBatch};
+    use arrow::datatypes::{Int64Type, UInt16Type};
+
+    use super::*;
+    use crate::parse_nginx_log;
+
+    fn logs() -> Vec<NginxLog> {
+        [200, 404, 200, 500, 200]
+            .iter()
+            .map(|status| {
+                let s = format!(
+                    r#"1.1.1.1 - - [17/May/2015:08:05:32 +0000] "GET / HTTP/1.1" {} 0 "-" "x""#,
+                    status
+                );
+                parse_nginx_log(&s).unwrap()
+            })
+            .collect()
+    }
+
+    fn status_counts(batches: &[RecordBatch]) -> Vec<(u16, i64)> {
+        batches
+            .iter()
+            .flat_map(|b| {
+                let status = b.column(0).as_primitive::<UInt16Type>().clone();
+                let count = b.column(1).as_primitive::<Int64Type>().clone();
+                (0..b.num_rows())
+                    .map(move |i| (status.value(i), count.value(i)))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn query_should_run_sql_over_logs() -> anyhow::Result<()> {
+        let ctx = SessionContext::new();
+        register_logs(&ctx, &logs())?;
+        let batches = ctx
+            .sql("SELECT status, count(*) FROM logs GROUP BY 1 ORDER BY 1")
+            .await?
+            .collect()
+            .await?;
+        assert_eq!(status_counts(&batches), vec![(200, 3), (404, 1), (500, 1)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_should_read_and_write_parquet() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("nginx-log-query-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let input = dir.join("logs.parquet").to_string_lossy().to_string();
+        let output = dir.join("out.csv").to_string_lossy().to_string();
+        crate::write_logs_to_parquet(logs(), &input)?;
+
+        let ctx = SessionContext::new();
+        register_parquet(&ctx, &input).await?;
+        let df = ctx
+            .sql("SELECT status, count(*) AS n FROM logs WHERE status >= 400 GROUP BY 1 ORDER BY 1")
+            .await?;
+        write(df.clone(), &output).await?;
+        assert_eq!(
+            std::fs::read_to_string(&output)?,
+            "status,n\n404,1\n500,1\n"
+        );
+        assert!(write(df, "out.json").await.is_err());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::anyhow;
+use winnow::Parser;
+
+use crate::{filter, NginxLog};
+
+/// Split an `X-Forwarded-For` value into hops, the original client first.
+/// Ports and brackets are stripped; `unknown` and junk tokens are kept as
+/// `None` so that walking the chain stops at them.
+pub fn parse_forwarded_for(s: &str) -> Vec<Option<IpAddr>> {
+    // nginx logs a missing header as `-`
+    if s.trim() == "-" {
+        return Vec::new();
+    }
+    s.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|token| !token.is_empty())
+        .map(parse_hop)
+        .collect()
+}
+
+fn parse_hop(token: &str) -> Option<IpAddr> {
+    let ip = token
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| token.parse::<SocketAddr>().ok().map(|v| v.ip()))
+        .or_else(|| token.strip_prefix('[')?.strip_suffix(']')?.parse().ok())?;
+    // `::ffff:1.2.3.4` is the IPv4 client seen through a dual-stack proxy
+    Some(ip.to_canonical())
+}
+
+/// Proxies whose `X-Forwarded-For` entries are believed, like nginx's
+/// `set_real_ip_from`.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    cidrs: Vec<(IpAddr, u8)>,
+    recursive: bool,
+}
+
+impl TrustedProxies {
+    /// `recursive` matches `real_ip_recursive on`.
+    pub fn new(cidrs: &[String], recursive: bool) -> anyhow::Result<Self> {
+        let cidrs = cidrs
+            .iter()
+            .map(|s| {
+                filter::parse_cidr
+                    .parse(s.as_str())
+                    .map_err(|e| anyhow!("Invalid trusted proxy CIDR\n{}", e))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { cidrs, recursive })
+    }
+
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.cidrs
+            .iter()
+            .any(|(net, prefix)| filter::in_cidr(&ip.to_canonical(), net, *prefix))
+    }
+
+    /// Walk the chain from the right the way the realip module does: a
+    /// trusted peer is replaced by the rightmost forwarded address, and in
+    /// recursive mode trusted addresses keep being skipped. A hop that is not
+    /// an address ends the walk at the last address reached.
+    pub fn client_ip(&self, addr: IpAddr, forwarded_for: &[Option<IpAddr>]) -> IpAddr {
+        if !self.is_trusted(&addr) {
+            return addr;
+        }
+        let mut client = addr;
+        for ip in forwarded_for.iter().rev() {
+            let Some(ip) = ip else {
+                break;
+            };
+            client = *ip;
+            if !self.recursive || !self.is_trusted(ip) {
+                break;
+            }
+        }
+        client
+    }
+
+    pub fn apply(&self, logs: &mut [NginxLog]) {
+        for log in logs {
+            log.client_ip = Some(self.client_ip(log.addr, &log.forwarded_for));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log_format::LogFormat;
+
+    // `-` stands for a hop that is not an address
+    fn ips(s: &[&str]) -> Vec<Option<IpAddr>> {
+        s.iter().map(|v| v.parse().ok()).collect()
+    }
+
+    #[test]
+    fn parse_forwarded_for_should_tolerate_noise() {
+        assert_eq!(
+            parse_forwarded_for("203.0.113.7, unknown,10.0.0.1:8080 , [2001:db8::1]:443"),
+            ips(&["203.0.113.7", "-", "10.0.0.1", "2001:db8::1"])
+        );
+        assert_eq!(
+            parse_forwarded_for("::ffff:198.51.100.2 2001:db8::2 <script> 300.1.1.1"),
+            ips(&["198.51.100.2", "2001:db8::2", "-", "-"])
+        );
+        assert!(parse_forwarded_for("-").is_empty());
+    }
+
+    #[test]
+    fn client_ip_should_walk_from_the_right() -> anyhow::Result<()> {
+        let trusted = ["10.0.0.0/8".to_string(), "2001:db8::/32".to_string()];
+        let chain = ips(&["198.51.100.1", "203.0.113.7", "10.1.1.1", "10.2.2.2"]);
+        let lb = "10.0.0.1".parse()?;
+
+        let recursive = TrustedProxies::new(&trusted, true)?;
+        assert_eq!(
+            recursive.client_ip(lb, &chain),
+            "203.0.113.7".parse::<IpAddr>()?
+        );
+        // an untrusted peer cannot spoof its address
+        let peer = "192.0.2.9".parse()?;
+        assert_eq!(recursive.client_ip(peer, &chain), peer);
+        // all trusted: the leftmost address wins
+        let internal = ips(&["10.9.9.9", "10.1.1.1"]);
+        assert_eq!(recursive.client_ip(lb, &internal), internal[0].unwrap());
+        assert_eq!(recursive.client_ip(lb, &[]), lb);
+        // like nginx, a junk hop ends the walk instead of being stepped over
+        let spoofed = ips(&["198.51.100.66", "-", "10.1.1.1"]);
+        assert_eq!(recursive.client_ip(lb, &spoofed), spoofed[2].unwrap());
+        assert_eq!(recursive.client_ip(lb, &ips(&["203.0.113.7", "-"])), lb);
+
+        let single = TrustedProxies::new(&trusted, false)?;
+        assert_eq!(single.client_ip(lb, &chain), chain[3].unwrap());
+
+        assert!(TrustedProxies::new(&["10.0.0.0/40".to_string()], true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn apply_should_set_client_ip_from_parsed_header() -> anyhow::Result<()> {
+        let format = LogFormat::new(
+            r#"$remote_addr [$time_local] "$request" $status "$http_x_forwarded_for""#,
+        )?;
+        let mut logs = vec![format
+            .parse(r#"10.0.0.1 [17/May/2015:08:05:32 +0000] "GET / HTTP/1.1" 200 "203.0.113.7, 10.0.0.2""#)
+            .unwrap()];
+        assert_eq!(logs[0].forwarded_for, ips(&["203.0.113.7", "10.0.0.2"]));
+
+        TrustedProxies::new(&["10.0.0.0/8".to_string()], true)?.apply(&mut logs);
+        assert_eq!(logs[0].client_ip, Some("203.0.113.7".parse()?));
+        assert_eq!(logs[0].client(), "203.0.113.7".parse::<IpAddr>()?);
+
+        // downstream stages key on the resolved client, not the proxy
+        let filter = crate::filter::parse_filter("client in 203.0.113.0/24")?;
+        assert!(filter.matches(&logs[0]));
+        // while `addr` stays the logged peer
+        let filter = crate::filter::parse_filter("addr == 10.0.0.1")?;
+        assert!(filter.matches(&logs[0]));
+        let mut sessions = crate::session::Sessionizer::new(
+            crate::session::SessionKey::Addr,
+            chrono::Duration::minutes(30),
+        );
+        assert!(sessions.push(&logs[0]).is_none());
+        assert_eq!(sessions.finish()[0].addr.to_string(), "203.0.113.7");
+        Ok(())
+    }
+}
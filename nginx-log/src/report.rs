@@ -0,0 +1,398 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Write as _,
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::NginxLog;
+
+const CHART_WIDTH: f64 = 900.0;
+const CHART_HEIGHT: f64 = 160.0;
+
+const STYLE: &str = r#"
+body { font-family: -apple-system, "Segoe UI", Helvetica, Arial, sans-serif; margin: 2em auto; max-width: 960px; color: #222; }
+h1 { font-size: 1.6em; } h2 { font-size: 1.2em; margin-top: 2em; border-bottom: 1px solid #ddd; }
+.cards { display: flex; gap: 1em; } .card { flex: 1; background: #f4f6f8; padding: .8em; border-radius: 6px; }
+.card b { display: block; font-size: 1.4em; }
+table { border-collapse: collapse; width: 100%; font-size: .9em; }
+td, th { padding: .3em .5em; border-bottom: 1px solid #eee; text-align: left; }
+td.n, th.n { text-align: right; white-space: nowrap; } td.t { word-break: break-all; }
+.bar { background: #4c78a8; height: .8em; }
+svg rect { fill: #4c78a8; } svg text { font-size: 11px; fill: #555; }
+"#;
+
+/// Aggregates behind the HTML report.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub requests: u64,
+    pub visitors: usize,
+    pub bytes: u64,
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+    pub bucket_secs: i64,
+    /// (bucket start, requests, bytes)
+    pub timeline: Vec<(DateTime<Utc>, u64, u64)>,
+    pub statuses: Vec<(u16, u64)>,
+    /// (path, requests, bytes)
+    pub top_paths: Vec<(String, u64, u64)>,
+    pub top_referers: Vec<(String, u64)>,
+    pub top_user_agents: Vec<(String, u64)>,
+    /// (url without its query, status, requests)
+    pub error_urls: Vec<(String, u16, u64)>,
+}
+
+impl Report {
+    /// Build the report keeping `top` rows per table. Paths use the
+    /// normalized route when there is one.
+    pub fn new(logs: &[NginxLog], top: usize) -> Self {
+        let start = logs.iter().map(|v| v.datetime).min();
+        let end = logs.iter().map(|v| v.datetime).max();
+        let span = match (start, end) {
+            (Some(start), Some(end)) => (end - start).num_seconds(),
+            _ => 0,
+        };
+        let bucket_secs = if span <= 2 * 3600 {
+            60
+        } else if span <= 2 * 86400 {
+            3600
+        } else {
+            86400
+        };
+
+        let mut timeline = BTreeMap::<i64, (u64, u64)>::new();
+        let mut statuses = BTreeMap::<u16, u64>::new();
+        let mut paths = HashMap::<String, (u64, u64)>::new();
+        let mut referers = HashMap::<String, u64>::new();
+        let mut user_agents = HashMap::<String, u64>::new();
+        let mut errors = HashMap::<(String, u16), u64>::new();
+        let mut visitors = HashSet::new();
+        for log in logs {
+            let ts = log.datetime.timestamp();
+            let bucket = timeline.entry(ts - ts.rem_euclid(bucket_secs)).or_default();
+            bucket.0 += 1;
+            bucket.1 += log.body_bytes;
+            *statuses.entry(log.status).or_default() += 1;
+
+            let path = log.route.clone().unwrap_or_else(|| log.url.clone());
+            let p = paths.entry(path.clone()).or_default();
+            p.0 += 1;
+            p.1 += log.body_bytes;
+            if log.status >= 400 {
+                // the failing url itself, not the route it was folded into
+                let url = log.url.split_once('?').map_or(&*log.url, |(v, _)| v);
+                *errors.entry((url.to_string(), log.status)).or_default() += 1;
+            }
+            if log.referer != "-" && !log.referer.is_empty() {
+                *referers.entry(log.referer.clone()).or_default() += 1;
+            }
+            *user_agents.entry(log.user_agent.clone()).or_default() += 1;
+            visitors.insert(log.client());
+        }
+        // quiet buckets are drawn as zeros rather than skipped
+        if let (Some(&first), Some(&last)) = (timeline.keys().next(), timeline.keys().last()) {
+            for ts in (first..last).step_by(bucket_secs as usize) {
+                timeline.entry(ts).or_default();
+            }
+        }
+
+        let mut top_paths = paths
+            .into_iter()
+            .map(|(path, (requests, bytes))| (path, requests, bytes))
+            .collect::<Vec<_>>();
+        top_paths.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top_paths.truncate(top);
+
+        let mut error_urls = errors
+            .into_iter()
+            .map(|((path, status), requests)| (path, status, requests))
+            .collect::<Vec<_>>();
+        error_urls.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| (&a.0, a.1).cmp(&(&b.0, b.1))));
+        error_urls.truncate(top);
+
+        Self {
+            requests: logs.len() as u64,
+            visitors: visitors.len(),
+            bytes: logs.iter().map(|v| v.body_bytes).sum(),
+            start,
+            end,
+            bucket_secs,
+            timeline: timeline
+                .into_iter()
+                .map(|(ts, (requests, bytes))| (Utc.timestamp_opt(ts, 0).unwrap(), requests, bytes))
+                .collect(),
+            statuses: statuses.into_iter().collect(),
+            top_paths,
+            top_referers: top_n(referers, top),
+            top_user_agents: top_n(user_agents, top),
+            error_urls,
+        }
+    }
+
+    /// Render a single HTML file with inline CSS and SVG charts, so it needs
+    /// no network access to display.
+    pub fn to_html(&self) -> String {
+        let mut out = String::new();
+        let period = match (self.start, self.end) {
+            (Some(start), Some(end)) => format!("{} – {}", start.to_rfc3339(), end.to_rfc3339()),
+            _ => "no records".to_string(),
+        };
+        let _ = write!(
+            out,
+            concat!(
+                "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n",
+                "<title>nginx access log report</title>\n<style>{}</style>\n</head>\n<body>\n",
+                "<h1>nginx access log report</h1>\n<p>{}</p>\n",
+            ),
+            STYLE,
+            escape_html(&period)
+        );
+
+        let errors = self.statuses.iter().filter(|(s, _)| *s >= 400);
+        let cards = [
+            (self.requests.to_string(), "requests"),
+            (self.visitors.to_string(), "unique visitors"),
+            (format_bytes(self.bytes), "bandwidth"),
+            (errors.map(|(_, n)| n).sum::<u64>().to_string(), "errors"),
+        ];
+        out.push_str("<div class=\"cards\">");
+        for (value, label) in cards {
+            let _ = write!(out, "<div class=\"card\"><b>{value}</b>{label}</div>");
+        }
+        out.push_str("</div>\n");
+
+        let unit = match self.bucket_secs {
+            60 => "minute",
+            3600 => "hour",
+            _ => "day",
+        };
+        let labels = self
+            .timeline
+            .iter()
+            .map(|(t, _, _)| t.format("%Y-%m-%d %H:%M").to_string())
+            .collect::<Vec<_>>();
+        let _ = writeln!(out, "<h2>Requests per {unit}</h2>");
+        let requests = self.timeline.iter().map(|v| v.1).collect::<Vec<_>>();
+        out.push_str(&bar_chart(&labels, &requests, |v| v.to_string()));
+        let _ = writeln!(out, "<h2>Bandwidth per {unit}</h2>");
+        let bytes = self.timeline.iter().map(|v| v.2).collect::<Vec<_>>();
+        out.push_str(&bar_chart(&labels, &bytes, format_bytes));
+
+        out.push_str("<h2>Status codes</h2>\n");
+        let max = self.statuses.iter().map(|v| v.1).max().unwrap_or(0);
+        let rows = self
+            .statuses
+            .iter()
+            .map(|(status, n)| vec![status.to_string(), n.to_string(), bar(*n, max)])
+            .collect::<Vec<_>>();
+        out.push_str(&table(
+            &["Status", "Requests", ""],
+            &rows,
+            &[false, true, false],
+        ));
+
+        out.push_str("<h2>Top paths</h2>\n");
+        let rows = self
+            .top_paths
+            .iter()
+            .map(|(path, n, bytes)| vec![escape_html(path), n.to_string(), format_bytes(*bytes)])
+            .collect::<Vec<_>>();
+        out.push_str(&table(
+            &["Path", "Requests", "Bandwidth"],
+            &rows,
+            &[false, true, true],
+        ));
+
+        out.push_str("<h2>Top referrers</h2>\n");
+        out.push_str(&count_table("Referrer", &self.top_referers));
+        out.push_str("<h2>Top user agents</h2>\n");
+        out.push_str(&count_table("User agent", &self.top_user_agents));
+
+        out.push_str("<h2>Error URLs</h2>\n");
+        let rows = self
+            .error_urls
+            .iter()
+            .map(|(path, status, n)| vec![escape_html(path), status.to_string(), n.to_string()])
+            .collect::<Vec<_>>();
+        out.push_str(&table(
+            &["Path", "Status", "Requests"],
+            &rows,
+            &[false, true, true],
+        ));
+
+        out.push_str("</body>\n</html>\n");
+        out
+    }
+}
+
+fn top_n(counts: HashMap<String, u64>, n: usize) -> Vec<(String, u64)> {
+    let mut v = counts.into_iter().collect::<Vec<_>>();
+    v.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    v.truncate(n);
+    v
+}
+
+fn bar_chart(labels: &[String], values: &[u64], format: impl Fn(u64) -> String) -> String {
+    let mut out = String::new();
+    let max = values.iter().copied().max().unwrap_or(0).max(1) as f64;
+    let width = CHART_WIDTH / values.len().max(1) as f64;
+    let _ = writeln!(
+        out,
+        "<svg viewBox=\"0 0 {CHART_WIDTH} {}\" width=\"100%\" role=\"img\">",
+        CHART_HEIGHT + 20.0
+    );
+    for (i, (label, v)) in labels.iter().zip(values).enumerate() {
+        let h = *v as f64 / max * CHART_HEIGHT;
+        let _ = writeln!(
+            out,
+            "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\"><title>{}: {}</title></rect>",
+            i as f64 * width,
+            CHART_HEIGHT - h,
+            (width - 1.0).max(0.5),
+            h,
+            escape_html(label),
+            format(*v)
+        );
+    }
+    if let (Some(first), Some(last)) = (labels.first(), labels.last()) {
+        let _ = writeln!(
+            out,
+            "<text x=\"0\" y=\"{}\">{}</text><text x=\"{CHART_WIDTH}\" y=\"{}\" text-anchor=\"end\">{}</text>",
+            CHART_HEIGHT + 15.0,
+            escape_html(first),
+            CHART_HEIGHT + 15.0,
+            escape_html(last)
+        );
+    }
+    out.push_str("</svg>\n");
+    out
+}
+
+fn bar(v: u64, max: u64) -> String {
+    format!(
+        "<div class=\"bar\" style=\"width:{:.1}%\"></div>",
+        v as f64 / max.max(1) as f64 * 100.0
+    )
+}
+
+fn count_table(title: &str, rows: &[(String, u64)]) -> String {
+    let rows = rows
+        .iter()
+        .map(|(k, n)| vec![escape_html(k), n.to_string()])
+        .collect::<Vec<_>>();
+    table(&[title, "Requests"], &rows, &[false, true])
+}
+
+// cells are expected to be escaped already
+fn table(headers: &[&str], rows: &[Vec<String>], numeric: &[bool]) -> String {
+    let class = |i: usize| if numeric[i] { "n" } else { "t" };
+    let mut out = String::from("<table>\n<tr>");
+    for (i, h) in headers.iter().enumerate() {
+        let _ = write!(out, "<th class=\"{}\">{}</th>", class(i), escape_html(h));
+    }
+    out.push_str("</tr>\n");
+    for row in rows {
+        out.push_str("<tr>");
+        for (i, cell) in row.iter().enumerate() {
+            let _ = write!(out, "<td class=\"{}\">{}</td>", class(i), cell);
+        }
+        out.push_str("</tr>\n");
+    }
+    out.push_str("</table>\n");
+    out
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut v = bytes as f64;
+    let mut unit = 0;
+    while v >= 1024.0 && unit < UNITS.len() - 1 {
+        v /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", v, UNITS[unit])
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_should_aggregate_logs() {
+        let mut logs = vec![
+            NginxLog::builder().secs(0).url("/a").bytes(100).build(),
+            NginxLog::builder()
+                .secs(0)
+                .url("/a")
+                .bytes(100)
+                .referer("http://x/")
+                .build(),
+            NginxLog::builder()
+                .addr("2.2.2.2")
+                .secs(300)
+                .url("/missing?id=1")
+                .status(404)
+                .referer("http://x/")
+                .build(),
+            NginxLog::builder()
+                .addr("3.3.3.3")
+                .secs(300)
+                .url("/b")
+                .bytes(50)
+                .build(),
+        ];
+        logs[2].route = Some("/{page}".to_string());
+        let report = Report::new(&logs, 2);
+        assert_eq!(report.requests, 4);
+        assert_eq!(report.visitors, 3);
+        assert_eq!(report.bytes, 250);
+        assert_eq!(report.bucket_secs, 60);
+        assert_eq!(
+            report
+                .timeline
+                .iter()
+                .map(|v| (v.1, v.2))
+                .collect::<Vec<_>>(),
+            vec![(2, 200), (0, 0), (0, 0), (0, 0), (0, 0), (2, 50)]
+        );
+        assert_eq!(
+            report.timeline[1].0.to_rfc3339(),
+            "2015-05-17T08:01:00+00:00"
+        );
+        assert_eq!(report.statuses, vec![(200, 3), (404, 1)]);
+        assert_eq!(
+            report.top_paths,
+            vec![("/a".to_string(), 2, 200), ("/b".to_string(), 1, 50)]
+        );
+        assert_eq!(report.top_referers, vec![("http://x/".to_string(), 2)]);
+        assert_eq!(report.error_urls, vec![("/missing".to_string(), 404, 1)]);
+    }
+
+    #[test]
+    fn to_html_should_be_self_contained() {
+        let logs = vec![NginxLog::builder()
+            .secs(0)
+            .url("/<script>")
+            .status(404)
+            .bytes(2048)
+            .build()];
+        let html = Report::new(&logs, 10).to_html();
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("&lt;script&gt;"));
+        assert!(!html.contains("<script"));
+        assert!(!html.contains(" src="));
+        assert!(!html.contains("<link"));
+        assert!(html.contains("2.0 KiB"));
+    }
+}
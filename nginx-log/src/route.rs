@@ -0,0 +1,185 @@
+use anyhow::anyhow;
+use winnow::{
+    combinator::{alt, delimited},
+    stream::AsChar,
+    token::{take_till, take_while},
+    PResult, Parser,
+};
+
+use crate::NginxLog;
+
+/// Collapses high-cardinality URL paths into routes such as `/users/:id/orders/:uuid`.
+///
+/// User-supplied templates (`/api/v1/items/{id}`) are tried first, in the order
+/// they were added; the generic placeholder rules only apply when none matches.
+#[derive(Debug, Default)]
+pub struct RouteNormalizer {
+    templates: Vec<RouteTemplate>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct RouteTemplate {
+    pattern: String,
+    segments: Vec<TemplateSegment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplateSegment {
+    Literal(String),
+    Param,
+}
+
+impl RouteNormalizer {
+    pub fn new(templates: &[String]) -> anyhow::Result<Self> {
+        let templates = templates
+            .iter()
+            .map(|t| parse_template(t))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { templates })
+    }
+
+    /// Fill in `route` for every log record.
+    pub fn apply(&self, logs: &mut [NginxLog]) {
+        for log in logs {
+            log.route = Some(self.normalize(&log.url));
+        }
+    }
+
+    pub fn normalize(&self, url: &str) -> String {
+        let path = url.split(['?', '#']).next().unwrap_or_default();
+        let segments = path.split('/').collect::<Vec<_>>();
+
+        if let Some(t) = self.templates.iter().find(|t| t.matches(&segments)) {
+            return t.pattern.clone();
+        }
+
+        segments
+            .iter()
+            .map(|s| normalize_segment(s))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+impl RouteTemplate {
+    fn matches(&self, segments: &[&str]) -> bool {
+        self.segments.len() == segments.len()
+            && self.segments.iter().zip(segments).all(|(t, s)| match t {
+                TemplateSegment::Literal(l) => l == s,
+                TemplateSegment::Param => !s.is_empty(),
+            })
+    }
+}
+
+fn parse_template(s: &str) -> anyhow::Result<RouteTemplate> {
+    if !s.starts_with('/') {
+        return Err(anyhow!("Route template must start with '/': {}", s));
+    }
+    let segments = s
+        .split('/')
+        .map(|seg| {
+            parse_template_segment
+                .parse(seg)
+                .map_err(|e| anyhow!("Invalid route template {}: {}", s, e))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(RouteTemplate {
+        pattern: s.to_string(),
+        segments,
+    })
+}
+
+fn parse_template_segment(s: &mut &str) -> PResult<TemplateSegment> {
+    alt((
+        delimited('{', take_while(1.., is_param_char), '}').value(TemplateSegment::Param),
+        take_till(0.., ['{', '}']).map(|v: &str| TemplateSegment::Literal(v.to_string())),
+    ))
+    .parse_next(s)
+}
+
+fn is_param_char(c: char) -> bool {
+    c.is_alphanum() || c == '_'
+}
+
+fn normalize_segment(s: &str) -> &str {
+    if s.is_empty() {
+        s
+    } else if parse_uuid.parse(s).is_ok() {
+        ":uuid"
+    } else if parse_date.parse(s).is_ok() {
+        ":date"
+    } else if s.chars().all(|c| c.is_ascii_digit()) {
+        ":id"
+    } else if s.len() >= 16 && s.chars().all(|c| c.is_ascii_hexdigit()) {
+        ":hash"
+    } else {
+        s
+    }
+}
+
+fn hex_digits<'s>(n: usize) -> impl Parser<&'s str, &'s str, winnow::error::ContextError> {
+    take_while(n, AsChar::is_hex_digit)
+}
+
+// e.g. 123e4567-e89b-12d3-a456-426614174000
+fn parse_uuid(s: &mut &str) -> PResult<()> {
+    (
+        hex_digits(8),
+        '-',
+        hex_digits(4),
+        '-',
+        hex_digits(4),
+        '-',
+        hex_digits(4),
+        '-',
+        hex_digits(12),
+    )
+        .void()
+        .parse_next(s)
+}
+
+// e.g. 2024-03-01
+fn parse_date(s: &mut &str) -> PResult<()> {
+    let digits = |n| take_while(n, AsChar::is_dec_digit);
+    (digits(4), '-', digits(2), '-', digits(2))
+        .void()
+        .parse_next(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_should_replace_placeholders() {
+        let n = RouteNormalizer::default();
+        assert_eq!(n.normalize("/users/81723/orders"), "/users/:id/orders");
+        assert_eq!(
+            n.normalize("/orders/123e4567-e89b-12d3-a456-426614174000?x=1"),
+            "/orders/:uuid"
+        );
+        assert_eq!(
+            n.normalize("/blobs/aa9f3c0d1e2b4f5a6b7c8d9e/raw"),
+            "/blobs/:hash/raw"
+        );
+        assert_eq!(n.normalize("/archive/2024-03-01/"), "/archive/:date/");
+        assert_eq!(n.normalize("/downloads/product_1"), "/downloads/product_1");
+        assert_eq!(n.normalize("/"), "/");
+    }
+
+    #[test]
+    fn templates_should_match_first() {
+        let n = RouteNormalizer::new(&["/api/v1/items/{id}".to_string()]).unwrap();
+        assert_eq!(n.normalize("/api/v1/items/widget-7"), "/api/v1/items/{id}");
+        assert_eq!(n.normalize("/api/v1/items/42"), "/api/v1/items/{id}");
+        assert_eq!(n.normalize("/api/v1/users/42"), "/api/v1/users/:id");
+        assert_eq!(n.normalize("/api/v1/items/"), "/api/v1/items/");
+    }
+
+    #[test]
+    fn parse_template_should_reject_invalid() {
+        assert!(parse_template("api/{id}").is_err());
+        assert!(parse_template("/api/{id").is_err());
+        assert!(parse_template("/api/{}").is_err());
+    }
+}
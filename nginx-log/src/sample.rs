@@ -0,0 +1,202 @@
+use clap::ValueEnum;
+use strum_macros::Display;
+
+use crate::NginxLog;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum SampleKey {
+    /// Keep or drop every request of a client together
+    Addr,
+    UserAgent,
+    Url,
+    /// The normalized route, falling back to the url
+    Route,
+}
+
+/// Keeps a fixed fraction of records chosen by a hash of one field, so the
+/// same records are picked across runs and files.
+#[derive(Debug, Clone)]
+pub struct HashSampler {
+    key: SampleKey,
+    threshold: u64,
+    seed: u64,
+}
+
+impl HashSampler {
+    pub fn new(key: SampleKey, rate: f64, seed: u64) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            (0.0..=1.0).contains(&rate),
+            "Sample rate must be between 0 and 1: {}",
+            rate
+        );
+        let threshold = if rate >= 1.0 {
+            u64::MAX
+        } else {
+            (rate * u64::MAX as f64) as u64
+        };
+        Ok(Self {
+            key,
+            threshold,
+            seed,
+        })
+    }
+
+    pub fn keep(&self, log: &NginxLog) -> bool {
+        let hash = match self.key {
+            SampleKey::Addr => stable_hash(self.seed, log.client().to_string().as_bytes()),
+            SampleKey::UserAgent => stable_hash(self.seed, log.user_agent.as_bytes()),
+            SampleKey::Url => stable_hash(self.seed, log.url.as_bytes()),
+            SampleKey::Route => {
+                let route = log.route.as_deref().unwrap_or(&log.url);
+                stable_hash(self.seed, route.as_bytes())
+            }
+        };
+        hash < self.threshold || self.threshold == u64::MAX
+    }
+}
+
+/// Uniform sample of at most `size` items (Algorithm R). Items come out in
+/// the order they were pushed.
+pub struct Reservoir<T> {
+    size: usize,
+    seen: u64,
+    items: Vec<(u64, T)>,
+    rng: SplitMix64,
+}
+
+impl<T> Reservoir<T> {
+    pub fn new(size: usize, seed: u64) -> anyhow::Result<Self> {
+        anyhow::ensure!(size >= 1, "Reservoir size must be at least 1");
+        // the input may be much smaller than `size`, so grow as items come
+        Ok(Self {
+            size,
+            seen: 0,
+            items: Vec::new(),
+            rng: SplitMix64(seed),
+        })
+    }
+
+    pub fn push(&mut self, item: T) {
+        let seq = self.seen;
+        self.seen += 1;
+        if self.items.len() < self.size {
+            self.items.push((seq, item));
+        } else {
+            let j = self.rng.below(self.seen);
+            if (j as usize) < self.size {
+                self.items[j as usize] = (seq, item);
+            }
+        }
+    }
+
+    pub fn into_vec(mut self) -> Vec<T> {
+        self.items.sort_by_key(|(seq, _)| *seq);
+        self.items.into_iter().map(|(_, item)| item).collect()
+    }
+}
+
+impl<T> Extend<T> for Reservoir<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        iter.into_iter().for_each(|item| self.push(item));
+    }
+}
+
+// FNV-1a, finished with a SplitMix64 round to spread similar keys
+fn stable_hash(seed: u64, bytes: &[u8]) -> u64 {
+    let hash = seed
+        .to_le_bytes()
+        .iter()
+        .chain(bytes)
+        .fold(FNV_OFFSET, |h, b| (h ^ *b as u64).wrapping_mul(FNV_PRIME));
+    SplitMix64(hash).next_u64()
+}
+
+/// Small seeded PRNG, good enough for sampling and synthetic data.
+pub struct SplitMix64(pub u64);
+
+impl SplitMix64 {
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniform in `[0, n)`.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_sampler_should_be_deterministic_per_key() -> anyhow::Result<()> {
+        let sampler = HashSampler::new(SampleKey::Addr, 0.1, 7)?;
+        let logs = (0..10_000)
+            .map(|i| {
+                NginxLog::builder()
+                    .addr(&format!("10.{}.{}.1", i / 256, i % 256))
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        let kept = logs.iter().filter(|l| sampler.keep(l)).count();
+        assert!((800..1200).contains(&kept), "{}", kept);
+
+        // every request of a kept client is kept, whatever the url
+        let client = logs
+            .iter()
+            .find(|l| sampler.keep(l))
+            .unwrap()
+            .addr
+            .to_string();
+        assert!(sampler.keep(&NginxLog::builder().addr(&client).url("/other").build()));
+        let again = HashSampler::new(SampleKey::Addr, 0.1, 7)?;
+        assert!(logs.iter().all(|l| sampler.keep(l) == again.keep(l)));
+
+        assert!(!HashSampler::new(SampleKey::Url, 0.0, 7)?.keep(&logs[0]));
+        assert!(HashSampler::new(SampleKey::Url, 1.0, 7)?.keep(&logs[0]));
+        assert!(HashSampler::new(SampleKey::Url, 1.5, 7).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reservoir_should_sample_uniformly_in_order() -> anyhow::Result<()> {
+        let mut reservoir = Reservoir::new(10, 1)?;
+        reservoir.extend(0..5);
+        assert_eq!(reservoir.into_vec(), vec![0, 1, 2, 3, 4]);
+        assert!(Reservoir::<u32>::new(0, 1).is_err());
+        let mut reservoir = Reservoir::new(usize::MAX, 1)?;
+        reservoir.extend(0..3);
+        assert_eq!(reservoir.into_vec(), vec![0, 1, 2]);
+
+        let mut counts = [0; 10];
+        for seed in 0..2000 {
+            let mut reservoir = Reservoir::new(3, seed)?;
+            reservoir.extend(0..10);
+            let sample = reservoir.into_vec();
+            assert_eq!(sample.len(), 3);
+            assert!(sample.windows(2).all(|w| w[0] < w[1]));
+            sample.iter().for_each(|i| counts[*i] += 1);
+        }
+        // each item is expected 600 times
+        assert!(
+            counts.iter().all(|c| (500..700).contains(c)),
+            "{:?}",
+            counts
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+use std::{collections::HashMap, fs::File, net::IpAddr, sync::Arc};
+
+use arrow::{
+    array::{Array, Int64Array, RecordBatch, StringArray, UInt64Array},
+    datatypes::{DataType, Field, Schema},
+};
+use chrono::{DateTime, Duration, Utc};
+use clap::ValueEnum;
+use parquet::arrow::ArrowWriter;
+
+use crate::NginxLog;
+
+/// Which fields identify a visitor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum SessionKey {
+    #[default]
+    AddrUserAgent,
+    Addr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub addr: IpAddr,
+    pub user_agent: Option<String>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub pages: u64,
+    pub bytes: u64,
+    pub entry_url: String,
+    pub exit_url: String,
+}
+
+/// Groups a time-ordered stream of records into visitor sessions that end
+/// after `timeout` without requests.
+pub struct Sessionizer {
+    key: SessionKey,
+    timeout: Duration,
+    open: HashMap<(IpAddr, Option<String>), Session>,
+}
+
+impl Sessionizer {
+    pub fn new(key: SessionKey, timeout: Duration) -> Self {
+        Self {
+            key,
+            timeout,
+            open: HashMap::new(),
+        }
+    }
+
+    /// Add a record, returning the visitor's previous session if it timed out.
+    pub fn push(&mut self, log: &NginxLog) -> Option<Session> {
+        let user_agent = match self.key {
+            SessionKey::AddrUserAgent => Some(log.user_agent.clone()),
+            SessionKey::Addr => None,
+        };
+        let key = (log.client(), user_agent);
+
+        match self.open.get_mut(&key) {
+            Some(session) if log.datetime - session.end <= self.timeout => {
+                // tolerate slightly out-of-order lines without moving backwards
+                session.end = session.end.max(log.datetime);
+                session.pages += 1;
+                session.bytes += log.body_bytes;
+                session.exit_url = log.url.clone();
+                None
+            }
+            _ => {
+                let session = Session {
+                    addr: log.client(),
+                    user_agent: key.1.clone(),
+                    start: log.datetime,
+                    end: log.datetime,
+                    pages: 1,
+                    bytes: log.body_bytes,
+                    entry_url: log.url.clone(),
+                    exit_url: log.url.clone(),
+                };
+                self.open.insert(key, session)
+            }
+        }
+    }
+
+    /// Close all sessions that are still open, ordered by start time.
+    pub fn finish(self) -> Vec<Session> {
+        let mut sessions = self.open.into_values().collect::<Vec<_>>();
+        sessions.sort_by_key(|s| s.start);
+        sessions
+    }
+}
+
+/// Build sessions from records in any order.
+pub fn sessionize(logs: &[NginxLog], key: SessionKey, timeout: Duration) -> Vec<Session> {
+    let mut ordered = logs.iter().collect::<Vec<_>>();
+    ordered.sort_by_key(|v| v.datetime);
+
+    let mut sessionizer = Sessionizer::new(key, timeout);
+    let mut sessions = ordered
+        .into_iter()
+        .filter_map(|v| sessionizer.push(v))
+        .collect::<Vec<_>>();
+    sessions.extend(sessionizer.finish());
+    sessions.sort_by_key(|s| s.start);
+    sessions
+}
+
+pub fn write_sessions_to_parquet(sessions: &[Session], filename: &str) -> anyhow::Result<String> {
+    let schema = Schema::new(vec![
+        Field::new("addr", DataType::Utf8, false),
+        Field::new("user_agent", DataType::Utf8, true),
+        Field::new("start", DataType::Int64, false),
+        Field::new("end", DataType::Int64, false),
+        Field::new("pages", DataType::UInt64, false),
+        Field::new("bytes", DataType::UInt64, false),
+        Field::new("entry_url", DataType::Utf8, false),
+        Field::new("exit_url", DataType::Utf8, false),
+    ]);
+    let schema = Arc::new(schema);
+
+    let addrs = sessions
+        .iter()
+        .map(|v| v.addr.to_string())
+        .collect::<Vec<String>>();
+    let user_agents = sessions
+        .iter()
+        .map(|v| v.user_agent.clone())
+        .collect::<Vec<Option<String>>>();
+    let starts = sessions
+        .iter()
+        .map(|v| v.start.timestamp())
+        .collect::<Vec<i64>>();
+    let ends = sessions
+        .iter()
+        .map(|v| v.end.timestamp())
+        .collect::<Vec<i64>>();
+    let pages = sessions.iter().map(|v| v.pages).collect::<Vec<u64>>();
+    let bytes = sessions.iter().map(|v| v.bytes).collect::<Vec<u64>>();
+    let entry_urls = sessions
+        .iter()
+        .map(|v| v.entry_url.clone())
+        .collect::<Vec<String>>();
+    let exit_urls = sessions
+        .iter()
+        .map(|v| v.exit_url.clone())
+        .collect::<Vec<String>>();
+
+    let batch = RecordBatch::try_new(
+        schema.clone(),
+        vec![
+            Arc::new(StringArray::from(addrs)) as Arc<dyn Array>,
+            Arc::new(StringArray::from(user_agents)) as Arc<dyn Array>,
+            Arc::new(Int64Array::from(starts)) as Arc<dyn Array>,
+            Arc::new(Int64Array::from(ends)) as Arc<dyn Array>,
+            Arc::new(UInt64Array::from(pages)) as Arc<dyn Array>,
+            Arc::new(UInt64Array::from(bytes)) as Arc<dyn Array>,
+            Arc::new(StringArray::from(entry_urls)) as Arc<dyn Array>,
+            Arc::new(StringArray::from(exit_urls)) as Arc<dyn Array>,
+        ],
+    )?;
+
+    let file = File::create(filename)?;
+    let mut writer = ArrowWriter::try_new(file, schema, None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+
+    Ok(filename.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessionize_should_split_on_idle_timeout() {
+        let logs = vec![
+            NginxLog::builder()
+                .at("08:00:00")
+                .url("/a")
+                .bytes(100)
+                .build(),
+            NginxLog::builder()
+                .at("08:10:00")
+                .url("/b")
+                .bytes(100)
+                .build(),
+            NginxLog::builder()
+                .at("08:55:00")
+                .url("/c")
+                .bytes(100)
+                .build(),
+            NginxLog::builder()
+                .addr("2.2.2.2")
+                .at("08:05:00")
+                .url("/z")
+                .bytes(100)
+                .user_agent("y")
+                .build(),
+        ];
+        let sessions = sessionize(&logs, SessionKey::AddrUserAgent, Duration::minutes(30));
+        assert_eq!(sessions.len(), 3);
+
+        let first = &sessions[0];
+        assert_eq!(first.pages, 2);
+        assert_eq!(first.bytes, 200);
+        assert_eq!(first.entry_url, "/a");
+        assert_eq!(first.exit_url, "/b");
+        assert_eq!((first.end - first.start).num_minutes(), 10);
+
+        assert_eq!(sessions[1].entry_url, "/z");
+        assert_eq!(sessions[2].entry_url, "/c");
+    }
+
+    #[test]
+    fn sessionize_should_respect_key() {
+        let logs = vec![
+            NginxLog::builder()
+                .at("08:00:00")
+                .url("/a")
+                .bytes(100)
+                .build(),
+            NginxLog::builder()
+                .at("08:01:00")
+                .url("/b")
+                .bytes(100)
+                .user_agent("y")
+                .build(),
+        ];
+        let by_ua = sessionize(&logs, SessionKey::AddrUserAgent, Duration::minutes(30));
+        assert_eq!(by_ua.len(), 2);
+
+        let by_addr = sessionize(&logs, SessionKey::Addr, Duration::minutes(30));
+        assert_eq!(by_addr.len(), 1);
+        assert_eq!(by_addr[0].user_agent, None);
+        assert_eq!(by_addr[0].exit_url, "/b");
+    }
+}
@@ -0,0 +1,287 @@
+use std::{collections::HashSet, path::Path};
+
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::{upstream::UpstreamList, NginxLog};
+
+// rows inserted per transaction
+const BATCH_SIZE: usize = 10_000;
+
+// stored in `PRAGMA user_version`, bumped whenever columns are added
+const SCHEMA_VERSION: i64 = 5;
+
+// columns added after the first schema with the version that added them,
+// added to older databases in place
+const ADDED_COLUMNS: &[(i64, &str, &str)] = &[
+    (2, "source", "TEXT"),
+    (3, "request_length", "INTEGER"),
+    (3, "host", "TEXT"),
+    (3, "request_id", "TEXT"),
+    (3, "upstream_addr", "TEXT"),
+    (3, "upstream_status", "TEXT"),
+    (3, "upstream_response_time", "TEXT"),
+    (3, "upstream_connect_time", "TEXT"),
+    (3, "upstream_header_time", "TEXT"),
+    (4, "forwarded_for", "TEXT"),
+    (4, "client_ip", "TEXT"),
+    (5, "upstream_response_length", "TEXT"),
+];
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS logs (
+    id INTEGER PRIMARY KEY,
+    addr TEXT NOT NULL,
+    datetime INTEGER NOT NULL,
+    method TEXT NOT NULL,
+    url TEXT NOT NULL,
+    protocol TEXT NOT NULL,
+    status INTEGER NOT NULL,
+    body_bytes INTEGER NOT NULL,
+    referer TEXT,
+    user_agent TEXT,
+    route TEXT,
+    browser TEXT,
+    browser_version TEXT,
+    os TEXT,
+    device TEXT,
+    client TEXT,
+    country TEXT,
+    city TEXT,
+    asn INTEGER,
+    as_org TEXT,
+    request_time REAL,
+    source TEXT,
+    request_length INTEGER,
+    host TEXT,
+    request_id TEXT,
+    upstream_addr TEXT,
+    upstream_status TEXT,
+    upstream_response_time TEXT,
+    upstream_response_length TEXT,
+    upstream_connect_time TEXT,
+    upstream_header_time TEXT,
+    forwarded_for TEXT,
+    client_ip TEXT
+);
+CREATE INDEX IF NOT EXISTS logs_datetime ON logs (datetime);
+CREATE INDEX IF NOT EXISTS logs_status ON logs (status);
+CREATE INDEX IF NOT EXISTS logs_addr ON logs (addr);
+"#;
+
+const INSERT: &str = r#"
+INSERT INTO logs (
+    addr, datetime, method, url, protocol, status, body_bytes, referer, user_agent, route,
+    browser, browser_version, os, device, client, country, city, asn, as_org, request_time,
+    source, request_length, host, request_id, upstream_addr, upstream_status,
+    upstream_response_time, upstream_response_length, upstream_connect_time,
+    upstream_header_time, forwarded_for, client_ip
+) VALUES (
+    ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20,
+    ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28, ?29, ?30, ?31, ?32
+)
+"#;
+
+/// The file extensions that select the SQLite sink instead of Parquet.
+pub fn is_sqlite_path(path: &str) -> bool {
+    matches!(
+        Path::new(path).extension().and_then(|v| v.to_str()),
+        Some("db" | "sqlite" | "sqlite3")
+    )
+}
+
+/// Append records to the `logs` table, creating the database and schema on
+/// first use. `datetime` is stored as unix seconds like in the Parquet export,
+/// and the list columns as JSON arrays with one entry per attempt.
+pub fn write_logs_to_sqlite(logs: &[NginxLog], filename: &str) -> anyhow::Result<String> {
+    let mut conn = Connection::open(filename)?;
+    migrate(&conn)?;
+
+    for chunk in logs.chunks(BATCH_SIZE) {
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(INSERT)?;
+            for log in chunk {
+                let ua = log.ua.as_ref();
+                let geo = log.geo.as_ref();
+                let upstream = log.upstream.as_ref();
+                stmt.execute(params![
+                    log.addr.to_string(),
+                    log.datetime.timestamp(),
+                    log.method.to_string(),
+                    log.url,
+                    log.protocol.to_string(),
+                    log.status,
+                    log.body_bytes as i64,
+                    log.referer,
+                    log.user_agent,
+                    log.route,
+                    ua.and_then(|v| v.browser.as_deref()),
+                    ua.and_then(|v| v.browser_version.as_deref()),
+                    ua.and_then(|v| v.os.as_deref()),
+                    ua.map(|v| v.device.to_string()),
+                    ua.map(|v| v.client.to_string()),
+                    geo.and_then(|v| v.country.as_deref()),
+                    geo.and_then(|v| v.city.as_deref()),
+                    geo.and_then(|v| v.asn),
+                    geo.and_then(|v| v.as_org.as_deref()),
+                    log.request_time,
+                    log.source,
+                    log.request_length.map(|v| v as i64),
+                    log.host,
+                    log.request_id,
+                    json_list(upstream.map(|v| &v.addr)),
+                    json_list(upstream.map(|v| &v.status)),
+                    json_list(upstream.map(|v| &v.response_time)),
+                    json_list(upstream.map(|v| &v.response_length)),
+                    json_list(upstream.map(|v| &v.connect_time)),
+                    json_list(upstream.map(|v| &v.header_time)),
+                    (!log.forwarded_for.is_empty())
+                        .then(|| serde_json::to_string(&log.forwarded_for))
+                        .transpose()?,
+                    log.client_ip.map(|v| v.to_string()),
+                ])?;
+            }
+        }
+        tx.commit()?;
+    }
+
+    Ok(filename.to_string())
+}
+
+// Create the schema, or bring a database written by an older version up to
+// date. Databases from a newer version are refused rather than written with
+// missing columns.
+fn migrate(conn: &Connection) -> anyhow::Result<()> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+    anyhow::ensure!(
+        version <= SCHEMA_VERSION,
+        "Database schema version {} is newer than the supported {}",
+        version,
+        SCHEMA_VERSION
+    );
+    conn.execute_batch(SCHEMA)?;
+    if version < SCHEMA_VERSION {
+        let columns = conn
+            .prepare("SELECT name FROM pragma_table_info('logs')")?
+            .query_map([], |r| r.get(0))?
+            .collect::<Result<HashSet<String>, _>>()?;
+        for (since, name, kind) in ADDED_COLUMNS {
+            if version < *since && !columns.contains(*name) {
+                conn.execute_batch(&format!("ALTER TABLE logs ADD COLUMN {} {}", name, kind))?;
+            }
+        }
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    }
+    Ok(())
+}
+
+// An upstream list as a JSON array of attempts, NULL when nginx logged none.
+fn json_list<T: Serialize>(list: Option<&UpstreamList<T>>) -> Option<String> {
+    let list = list.filter(|v| !v.is_empty())?;
+    serde_json::to_string(&list.attempts().collect::<Vec<_>>()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_nginx_log;
+
+    fn logs() -> Vec<NginxLog> {
+        [(200, "0.010"), (404, "")]
+            .iter()
+            .map(|(status, request_time)| {
+                let s = format!(
+                    r#"1.1.1.1 - - [17/May/2015:08:05:32 +0000] "GET /a HTTP/1.1" {} 10 "-" "x" {}"#,
+                    status, request_time
+                );
+                parse_nginx_log(&s).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn is_sqlite_path_should_work() {
+        assert!(is_sqlite_path("logs.db"));
+        assert!(is_sqlite_path("/tmp/logs.sqlite3"));
+        assert!(!is_sqlite_path("nginx_logs.parquet"));
+    }
+
+    #[test]
+    fn write_logs_to_sqlite_should_append() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("nginx-log-{}.sqlite", std::process::id()));
+        let filename = path.to_string_lossy().to_string();
+        let _ = std::fs::remove_file(&path);
+
+        write_logs_to_sqlite(&logs(), &filename)?;
+        write_logs_to_sqlite(&logs(), &filename)?;
+
+        let conn = Connection::open(&path)?;
+        let count: i64 =
+            conn.query_row("SELECT count(*) FROM logs WHERE status = 404", [], |r| {
+                r.get(0)
+            })?;
+        assert_eq!(count, 2);
+        let (datetime, request_time): (i64, Option<f64>) = conn.query_row(
+            "SELECT datetime, request_time FROM logs WHERE status = 200 LIMIT 1",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )?;
+        assert_eq!(datetime, 1431849932);
+        assert_eq!(request_time, Some(0.010));
+        let indexes: i64 = conn.query_row(
+            "SELECT count(*) FROM sqlite_master WHERE type = 'index' AND tbl_name = 'logs'",
+            [],
+            |r| r.get(0),
+        )?;
+        assert_eq!(indexes, 3);
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+        assert_eq!(version, SCHEMA_VERSION);
+
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1)?;
+        assert!(write_logs_to_sqlite(&logs(), &filename).is_err());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn write_logs_to_sqlite_should_migrate_and_store_lists() -> anyhow::Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("nginx-log-migrate-{}.db", std::process::id()));
+        let filename = path.to_string_lossy().to_string();
+        let _ = std::fs::remove_file(&path);
+        // a database written by the first schema
+        Connection::open(&path)?.execute_batch(
+            "CREATE TABLE logs (id INTEGER PRIMARY KEY, addr TEXT NOT NULL, \
+             datetime INTEGER NOT NULL, method TEXT NOT NULL, url TEXT NOT NULL, \
+             protocol TEXT NOT NULL, status INTEGER NOT NULL, body_bytes INTEGER NOT NULL, \
+             referer TEXT, user_agent TEXT, route TEXT, browser TEXT, browser_version TEXT, \
+             os TEXT, device TEXT, client TEXT, country TEXT, city TEXT, asn INTEGER, \
+             as_org TEXT, request_time REAL); PRAGMA user_version = 1;",
+        )?;
+
+        let format = crate::log_format::LogFormat::new(
+            r#"$remote_addr [$time_local] "$request" $status $upstream_addr $upstream_status $host"#,
+        )?;
+        let log = format
+            .parse(r#"1.1.1.1 [17/May/2015:08:05:32 +0000] "GET / HTTP/1.1" 200 10.0.0.1:80, 10.0.0.2:80 502, 200 example.com"#)
+            .unwrap();
+        write_logs_to_sqlite(&[log], &filename)?;
+
+        let conn = Connection::open(&path)?;
+        let (addrs, statuses, host, last): (String, String, String, i64) = conn.query_row(
+            "SELECT upstream_addr, upstream_status, host, \
+             (SELECT value FROM json_each(upstream_status) ORDER BY key DESC LIMIT 1) FROM logs",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+        )?;
+        assert_eq!(addrs, r#"["10.0.0.1:80","10.0.0.2:80"]"#);
+        assert_eq!(statuses, "[502,200]");
+        assert_eq!(host, "example.com");
+        assert_eq!(last, 200);
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+        assert_eq!(version, SCHEMA_VERSION);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,275 @@
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+
+use chrono::{DateTime, Duration, Utc};
+use winnow::Parser;
+
+use crate::{parse_datetime, parse_ignored, parse_ip, parse_nginx_log, NginxLog};
+
+// evenly spaced probes used to check the file is sorted before trusting the search
+const SORT_PROBES: u64 = 16;
+// lines read after a probe offset while looking for one with a timestamp
+const MAX_PROBE_LINES: usize = 64;
+
+/// Seconds a line may be dated before an earlier line and still count as
+/// sorted, since nginx workers write their lines slightly out of order. The
+/// same default as `merge --tolerance`.
+pub const SORT_TOLERANCE_SECS: i64 = 5;
+
+/// Half-open `[since, until)` range; a missing bound is unbounded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimeRange {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scan {
+    BinarySearch,
+    /// The input was not sorted by time, so every line was read
+    Linear,
+}
+
+impl TimeRange {
+    pub fn is_unbounded(&self) -> bool {
+        self.since.is_none() && self.until.is_none()
+    }
+
+    pub fn contains(&self, t: &DateTime<Utc>) -> bool {
+        self.since.is_none_or(|since| *t >= since) && self.until.is_none_or(|until| *t < until)
+    }
+}
+
+/// Parse `--since`/`--until` values, either RFC 3339 or the nginx `[...]` format.
+pub fn parse_time(s: &str) -> anyhow::Result<DateTime<Utc>> {
+    let dt = DateTime::parse_from_rfc3339(s)
+        .or_else(|_| DateTime::parse_from_str(s, "%d/%b/%Y:%H:%M:%S %z"))?;
+    Ok(dt.with_timezone(&Utc))
+}
+
+/// Extract the records in `range` from a time-sorted input. The start is found
+/// by binary search over byte offsets, so only the lines in range are parsed.
+/// Lines may be up to `tolerance` older than the lines before them; an input
+/// more out of order than that is detected and read linearly instead.
+pub fn extract<R: Read + Seek>(
+    mut reader: R,
+    range: &TimeRange,
+    tolerance: Duration,
+) -> anyhow::Result<(Vec<NginxLog>, Scan)> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    if is_sorted_sample(&mut reader, len, tolerance)? {
+        // a line in range may follow lines up to `tolerance` newer than it
+        let start = match range.since {
+            Some(since) => seek_start(&mut reader, len, since - tolerance)?,
+            None => 0,
+        };
+        reader.seek(SeekFrom::Start(start))?;
+        if let Some(logs) = scan_sorted(BufReader::new(&mut reader), range, tolerance)? {
+            return Ok((logs, Scan::BinarySearch));
+        }
+    }
+
+    reader.seek(SeekFrom::Start(0))?;
+    let mut logs = Vec::new();
+    for line in BufReader::new(reader).lines() {
+        if let Ok(log) = parse_nginx_log(&line?) {
+            if range.contains(&log.datetime) {
+                logs.push(log);
+            }
+        }
+    }
+    Ok((logs, Scan::Linear))
+}
+
+fn parse_line_datetime(line: &str) -> Option<DateTime<Utc>> {
+    (parse_ip, parse_ignored, parse_ignored, parse_datetime)
+        .map(|(_, _, _, dt)| dt)
+        .parse_next(&mut &*line)
+        .ok()
+}
+
+// Timestamp of the first dated line starting at or after `offset`.
+fn time_at<R: Read + Seek>(reader: &mut R, offset: u64) -> io::Result<Option<DateTime<Utc>>> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut reader = BufReader::new(reader);
+    let mut buf = Vec::new();
+    if offset > 0 {
+        // skip the rest of the line the offset landed in
+        reader.read_until(b'\n', &mut buf)?;
+    }
+    for _ in 0..MAX_PROBE_LINES {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            return Ok(None);
+        }
+        if let Some(dt) = parse_line_datetime(&String::from_utf8_lossy(&buf)) {
+            return Ok(Some(dt));
+        }
+    }
+    Ok(None)
+}
+
+fn is_sorted_sample<R: Read + Seek>(
+    reader: &mut R,
+    len: u64,
+    tolerance: Duration,
+) -> io::Result<bool> {
+    let mut latest = None;
+    for i in 0..SORT_PROBES {
+        if let Some(dt) = time_at(reader, len * i / SORT_PROBES)? {
+            if latest.is_some_and(|latest| dt < latest - tolerance) {
+                return Ok(false);
+            }
+            latest = latest.max(Some(dt));
+        }
+    }
+    Ok(true)
+}
+
+// Offset of a line boundary at or before the first line dated `since` or later.
+fn seek_start<R: Read + Seek>(reader: &mut R, len: u64, since: DateTime<Utc>) -> io::Result<u64> {
+    let (mut lo, mut hi) = (0, len);
+    while hi - lo > 1 {
+        let mid = lo + (hi - lo) / 2;
+        match time_at(reader, mid)? {
+            Some(dt) if dt < since => lo = mid,
+            _ => hi = mid,
+        }
+    }
+    if lo == 0 {
+        return Ok(0);
+    }
+    // move to the start of the line after the one containing `lo`
+    reader.seek(SeekFrom::Start(lo))?;
+    let mut buf = Vec::new();
+    let n = BufReader::new(reader).read_until(b'\n', &mut buf)?;
+    Ok(lo + n as u64)
+}
+
+// `None` when the lines turn out to be more out of order than `tolerance`.
+fn scan_sorted(
+    reader: impl BufRead,
+    range: &TimeRange,
+    tolerance: Duration,
+) -> io::Result<Option<Vec<NginxLog>>> {
+    let mut logs = Vec::new();
+    let mut latest = None;
+    for line in reader.lines() {
+        let Ok(log) = parse_nginx_log(&line?) else {
+            continue;
+        };
+        if latest.is_some_and(|latest| log.datetime < latest - tolerance) {
+            return Ok(None);
+        }
+        latest = latest.max(Some(log.datetime));
+        // later lines are at most `tolerance` older than this one
+        if range
+            .until
+            .is_some_and(|until| log.datetime - tolerance >= until)
+        {
+            break;
+        }
+        if range.contains(&log.datetime) {
+            logs.push(log);
+        }
+    }
+    Ok(Some(logs))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn line(secs: u32) -> String {
+        format!(
+            "1.1.1.1 - - [17/May/2015:{:02}:{:02}:{:02} +0000] \"GET /{} HTTP/1.1\" 200 0 \"-\" \"x\"\n",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+            secs
+        )
+    }
+
+    fn range(since: u32, until: u32) -> TimeRange {
+        let t = |secs: u32| {
+            parse_time(&format!(
+                "2015-05-17T{:02}:{:02}:{:02}Z",
+                secs / 3600,
+                secs / 60 % 60,
+                secs % 60
+            ))
+            .ok()
+        };
+        TimeRange {
+            since: t(since),
+            until: t(until),
+        }
+    }
+
+    fn tolerance() -> Duration {
+        Duration::seconds(SORT_TOLERANCE_SECS)
+    }
+
+    fn urls(logs: &[NginxLog]) -> Vec<u32> {
+        logs.iter().map(|v| v.url[1..].parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn extract_should_binary_search_sorted_input() -> anyhow::Result<()> {
+        let mut data = (0..5000).map(|i| line(i * 2)).collect::<String>();
+        data.insert_str(0, "garbage line\n");
+        let (logs, scan) = extract(Cursor::new(data.clone()), &range(1001, 1011), tolerance())?;
+        assert_eq!(scan, Scan::BinarySearch);
+        assert_eq!(urls(&logs), vec![1002, 1004, 1006, 1008, 1010]);
+
+        let (logs, _) = extract(Cursor::new(data.clone()), &range(0, 3), tolerance())?;
+        assert_eq!(urls(&logs), vec![0, 2]);
+        let (logs, _) = extract(Cursor::new(data), &range(9996, 20000), tolerance())?;
+        assert_eq!(urls(&logs), vec![9996, 9998]);
+        Ok(())
+    }
+
+    #[test]
+    fn extract_should_fall_back_to_linear_scan() -> anyhow::Result<()> {
+        let data = (0..2000)
+            .map(|i| line(if i % 2 == 0 { i } else { 4000 - i }))
+            .collect::<String>();
+        let (logs, scan) = extract(Cursor::new(data), &range(1000, 1003), tolerance())?;
+        assert_eq!(scan, Scan::Linear);
+        assert_eq!(urls(&logs), vec![1000, 1002]);
+
+        let mut data = line(0).into_bytes();
+        data.extend(b"\xff\xfe\n");
+        data.extend(line(1).into_bytes());
+        let data = [line(4000).into_bytes(), data].concat();
+        assert!(extract(Cursor::new(data), &range(0, 10), tolerance()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn extract_should_tolerate_worker_jitter() -> anyhow::Result<()> {
+        // each pair of lines is written two seconds out of order
+        let data = (0..5000)
+            .map(|i| line(if i % 2 == 0 { i * 2 + 2 } else { i * 2 - 2 }))
+            .collect::<String>();
+        let (logs, scan) = extract(Cursor::new(data.clone()), &range(1001, 1011), tolerance())?;
+        assert_eq!(scan, Scan::BinarySearch);
+        let mut got = urls(&logs);
+        got.sort();
+        assert_eq!(got, vec![1002, 1004, 1006, 1008, 1010]);
+
+        let (_, scan) = extract(Cursor::new(data), &range(1001, 1011), Duration::zero())?;
+        assert_eq!(scan, Scan::Linear);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_time_should_accept_both_formats() {
+        assert_eq!(
+            parse_time("17/May/2015:10:05:32 +0200").unwrap(),
+            parse_time("2015-05-17T08:05:32Z").unwrap()
+        );
+        assert!(parse_time("yesterday").is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use std::fmt;
+
+use winnow::{
+    ascii::{digit1, float},
+    combinator::{alt, separated},
+    error::ContextError,
+    token::take_till,
+    PResult, Parser,
+};
+
+/// An `$upstream_*` value with one entry per contacted server. nginx joins
+/// retries within an upstream group with `, ` and the groups reached through
+/// an internal redirect with ` : `; `-` marks a server that gave no value.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct UpstreamList<T>(pub Vec<Vec<Option<T>>>);
+
+impl<T> UpstreamList<T> {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Every attempt in order, across groups.
+    pub fn attempts(&self) -> impl Iterator<Item = Option<&T>> {
+        self.0.iter().flatten().map(Option::as_ref)
+    }
+
+    /// The value of the server that produced the response.
+    pub fn last(&self) -> Option<&T> {
+        self.0.last()?.last()?.as_ref()
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for UpstreamList<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "-");
+        }
+        for (i, group) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " : ")?;
+            }
+            for (j, value) in group.iter().enumerate() {
+                if j > 0 {
+                    write!(f, ", ")?;
+                }
+                match value {
+                    Some(v) => write!(f, "{}", v)?,
+                    None => write!(f, "-")?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The `$upstream_*` variables of one request.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Upstream {
+    pub addr: UpstreamList<String>,
+    pub status: UpstreamList<u16>,
+    pub response_time: UpstreamList<f64>,
+    pub response_length: UpstreamList<u64>,
+    pub connect_time: UpstreamList<f64>,
+    pub header_time: UpstreamList<f64>,
+}
+
+impl Upstream {
+    /// Number of servers tried, taken from the longest list.
+    pub fn tries(&self) -> usize {
+        [
+            self.addr.attempts().count(),
+            self.status.attempts().count(),
+            self.response_time.attempts().count(),
+            self.response_length.attempts().count(),
+            self.connect_time.attempts().count(),
+            self.header_time.attempts().count(),
+        ]
+        .into_iter()
+        .max()
+        .unwrap_or(0)
+    }
+}
+
+// A lone `-` means no upstream was contacted.
+fn parse_list<'s, T, P>(mut item: P) -> impl FnMut(&mut &'s str) -> PResult<UpstreamList<T>>
+where
+    P: Parser<&'s str, T, ContextError>,
+{
+    move |s: &mut &'s str| {
+        let groups: Vec<Vec<Option<T>>> = separated(
+            1..,
+            separated(1.., alt(("-".map(|_| None), item.by_ref().map(Some))), ", "),
+            " : ",
+        )
+        .parse_next(s)?;
+        if groups.len() == 1 && groups[0].len() == 1 && groups[0][0].is_none() {
+            return Ok(UpstreamList(Vec::new()));
+        }
+        Ok(UpstreamList(groups))
+    }
+}
+
+/// `$upstream_addr`: `host:port`, `unix:/path` or an upstream group name.
+pub fn parse_addr_list(s: &mut &str) -> PResult<UpstreamList<String>> {
+    parse_list(take_till(1.., [',', ' ', '"']).map(str::to_string)).parse_next(s)
+}
+
+/// `$upstream_status`
+pub fn parse_status_list(s: &mut &str) -> PResult<UpstreamList<u16>> {
+    parse_list(digit1.parse_to()).parse_next(s)
+}
+
+/// `$upstream_response_length`
+pub fn parse_length_list(s: &mut &str) -> PResult<UpstreamList<u64>> {
+    parse_list(digit1.parse_to()).parse_next(s)
+}
+
+/// `$upstream_response_time`, `$upstream_connect_time` and `$upstream_header_time`
+pub fn parse_time_list(s: &mut &str) -> PResult<UpstreamList<f64>> {
+    parse_list(float).parse_next(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_time_list_should_handle_retries_and_redirects() -> anyhow::Result<()> {
+        let mut s = "0.004, 0.120 : 0.050 200";
+        let list = parse_time_list(&mut s).unwrap();
+        assert_eq!(
+            list,
+            UpstreamList(vec![vec![Some(0.004), Some(0.12)], vec![Some(0.05)]])
+        );
+        assert_eq!(s, " 200");
+        assert_eq!(list.last(), Some(&0.05));
+        assert_eq!(list.attempts().count(), 3);
+        assert_eq!(list.to_string(), "0.004, 0.12 : 0.05");
+
+        let list = parse_time_list(&mut "-").unwrap();
+        assert!(list.is_empty());
+        assert_eq!(list.to_string(), "-");
+        assert!(parse_time_list(&mut "abc").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_addr_and_status_lists_should_work() -> anyhow::Result<()> {
+        let mut s = r#"10.0.0.1:8080, 10.0.0.2:8080 : unix:/run/app.sock" "#;
+        let addrs = parse_addr_list(&mut s).unwrap();
+        assert_eq!(s, r#"" "#);
+        assert_eq!(addrs.last().map(String::as_str), Some("unix:/run/app.sock"));
+        assert_eq!(
+            addrs.to_string(),
+            "10.0.0.1:8080, 10.0.0.2:8080 : unix:/run/app.sock"
+        );
+
+        let statuses = parse_status_list(&mut "502, - : 200").unwrap();
+        assert_eq!(
+            statuses.attempts().collect::<Vec<_>>(),
+            vec![Some(&502), None, Some(&200)]
+        );
+        let upstream = Upstream {
+            addr: addrs,
+            status: statuses,
+            ..Default::default()
+        };
+        assert_eq!(upstream.tries(), 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+
+use strum_macros::Display;
+use winnow::{
+    ascii::space0,
+    combinator::{alt, delimited, opt, preceded, repeat},
+    token::take_till,
+    PResult, Parser,
+};
+
+use crate::NginxLog;
+
+/// A single `product/version` token or a parenthesised comment of a user agent.
+#[derive(Debug, PartialEq, Eq)]
+pub enum UaToken<'a> {
+    Product {
+        name: &'a str,
+        version: Option<&'a str>,
+    },
+    Comment(&'a str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum ClientKind {
+    Browser,
+    Crawler,
+    Bot,
+    PackageManager,
+    #[default]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum DeviceClass {
+    Desktop,
+    Mobile,
+    Tablet,
+    #[default]
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct UserAgentInfo {
+    pub browser: Option<String>,
+    pub browser_version: Option<String>,
+    pub os: Option<String>,
+    pub device: DeviceClass,
+    pub client: ClientKind,
+}
+
+// (product name, family, kind), first match wins. Crawlers and tools go first
+// because they usually also claim to be `Mozilla/5.0`.
+const CLIENT_RULES: &[(&str, &str, ClientKind)] = &[
+    ("Googlebot", "Googlebot", ClientKind::Crawler),
+    ("bingbot", "Bingbot", ClientKind::Crawler),
+    ("YandexBot", "YandexBot", ClientKind::Crawler),
+    ("Baiduspider", "Baiduspider", ClientKind::Crawler),
+    ("DuckDuckBot", "DuckDuckBot", ClientKind::Crawler),
+    ("Applebot", "Applebot", ClientKind::Crawler),
+    ("AhrefsBot", "AhrefsBot", ClientKind::Crawler),
+    ("SemrushBot", "SemrushBot", ClientKind::Crawler),
+    ("facebookexternalhit", "Facebook", ClientKind::Crawler),
+    ("APT-HTTP", "APT", ClientKind::PackageManager),
+    ("urlgrabber", "Yum", ClientKind::PackageManager),
+    ("libdnf", "DNF", ClientKind::PackageManager),
+    ("pip", "pip", ClientKind::PackageManager),
+    ("npm", "npm", ClientKind::PackageManager),
+    ("Homebrew", "Homebrew", ClientKind::PackageManager),
+    ("Cargo", "Cargo", ClientKind::PackageManager),
+    ("curl", "curl", ClientKind::Bot),
+    ("Wget", "Wget", ClientKind::Bot),
+    ("python-requests", "Python Requests", ClientKind::Bot),
+    ("Python-urllib", "Python urllib", ClientKind::Bot),
+    ("Go-http-client", "Go http client", ClientKind::Bot),
+    ("libwww-perl", "libwww-perl", ClientKind::Bot),
+    ("Java", "Java", ClientKind::Bot),
+    ("Edg", "Edge", ClientKind::Browser),
+    ("OPR", "Opera", ClientKind::Browser),
+    ("SamsungBrowser", "Samsung Internet", ClientKind::Browser),
+    ("FxiOS", "Firefox", ClientKind::Browser),
+    ("Firefox", "Firefox", ClientKind::Browser),
+    ("CriOS", "Chrome", ClientKind::Browser),
+    ("Chrome", "Chrome", ClientKind::Browser),
+    ("Safari", "Safari", ClientKind::Browser),
+    ("MSIE", "Internet Explorer", ClientKind::Browser),
+    ("Trident", "Internet Explorer", ClientKind::Browser),
+];
+
+// (substring, os), first match wins.
+const OS_RULES: &[(&str, &str)] = &[
+    ("Windows Phone", "Windows Phone"),
+    ("Windows", "Windows"),
+    ("Android", "Android"),
+    ("iPhone", "iOS"),
+    ("iPad", "iOS"),
+    ("iPod", "iOS"),
+    ("Mac OS X", "macOS"),
+    ("Macintosh", "macOS"),
+    ("CrOS", "Chrome OS"),
+    ("Ubuntu", "Ubuntu"),
+    ("Debian", "Debian"),
+    ("Fedora", "Fedora"),
+    ("Linux", "Linux"),
+];
+
+impl UserAgentInfo {
+    pub fn parse(ua: &str) -> Self {
+        let tokens = tokenize(ua);
+
+        // products plus `name/version` or `name version` parts of comments,
+        // e.g. `(compatible; Googlebot/2.1; ...)` or `(compatible; MSIE 6.0; ...)`
+        let mut products = Vec::new();
+        let mut parts = Vec::new();
+        for token in &tokens {
+            match token {
+                UaToken::Product { name, version } => {
+                    products.push((*name, *version));
+                    parts.push(*name);
+                }
+                UaToken::Comment(c) => {
+                    for part in c.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+                        let (name, version) = match part.split_once(['/', ' ']) {
+                            Some((n, v)) => (n, Some(v)),
+                            None => (part, None),
+                        };
+                        products.push((name, version));
+                        parts.push(part);
+                    }
+                }
+            }
+        }
+
+        let mut info = UserAgentInfo::default();
+        let rule = CLIENT_RULES
+            .iter()
+            .find_map(|(rule, family, kind)| {
+                products
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(rule))
+                    .map(|(_, version)| (*family, *version, *kind))
+            })
+            .or_else(|| {
+                products
+                    .iter()
+                    .find(|(name, _)| looks_like_crawler(name))
+                    .map(|(name, version)| (*name, *version, ClientKind::Crawler))
+            });
+        if let Some((family, version, kind)) = rule {
+            let version = match family {
+                // Safari keeps its marketing version in `Version/x`
+                "Safari" => products
+                    .iter()
+                    .find(|(name, _)| *name == "Version")
+                    .and_then(|(_, v)| *v),
+                _ => version,
+            };
+            info.browser = Some(family.to_string());
+            info.browser_version = version.map(|v| v.to_string());
+            info.client = kind;
+        }
+
+        info.os = OS_RULES
+            .iter()
+            .find(|(rule, _)| parts.iter().any(|p| p.contains(rule)))
+            .map(|(_, os)| os.to_string());
+
+        if info.client == ClientKind::Browser {
+            let has = |s: &str| parts.iter().any(|p| p.contains(s));
+            info.device = if has("iPad") || has("Tablet") || (has("Android") && !has("Mobile")) {
+                DeviceClass::Tablet
+            } else if has("Mobile") || has("iPhone") || has("Android") {
+                DeviceClass::Mobile
+            } else if info.os.is_some() {
+                DeviceClass::Desktop
+            } else {
+                DeviceClass::Unknown
+            };
+        }
+        info
+    }
+}
+
+/// Parse `user_agent` of every log record, caching repeated agents.
+pub fn apply(logs: &mut [NginxLog]) {
+    let mut cache: HashMap<String, UserAgentInfo> = HashMap::new();
+    for log in logs {
+        let info = cache
+            .entry(log.user_agent.clone())
+            .or_insert_with_key(|ua| UserAgentInfo::parse(ua));
+        log.ua = Some(info.clone());
+    }
+}
+
+fn looks_like_crawler(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    ["bot", "crawler", "spider"]
+        .iter()
+        .any(|k| name.contains(k))
+}
+
+/// Split a user agent into tokens, ignoring anything that can't be tokenized.
+pub fn tokenize(s: &str) -> Vec<UaToken<'_>> {
+    let input = &mut (&*s);
+    repeat(0.., preceded(space0, parse_ua_token))
+        .parse_next(input)
+        .unwrap_or_default()
+}
+
+fn parse_ua_token<'s>(s: &mut &'s str) -> PResult<UaToken<'s>> {
+    alt((parse_ua_comment.map(UaToken::Comment), parse_ua_product)).parse_next(s)
+}
+
+fn parse_ua_product<'s>(s: &mut &'s str) -> PResult<UaToken<'s>> {
+    let name = take_till(1.., [' ', '/', '(', ')']).parse_next(s)?;
+    let version = opt(preceded('/', take_till(0.., [' ', '(', ')']))).parse_next(s)?;
+    Ok(UaToken::Product { name, version })
+}
+
+// comments may be nested: `(KHTML, like Gecko (x))`
+fn parse_ua_comment<'s>(s: &mut &'s str) -> PResult<&'s str> {
+    delimited(
+        '(',
+        repeat::<_, _, (), _, _>(
+            0..,
+            alt((take_till(1.., ['(', ')']).void(), parse_ua_comment.void())),
+        )
+        .take(),
+        ')',
+    )
+    .parse_next(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_should_work() {
+        let tokens = tokenize("Debian APT-HTTP/1.3 (0.8.16~exp12ubuntu10.21)");
+        assert_eq!(
+            tokens,
+            vec![
+                UaToken::Product {
+                    name: "Debian",
+                    version: None
+                },
+                UaToken::Product {
+                    name: "APT-HTTP",
+                    version: Some("1.3")
+                },
+                UaToken::Comment("0.8.16~exp12ubuntu10.21"),
+            ]
+        );
+
+        let tokens = tokenize("a/1 (x (nested); y) b");
+        assert_eq!(tokens[1], UaToken::Comment("x (nested); y"));
+        assert_eq!(tokens.len(), 3);
+    }
+
+    #[test]
+    fn parse_should_classify_browsers() {
+        let info = UserAgentInfo::parse("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36 Edg/120.0.2210.91");
+        assert_eq!(info.browser.as_deref(), Some("Edge"));
+        assert_eq!(info.browser_version.as_deref(), Some("120.0.2210.91"));
+        assert_eq!(info.os.as_deref(), Some("Windows"));
+        assert_eq!(info.device, DeviceClass::Desktop);
+        assert_eq!(info.client, ClientKind::Browser);
+
+        let info = UserAgentInfo::parse("Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.0 Mobile/15E148 Safari/604.1");
+        assert_eq!(info.browser.as_deref(), Some("Safari"));
+        assert_eq!(info.browser_version.as_deref(), Some("17.0"));
+        assert_eq!(info.os.as_deref(), Some("iOS"));
+        assert_eq!(info.device, DeviceClass::Mobile);
+    }
+
+    #[test]
+    fn parse_should_classify_non_browsers() {
+        let info = UserAgentInfo::parse("Debian APT-HTTP/1.3 (0.8.16~exp12ubuntu10.21)");
+        assert_eq!(info.browser.as_deref(), Some("APT"));
+        assert_eq!(info.browser_version.as_deref(), Some("1.3"));
+        assert_eq!(info.os.as_deref(), Some("Debian"));
+        assert_eq!(info.client, ClientKind::PackageManager);
+
+        let info = UserAgentInfo::parse(
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+        );
+        assert_eq!(info.browser.as_deref(), Some("Googlebot"));
+        assert_eq!(info.browser_version.as_deref(), Some("2.1"));
+        assert_eq!(info.client, ClientKind::Crawler);
+
+        let info = UserAgentInfo::parse("MyCustomSpider/0.1");
+        assert_eq!(info.client, ClientKind::Crawler);
+
+        let info = UserAgentInfo::parse("-");
+        assert_eq!(info.client, ClientKind::Unknown);
+        assert_eq!(info.device, DeviceClass::Unknown);
+    }
+}
@@ -0,0 +1,378 @@
+use std::{fs::File, net::IpAddr, sync::Arc};
+
+use anyhow::anyhow;
+use arrow::array::{ArrayRef, RecordBatch, StringArray};
+use chrono::{NaiveDate, NaiveTime};
+use parquet::arrow::ArrowWriter;
+use winnow::{
+    combinator::{preceded, rest, separated, terminated},
+    token::take_till,
+    PResult, Parser,
+};
+
+use crate::{
+    logs_to_record_batch, percent::percent_decode_once, realip, HttpMethod, HttpProto, NginxLog,
+};
+
+/// A W3C extended log row: the fields shared with nginx plus every other
+/// column by its `#Fields` name, URL-decoded. `-` columns are left out.
+#[derive(Debug)]
+pub struct W3cLog {
+    pub log: NginxLog,
+    pub extra: Vec<(String, String)>,
+}
+
+/// Reads a W3C extended log, as written by IIS and CloudFront, line by line.
+/// Each `#Fields` directive replaces the columns of the rows after it.
+#[derive(Debug, Default)]
+pub struct W3cReader {
+    fields: Vec<String>,
+    // `#Date`, for rows without a `date` column
+    date: Option<NaiveDate>,
+}
+
+// columns collected from one row
+#[derive(Default)]
+struct Row {
+    date: Option<NaiveDate>,
+    time: Option<NaiveTime>,
+    addr: Option<IpAddr>,
+    method: Option<HttpMethod>,
+    stem: Option<String>,
+    query: Option<String>,
+    protocol: Option<HttpProto>,
+    status: Option<u16>,
+    body_bytes: Option<u64>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_time: Option<f64>,
+    request_length: Option<u64>,
+    host: Option<String>,
+    request_id: Option<String>,
+    forwarded_for: Vec<Option<IpAddr>>,
+    extra: Vec<(String, String)>,
+}
+
+impl W3cReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The columns of the current `#Fields` directive.
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    /// Parse one line. Directives update the reader and return `None`.
+    pub fn parse_line(&mut self, line: &str) -> anyhow::Result<Option<W3cLog>> {
+        if line.starts_with('#') {
+            let (name, value) = parse_directive
+                .parse(line)
+                .map_err(|e| anyhow!("Invalid directive\n{}", e))?;
+            match name.to_ascii_lowercase().as_str() {
+                "fields" => self.fields = value.split_whitespace().map(str::to_string).collect(),
+                // `#Date: 2024-03-01 00:00:00`
+                "date" => self.date = value.get(..10).and_then(|v| v.parse().ok()),
+                _ => {}
+            }
+            return Ok(None);
+        }
+        if line.trim().is_empty() {
+            return Ok(None);
+        }
+        anyhow::ensure!(!self.fields.is_empty(), "Row before any #Fields directive");
+
+        // CloudFront separates columns with tabs, IIS with spaces
+        let separator = if line.contains('\t') { '\t' } else { ' ' };
+        let values: Vec<&str> = parse_row(separator)
+            .parse(line)
+            .map_err(|e| anyhow!("Invalid row\n{}", e))?;
+        anyhow::ensure!(
+            values.len() == self.fields.len(),
+            "Row has {} columns but #Fields names {}",
+            values.len(),
+            self.fields.len()
+        );
+
+        let mut row = Row {
+            date: self.date,
+            ..Default::default()
+        };
+        for (field, value) in self.fields.iter().zip(values) {
+            if value != "-" && !value.is_empty() {
+                parse_column(field, value, &mut row)
+                    .map_err(|e| anyhow!("Invalid {} {:?}: {}", field, value, e))?;
+            }
+        }
+        let missing = |name: &str| anyhow!("Row has no {}", name);
+        let datetime = row
+            .date
+            .ok_or_else(|| missing("date"))?
+            .and_time(row.time.ok_or_else(|| missing("time"))?)
+            .and_utc();
+        let stem = row.stem.ok_or_else(|| missing("cs-uri-stem"))?;
+        let log = NginxLog {
+            addr: row.addr.ok_or_else(|| missing("c-ip"))?,
+            datetime,
+            method: row.method.ok_or_else(|| missing("cs-method"))?,
+            url: match row.query {
+                Some(query) => format!("{}?{}", stem, query),
+                None => stem,
+            },
+            // IIS does not log the version by default
+            protocol: row.protocol.unwrap_or(HttpProto::HTTP1_1),
+            status: row.status.ok_or_else(|| missing("sc-status"))?,
+            body_bytes: row.body_bytes.unwrap_or(0),
+            referer: row.referer.unwrap_or_else(|| "-".to_string()),
+            user_agent: row.user_agent.unwrap_or_else(|| "-".to_string()),
+            route: None,
+            ua: None,
+            geo: None,
+            request_time: row.request_time,
+            source: None,
+            request_length: row.request_length,
+            host: row.host,
+            request_id: row.request_id,
+            upstream: None,
+            forwarded_for: row.forwarded_for,
+            client_ip: None,
+        };
+        Ok(Some(W3cLog {
+            log,
+            extra: row.extra,
+        }))
+    }
+}
+
+/// Parse a whole W3C extended log, skipping rows that do not match their
+/// `#Fields`.
+pub fn parse_w3c_logs(input: &str) -> Vec<W3cLog> {
+    let mut reader = W3cReader::new();
+    input
+        .lines()
+        .filter_map(|v| reader.parse_line(v).ok().flatten())
+        .collect()
+}
+
+/// Write the records with the columns of `write_logs_to_parquet` followed by
+/// one string column per extra field, in the order they first appear. Rows
+/// logged under a `#Fields` without that field get a null.
+pub fn write_w3c_logs_to_parquet(logs: Vec<W3cLog>, filename: &str) -> anyhow::Result<String> {
+    let (logs, extras): (Vec<_>, Vec<_>) = logs.into_iter().map(|v| (v.log, v.extra)).unzip();
+
+    let batch = logs_to_record_batch(&logs)?;
+    let mut columns = batch
+        .schema()
+        .fields()
+        .iter()
+        .map(|f| f.name().clone())
+        .zip(batch.columns().iter().cloned())
+        .collect::<Vec<(String, ArrayRef)>>();
+    let mut names: Vec<&str> = Vec::new();
+    for (name, _) in extras.iter().flatten() {
+        if !names.contains(&name.as_str()) && !columns.iter().any(|(v, _)| v == name) {
+            names.push(name);
+        }
+    }
+    for name in names {
+        let values = extras
+            .iter()
+            .map(|row| row.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str()))
+            .collect::<Vec<_>>();
+        columns.push((name.to_string(), Arc::new(StringArray::from(values))));
+    }
+    let batch = RecordBatch::try_from_iter(columns)?;
+
+    let mut writer = ArrowWriter::try_new(File::create(filename)?, batch.schema(), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(filename.to_string())
+}
+
+// The url is kept as logged, like nginx's `$request_uri`; the text columns
+// are URL-decoded.
+fn parse_column(field: &str, value: &str, row: &mut Row) -> anyhow::Result<()> {
+    let text = || percent_decode_once(value);
+    match field.to_ascii_lowercase().as_str() {
+        "date" => row.date = Some(value.parse()?),
+        "time" => row.time = Some(NaiveTime::parse_from_str(value, "%H:%M:%S%.f")?),
+        "c-ip" => row.addr = Some(value.parse()?),
+        "cs-method" => row.method = Some(value.parse()?),
+        "cs-uri-stem" => row.stem = Some(value.to_string()),
+        "cs-uri-query" => row.query = Some(value.to_string()),
+        "cs-uri" => match value.split_once('?') {
+            Some((stem, query)) => {
+                row.stem = Some(stem.to_string());
+                row.query = Some(query.to_string());
+            }
+            None => row.stem = Some(value.to_string()),
+        },
+        "cs-version" | "cs-protocol-version" => row.protocol = Some(value.parse()?),
+        "sc-status" => row.status = Some(value.parse()?),
+        "sc-bytes" => row.body_bytes = Some(value.parse()?),
+        "cs-bytes" => row.request_length = Some(value.parse()?),
+        // CloudFront logs seconds with a fraction, IIS whole milliseconds
+        "time-taken" if value.contains('.') => row.request_time = Some(value.parse()?),
+        "time-taken" => row.request_time = Some(value.parse::<u64>()? as f64 / 1e3),
+        "cs(user-agent)" => row.user_agent = Some(text()),
+        "cs(referer)" | "cs(referrer)" => row.referer = Some(text()),
+        "cs-host" | "cs(host)" | "x-host-header" => row.host = Some(text()),
+        "x-edge-request-id" | "cs(x-request-id)" => row.request_id = Some(value.to_string()),
+        "x-forwarded-for" | "cs(x-forwarded-for)" => {
+            row.forwarded_for = realip::parse_forwarded_for(&text())
+        }
+        _ => row.extra.push((field.to_string(), text())),
+    }
+    Ok(())
+}
+
+// `#Fields: date time c-ip`
+fn parse_directive<'s>(s: &mut &'s str) -> PResult<(&'s str, &'s str)> {
+    preceded('#', (terminated(take_till(1.., ':'), ':'), rest))
+        .map(|(name, value): (&str, &str)| (name, value.trim()))
+        .parse_next(s)
+}
+
+fn parse_row<'s>(
+    separator: char,
+) -> impl Parser<&'s str, Vec<&'s str>, winnow::error::ContextError> {
+    separated(1.., take_till(0.., separator), separator)
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::Array;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use super::*;
+
+    const IIS: &str = "\
+#Software: Microsoft Internet Information Services 10.0
+#Version: 1.0
+#Date: 2024-03-01 12:00:00
+#Fields: date time s-ip cs-method cs-uri-stem cs-uri-query s-port c-ip cs(User-Agent) sc-status time-taken
+2024-03-01 12:00:01 10.0.0.5 GET /default.aspx id=7 443 203.0.113.9 Mozilla/5.0+(Windows+NT+10.0) 200 15
+#Fields: time c-ip cs-method cs-uri-stem sc-status sc-bytes cs(Referer)
+12:00:02 2001:db8::7 POST /api/login 401 120 https://example.com/a%20b
+12:00:03 2001:db8::7 GET /missing-columns
+";
+
+    #[test]
+    fn w3c_reader_should_follow_fields_changes() {
+        let logs = parse_w3c_logs(IIS);
+        assert_eq!(logs.len(), 2);
+        let log = &logs[0].log;
+        assert_eq!(log.datetime.to_rfc3339(), "2024-03-01T12:00:01+00:00");
+        assert_eq!(log.url, "/default.aspx?id=7");
+        assert_eq!(log.user_agent, "Mozilla/5.0 (Windows NT 10.0)");
+        assert_eq!(log.request_time, Some(0.015));
+        assert_eq!(log.protocol, HttpProto::HTTP1_1);
+        assert_eq!(
+            logs[0].extra,
+            vec![
+                ("s-ip".to_string(), "10.0.0.5".to_string()),
+                ("s-port".to_string(), "443".to_string()),
+            ]
+        );
+
+        // the second `#Fields` has no date, which comes from `#Date`
+        let log = &logs[1].log;
+        assert_eq!(log.datetime.to_rfc3339(), "2024-03-01T12:00:02+00:00");
+        assert_eq!(log.method, HttpMethod::Post);
+        assert_eq!(log.body_bytes, 120);
+        assert_eq!(log.referer, "https://example.com/a b");
+        assert_eq!(log.user_agent, "-");
+    }
+
+    #[test]
+    fn w3c_reader_should_parse_cloudfront_rows() -> anyhow::Result<()> {
+        let mut reader = W3cReader::new();
+        assert!(reader.parse_line("#Version: 1.0")?.is_none());
+        let fields = concat!(
+            "#Fields: date time x-edge-location sc-bytes c-ip cs-method cs(Host) cs-uri-stem ",
+            "sc-status cs(Referer) cs(User-Agent) cs-uri-query x-edge-request-id x-host-header ",
+            "time-taken x-forwarded-for cs-protocol-version",
+        );
+        assert!(reader.parse_line(fields)?.is_none());
+        assert_eq!(reader.fields().len(), 17);
+        let row = [
+            "2024-03-01",
+            "12:00:00",
+            "FRA56-P1",
+            "2390",
+            "192.0.2.10",
+            "GET",
+            "d111111abcdef8.cloudfront.net",
+            "/index.html",
+            "304",
+            "-",
+            "Mozilla/5.0%20(Macintosh)",
+            "-",
+            "kvp0Dd6Dxh1X",
+            "www.example.com",
+            "0.002",
+            "198.51.100.1,%20192.0.2.10",
+            "HTTP/2.0",
+        ]
+        .join("\t");
+        let W3cLog { log, extra } = reader.parse_line(&row)?.unwrap();
+        assert_eq!(log.status, 304);
+        assert_eq!(log.body_bytes, 2390);
+        assert_eq!(log.url, "/index.html");
+        assert_eq!(log.referer, "-");
+        assert_eq!(log.user_agent, "Mozilla/5.0 (Macintosh)");
+        assert_eq!(log.host.as_deref(), Some("www.example.com"));
+        assert_eq!(log.request_id.as_deref(), Some("kvp0Dd6Dxh1X"));
+        assert_eq!(log.request_time, Some(0.002));
+        assert_eq!(log.forwarded_for.len(), 2);
+        assert_eq!(log.protocol, HttpProto::HTTP2_0);
+        assert_eq!(
+            extra,
+            vec![("x-edge-location".to_string(), "FRA56-P1".to_string())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn w3c_reader_should_reject_mismatched_rows() {
+        let mut reader = W3cReader::new();
+        assert!(reader.parse_line("2024-03-01 12:00:00 GET").is_err());
+        reader
+            .parse_line("#Fields: date time c-ip cs-method cs-uri-stem sc-status")
+            .unwrap();
+        assert!(reader
+            .parse_line("2024-03-01 12:00:00 1.2.3.4 GET /")
+            .is_err());
+        assert!(reader
+            .parse_line("2024-03-01 12:00:00 1.2.3.4 BREW / 200")
+            .is_err());
+        assert!(reader
+            .parse_line("2024-03-01 12:00:00 - GET / 200")
+            .is_err());
+        assert!(reader
+            .parse_line("2024-03-01 12:00:00 1.2.3.4 GET / 200")
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn write_w3c_logs_to_parquet_should_add_extra_columns() -> anyhow::Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("nginx-log-w3c-{}.parquet", std::process::id()));
+        write_w3c_logs_to_parquet(parse_w3c_logs(IIS), path.to_str().unwrap())?;
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&path)?)?.build()?;
+        let batches = reader.collect::<Result<Vec<_>, _>>()?;
+        std::fs::remove_file(&path)?;
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), 2);
+        assert!(batch.column_by_name("user_agent").is_some());
+        let port = batch.column_by_name("s-port").unwrap();
+        let port = port.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(port.value(0), "443");
+        // the second `#Fields` has no s-port
+        assert!(port.is_null(1));
+        assert!(batch.column_by_name("s-ip").is_some());
+        Ok(())
+    }
+}